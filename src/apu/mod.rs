@@ -0,0 +1,435 @@
+/*
+ * Audio processing unit, 0xFF10-0xFF3F
+ *
+ * FF10-FF14  channel 1, square with frequency sweep
+ * FF16-FF19  channel 2, square
+ * FF1A-FF1E  channel 3, wave
+ * FF20-FF23  channel 4, noise
+ * FF24       NR50 master volume, left (6-4) and right (2-0)
+ * FF25       NR51 panning, channels to the left (7-4) and right (3-0)
+ * FF26       NR52 power (7) and channels status (3-0)
+ * FF30-FF3F  wave RAM
+ */
+
+use std::collections::VecDeque;
+use std::io;
+use state::{StateReader, StateWriter};
+use self::noise::Noise;
+use self::square::Square;
+use self::wav::Recorder;
+use self::wave::Wave;
+
+pub mod noise;
+pub mod square;
+pub mod wav;
+pub mod wave;
+
+/// T-cycles per second, the APU runs at this rate in double speed too
+pub const CLOCK_RATE: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// Length counter, the channel stops when it reaches 0
+#[derive(Clone, Copy)]
+pub struct Length {
+    pub enabled: bool,
+    pub counter: u16,
+    max: u16,
+}
+
+impl Length {
+    pub fn new(max: u16) -> Length {
+        Length { enabled: false, counter: 0, max }
+    }
+
+    pub fn load(&mut self, v: u16) {
+        self.counter = self.max - v;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u16(self.counter);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.read_bool()?;
+        self.counter = r.read_u16()?.min(self.max);
+        Ok(())
+    }
+
+    /// Returns true when the channel must be disabled
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+/// Volume envelope, NRx2
+#[derive(Clone, Copy, Default)]
+pub struct Envelope {
+    pub initial: u8,
+    pub increase: bool,
+    pub period: u8,
+    pub volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn read(&self) -> u8 {
+        self.initial << 4 | (self.increase as u8) << 3 | self.period
+    }
+
+    pub fn write(&mut self, v: u8) {
+        self.initial = v >> 4;
+        self.increase = v & 0x08 != 0;
+        self.period = v & 0x07;
+    }
+
+    /// The upper 5 bits of NRx2 power the DAC
+    pub fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.read());
+        w.write_u8(self.volume);
+        w.write_u8(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        let v = r.read_u8()?;
+        self.write(v);
+        self.volume = r.read_u8()? & 0x0F;
+        self.timer = r.read_u8()?;
+        Ok(())
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+pub struct Apu {
+    /// NR52 bit 7
+    pub enabled: bool,
+    pub square1: Square,
+    pub square2: Square,
+    pub wave: Wave,
+    pub noise: Noise,
+    /// NR50
+    pub volume: u8,
+    /// NR51
+    pub panning: u8,
+    /// Frame sequencer step, 0-7 at 512 Hz
+    pub sequencer_step: u8,
+    pub sample_rate: u32,
+    /// Cycles times the sample rate since the last sample
+    sample_clock: u32,
+    /// Charge of the output capacitors, removes the DAC DC offset
+    capacitors: [f32; 2],
+    capacitor_charge: f32,
+    /// Interleaved left and right samples waiting to be pulled
+    pub samples: VecDeque<i16>,
+    /// Running WAV capture
+    pub recorder: Option<Recorder>,
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        let mut apu = Apu {
+            enabled: false,
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            volume: 0,
+            panning: 0,
+            sequencer_step: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0,
+            capacitors: [0.0; 2],
+            capacitor_charge: 0.0,
+            samples: VecDeque::new(),
+            recorder: None,
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu
+    }
+
+    /// Host rate samples are produced at, pending samples are dropped
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate.clamp(1, CLOCK_RATE);
+        self.sample_clock = 0;
+        self.capacitor_charge = 0.999_958f32.powf(CLOCK_RATE as f32 / self.sample_rate as f32);
+        self.samples.clear();
+    }
+
+    /// Called when the PPU completes a frame
+    pub fn end_frame(&mut self) {
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.frame += 1;
+        }
+    }
+
+    /// Clocked by the falling edge of DIV bit 4 (bit 5 in double speed)
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.sequencer_step & 0x01 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.sequencer_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.sequencer_step = (self.sequencer_step + 1) % 8;
+    }
+
+    /// Run the channels for some T-cycles and queue the samples due
+    pub fn step(&mut self, cycles: u32) {
+        if self.enabled {
+            self.square1.step(cycles);
+            self.square2.step(cycles);
+            self.wave.step(cycles);
+            self.noise.step(cycles);
+        }
+
+        self.sample_clock += cycles * self.sample_rate;
+        while self.sample_clock >= CLOCK_RATE {
+            self.sample_clock -= CLOCK_RATE;
+            let (left, right, channels) = self.mix();
+            if let Some(ref mut recorder) = self.recorder {
+                recorder.record(left, right, &channels, self.capacitor_charge);
+            }
+            // One second of audio at most if nobody pulls them
+            if self.samples.len() >= self.sample_rate as usize * 2 {
+                self.samples.pop_front();
+                self.samples.pop_front();
+            }
+            self.samples.push_back(left);
+            self.samples.push_back(right);
+        }
+    }
+
+    /// Digital outputs of the four channels, None for a DAC turned off
+    pub fn outputs(&self) -> [Option<u8>; 4] {
+        if !self.enabled {
+            return [None; 4];
+        }
+        [self.square1.output(), self.square2.output(), self.wave.output(), self.noise.output()]
+    }
+
+    /// Left and right samples, along with the analog output (-1.0 to
+    /// 1.0) of each channel
+    fn mix(&mut self) -> (i16, i16, [f32; 4]) {
+        let mut channels = [0.0; 4];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in self.outputs().iter().enumerate() {
+            if let Some(digital) = *output {
+                channels[i] = 1.0 - digital as f32 / 7.5;
+                if self.panning & (0x10 << i) != 0 {
+                    left += channels[i];
+                }
+                if self.panning & (0x01 << i) != 0 {
+                    right += channels[i];
+                }
+            }
+        }
+        left *= ((self.volume >> 4) & 0x07) as f32 + 1.0;
+        right *= (self.volume & 0x07) as f32 + 1.0;
+
+        let mut out = [0i16; 2];
+        for (i, &level) in [left, right].iter().enumerate() {
+            let filtered = level - self.capacitors[i];
+            self.capacitors[i] = level - filtered * self.capacitor_charge;
+            // 4 channels at full volume is 32
+            out[i] = (filtered / 32.0 * i16::MAX as f32) as i16;
+        }
+        (out[0], out[1], channels)
+    }
+
+    /// Registers and channels, the host side (sample rate, pending
+    /// samples, recording) is left as is
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        self.square1.save_state(w);
+        self.square2.save_state(w);
+        self.wave.save_state(w);
+        self.noise.save_state(w);
+        w.write_u8(self.volume);
+        w.write_u8(self.panning);
+        w.write_u8(self.sequencer_step);
+        w.write_f32(self.capacitors[0]);
+        w.write_f32(self.capacitors[1]);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.read_bool()?;
+        self.square1.load_state(r)?;
+        self.square2.load_state(r)?;
+        self.wave.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.volume = r.read_u8()?;
+        self.panning = r.read_u8()?;
+        self.sequencer_step = r.read_u8()? & 0x07;
+        self.capacitors = [r.read_f32()?, r.read_f32()?];
+        Ok(())
+    }
+
+    /// Move pending interleaved samples into a buffer, returns how many
+    /// were written
+    pub fn read_samples(&mut self, out: &mut [i16]) -> usize {
+        let count = out.len().min(self.samples.len());
+        for (dst, src) in out.iter_mut().zip(self.samples.drain(..count)) {
+            *dst = src;
+        }
+        count
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10..=0xFF14 => self.square1.read(addr - 0xFF10),
+            0xFF15..=0xFF19 => self.square2.read(addr - 0xFF15),
+            0xFF1A..=0xFF1E => self.wave.read(addr - 0xFF1A),
+            0xFF20..=0xFF23 => self.noise.read(addr - 0xFF20),
+            0xFF24 => self.volume,
+            0xFF25 => self.panning,
+            0xFF26 => {
+                0x70 | (self.enabled as u8) << 7
+                    | (self.noise.enabled as u8) << 3
+                    | (self.wave.enabled as u8) << 2
+                    | (self.square2.enabled as u8) << 1
+                    | self.square1.enabled as u8
+            }
+            0xFF30..=0xFF3F => self.wave.ram[addr as usize - 0xFF30],
+            _ => 0xFF,
+        }
+    }
+
+    /// Only NR52 and the wave RAM can be written while powered off
+    pub fn write_byte(&mut self, addr: u16, v: u8) {
+        match addr {
+            0xFF26 => self.set_power(v & 0x80 != 0),
+            0xFF30..=0xFF3F => self.wave.ram[addr as usize - 0xFF30] = v,
+            _ if !self.enabled => {}
+            0xFF10..=0xFF14 => self.square1.write(addr - 0xFF10, v),
+            0xFF15..=0xFF19 => self.square2.write(addr - 0xFF15, v),
+            0xFF1A..=0xFF1E => self.wave.write(addr - 0xFF1A, v),
+            0xFF20..=0xFF23 => self.noise.write(addr - 0xFF20, v),
+            0xFF24 => self.volume = v,
+            0xFF25 => self.panning = v,
+            _ => {}
+        }
+    }
+
+    /// Powering off clears every register but the wave RAM
+    fn set_power(&mut self, on: bool) {
+        if self.enabled && !on {
+            let ram = self.wave.ram;
+            self.square1 = Square::new(true);
+            self.square2 = Square::new(false);
+            self.wave = Wave::new();
+            self.wave.ram = ram;
+            self.noise = Noise::new();
+            self.volume = 0;
+            self.panning = 0;
+        } else if !self.enabled && on {
+            self.sequencer_step = 0;
+        }
+        self.enabled = on;
+    }
+}
+
+#[test]
+fn power_and_registers() {
+    let mut apu = Apu::new();
+    apu.write_byte(0xFF12, 0xF3);
+    assert_eq!(apu.read_byte(0xFF12), 0x00);
+    assert_eq!(apu.read_byte(0xFF26), 0x70);
+
+    apu.write_byte(0xFF26, 0x80);
+    apu.write_byte(0xFF11, 0x80);
+    apu.write_byte(0xFF12, 0xF3);
+    apu.write_byte(0xFF14, 0x87);
+    apu.write_byte(0xFF30, 0x42);
+    assert_eq!(apu.read_byte(0xFF11), 0xBF);
+    assert_eq!(apu.read_byte(0xFF12), 0xF3);
+    assert_eq!(apu.read_byte(0xFF13), 0xFF);
+    assert_eq!(apu.read_byte(0xFF26), 0xF1);
+
+    apu.write_byte(0xFF26, 0x00);
+    assert_eq!(apu.read_byte(0xFF12), 0x00);
+    assert_eq!(apu.read_byte(0xFF26), 0x70);
+    assert_eq!(apu.read_byte(0xFF30), 0x42);
+}
+
+#[test]
+fn length_counter() {
+    let mut apu = Apu::new();
+    apu.write_byte(0xFF26, 0x80);
+    apu.write_byte(0xFF21, 0xF0);
+    apu.write_byte(0xFF20, 62);
+    apu.write_byte(0xFF23, 0xC0);
+    assert_eq!(apu.read_byte(0xFF26) & 0x08, 0x08);
+
+    // Clocked on every other step of the frame sequencer
+    for _ in 0..3 {
+        apu.clock_frame_sequencer();
+    }
+    assert_eq!(apu.read_byte(0xFF26) & 0x08, 0x00);
+}
+
+#[test]
+fn sample_rate() {
+    let mut apu = Apu::new();
+    apu.set_sample_rate(32_768);
+    for _ in 0..CLOCK_RATE / 4 / 64 {
+        apu.step(4);
+    }
+    assert_eq!(apu.samples.len(), 512 * 2);
+
+    let mut out = [0; 100];
+    assert_eq!(apu.read_samples(&mut out), 100);
+    assert_eq!(apu.samples.len(), 1024 - 100);
+}
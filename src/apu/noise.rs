@@ -0,0 +1,169 @@
+/*
+ * Noise channel, output taken from a 15 bits LFSR (7 bits in short mode)
+ *
+ * NR41  length load (5-0)
+ * NR42  envelope
+ * NR43  clock shift (7-4), width (3), divisor code (2-0)
+ * NR44  trigger (7), length enable (6)
+ */
+
+use std::io;
+use apu::{Envelope, Length};
+use state::{StateReader, StateWriter};
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub struct Noise {
+    pub enabled: bool,
+    pub length: Length,
+    pub envelope: Envelope,
+    pub shift: u8,
+    /// 7 bits LFSR
+    pub short: bool,
+    pub divisor: u8,
+    pub lfsr: u16,
+    timer: u32,
+}
+
+impl Default for Noise {
+    fn default() -> Noise {
+        Noise::new()
+    }
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            shift: 0,
+            short: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.write_u8(self.shift);
+        w.write_bool(self.short);
+        w.write_u8(self.divisor);
+        w.write_u16(self.lfsr);
+        w.write_u32(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.read_bool()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.shift = r.read_u8()? & 0x0F;
+        self.short = r.read_bool()?;
+        self.divisor = r.read_u8()? & 0x07;
+        self.lfsr = r.read_u16()?;
+        self.timer = r.read_u32()?;
+        Ok(())
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            1 => self.envelope.read(),
+            2 => self.shift << 4 | (self.short as u8) << 3 | self.divisor,
+            3 => 0xBF | (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, v: u8) {
+        match reg {
+            0 => self.length.load(v as u16 & 0x3F),
+            1 => {
+                self.envelope.write(v);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            2 => {
+                self.shift = v >> 4;
+                self.short = v & 0x08 != 0;
+                self.divisor = v & 0x07;
+            }
+            3 => {
+                self.length.enabled = v & 0x40 != 0;
+                if v & 0x80 != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn shift_lfsr(&mut self) {
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+        self.lfsr = (self.lfsr >> 1) | bit << 14;
+        if self.short {
+            self.lfsr = (self.lfsr & !0x40) | bit << 6;
+        }
+    }
+
+    pub fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.shift_lfsr();
+        }
+        self.timer -= cycles;
+    }
+
+    /// Digital output (0-15), None while the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        Some((!self.lfsr & 0x01) as u8 * self.envelope.volume)
+    }
+}
+
+#[test]
+fn lfsr() {
+    let mut noise = Noise::new();
+    noise.shift_lfsr();
+    assert_eq!(noise.lfsr, 0x3FFF);
+    for _ in 0..14 {
+        noise.shift_lfsr();
+    }
+    // Down to 0x0001, then a 1 comes back in at bit 14
+    assert_eq!(noise.lfsr, 0x4000);
+
+    // Short mode repeats every 127 shifts
+    let mut noise = Noise::new();
+    noise.short = true;
+    for _ in 0..10 {
+        noise.shift_lfsr();
+    }
+    let state = noise.lfsr & 0x7F;
+    for _ in 0..127 {
+        noise.shift_lfsr();
+    }
+    assert_eq!(noise.lfsr & 0x7F, state);
+}
@@ -0,0 +1,252 @@
+/*
+ * Square channels, 1 has a frequency sweep and 2 does not
+ *
+ * NRx0  sweep period (6-4), negate (3), shift (2-0), channel 1 only
+ * NRx1  duty (7-6), length load (5-0)
+ * NRx2  envelope
+ * NRx3  frequency low
+ * NRx4  trigger (7), length enable (6), frequency high (2-0)
+ */
+
+use std::io;
+use apu::{Envelope, Length};
+use state::{StateReader, StateWriter};
+
+const DUTY: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+pub struct Square {
+    pub enabled: bool,
+    has_sweep: bool,
+    pub duty: u8,
+    position: u8,
+    pub frequency: u16,
+    timer: u32,
+    pub length: Length,
+    pub envelope: Envelope,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    /// Frequency the sweep works on, copied on trigger
+    shadow: u16,
+    /// A negate calculation ran since the trigger
+    negate_used: bool,
+}
+
+impl Square {
+    pub fn new(has_sweep: bool) -> Square {
+        Square {
+            enabled: false,
+            has_sweep,
+            duty: 0,
+            position: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow: 0,
+            negate_used: false,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.duty);
+        w.write_u8(self.position);
+        w.write_u16(self.frequency);
+        w.write_u32(self.timer);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_timer);
+        w.write_bool(self.sweep_enabled);
+        w.write_u16(self.shadow);
+        w.write_bool(self.negate_used);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.read_bool()?;
+        self.duty = r.read_u8()? & 0x03;
+        self.position = r.read_u8()? & 0x07;
+        self.frequency = r.read_u16()? & 0x7FF;
+        self.timer = r.read_u32()?;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()?;
+        self.sweep_timer = r.read_u8()?;
+        self.sweep_enabled = r.read_bool()?;
+        self.shadow = r.read_u16()?;
+        self.negate_used = r.read_bool()?;
+        Ok(())
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 if self.has_sweep => {
+                0x80 | self.sweep_period << 4 | (self.sweep_negate as u8) << 3 | self.sweep_shift
+            }
+            1 => self.duty << 6 | 0x3F,
+            2 => self.envelope.read(),
+            4 => 0xBF | (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, v: u8) {
+        match reg {
+            0 if self.has_sweep => {
+                self.sweep_period = (v >> 4) & 0x07;
+                self.sweep_negate = v & 0x08 != 0;
+                self.sweep_shift = v & 0x07;
+                // Leaving negate mode after using it kills the channel
+                if self.negate_used && !self.sweep_negate {
+                    self.enabled = false;
+                }
+            }
+            1 => {
+                self.duty = v >> 6;
+                self.length.load(v as u16 & 0x3F);
+            }
+            2 => {
+                self.envelope.write(v);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | v as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (v as u16 & 0x07) << 8;
+                self.length.enabled = v & 0x40 != 0;
+                if v & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        if self.has_sweep {
+            self.shadow = self.frequency;
+            self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+            self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+            self.negate_used = false;
+            if self.sweep_shift != 0 {
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    /// Next frequency of the sweep, the channel is disabled when it
+    /// overflows
+    fn sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.sweep_shift;
+        let frequency = if self.sweep_negate {
+            self.negate_used = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.sweep_frequency();
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.shadow = frequency;
+                self.frequency = frequency;
+                // Checked again right away, only for the overflow
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    /// Digital output (0-15), None while the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        if !self.enabled {
+            return Some(0);
+        }
+        Some(DUTY[self.duty as usize][self.position as usize] * self.envelope.volume)
+    }
+}
+
+#[test]
+fn sweep() {
+    let mut square = Square::new(true);
+    square.write(2, 0xF0);
+    square.write(0, 0x11);
+    square.write(3, 0x00);
+    square.write(4, 0x84);
+    assert!(square.enabled);
+
+    // 0x600 + 0x300 is past 2047, checked right after the update
+    square.clock_sweep();
+    assert_eq!(square.frequency, 0x600);
+    assert!(!square.enabled);
+
+    // Overflow detected on trigger
+    square.write(3, 0xFF);
+    square.write(4, 0x87);
+    assert!(!square.enabled);
+
+    // Leaving negate mode after a negate calculation
+    square.write(0, 0x19);
+    square.write(4, 0x84);
+    assert!(square.enabled);
+    square.write(0, 0x11);
+    assert!(!square.enabled);
+}
@@ -0,0 +1,137 @@
+/*
+ * Capture of the APU output to 16 bits PCM WAV files
+ */
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Write a canonical 44 bytes header RIFF file of interleaved samples
+pub fn write_wav<W: Write>(out: &mut W, sample_rate: u32, channels: u16, samples: &[i16]) -> io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+    let block_align = channels * 2;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&channels.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn save_wav<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16, samples: &[i16]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_wav(&mut out, sample_rate, channels, samples)?;
+    out.flush()
+}
+
+/// Samples captured from the APU while a recording runs
+pub struct Recorder {
+    pub sample_rate: u32,
+    /// First frame recorded and the one the recording stops at, counted
+    /// from the start of the recording. Everything when None.
+    pub range: Option<(u64, u64)>,
+    /// Frames completed since the recording started
+    pub frame: u64,
+    /// Interleaved left and right mix, as heard by the player
+    pub mix: Vec<i16>,
+    /// Mono output of each channel, before panning and master volume
+    pub stems: Option<[Vec<i16>; 4]>,
+    stem_capacitors: [f32; 4],
+}
+
+impl Recorder {
+    pub fn new(sample_rate: u32, range: Option<(u64, u64)>, stems: bool) -> Recorder {
+        Recorder {
+            sample_rate,
+            range,
+            frame: 0,
+            mix: Vec::new(),
+            stems: if stems { Some([Vec::new(), Vec::new(), Vec::new(), Vec::new()]) } else { None },
+            stem_capacitors: [0.0; 4],
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        match self.range {
+            Some((start, end)) => self.frame >= start && self.frame < end,
+            None => true,
+        }
+    }
+
+    /// Keep a mixed sample and the analog output of the four channels
+    pub fn record(&mut self, left: i16, right: i16, channels: &[f32; 4], capacitor_charge: f32) {
+        if !self.recording() {
+            return;
+        }
+        self.mix.push(left);
+        self.mix.push(right);
+
+        if let Some(ref mut stems) = self.stems {
+            for (i, stem) in stems.iter_mut().enumerate() {
+                let filtered = channels[i] - self.stem_capacitors[i];
+                self.stem_capacitors[i] = channels[i] - filtered * capacitor_charge;
+                stem.push((filtered / 2.0 * i16::MAX as f32) as i16);
+            }
+        }
+    }
+
+    /// Write the stereo mix to a file, and the stems next to it as
+    /// `<name>.ch1.wav` to `<name>.ch4.wav`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        save_wav(path, self.sample_rate, 2, &self.mix)?;
+        if let Some(ref stems) = self.stems {
+            for (i, stem) in stems.iter().enumerate() {
+                save_wav(stem_path(path, i + 1), self.sample_rate, 1, stem)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn stem_path(path: &Path, channel: usize) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}.ch{}.wav", stem, channel))
+}
+
+#[test]
+fn header() {
+    let mut out = Vec::new();
+    write_wav(&mut out, 44_100, 2, &[1, -1, 0x1234, 0]).unwrap();
+    assert_eq!(out.len(), 44 + 8);
+    assert_eq!(&out[0..4], b"RIFF");
+    assert_eq!(&out[4..8], &44u32.to_le_bytes());
+    assert_eq!(&out[22..24], &2u16.to_le_bytes());
+    assert_eq!(&out[28..32], &(44_100u32 * 4).to_le_bytes());
+    assert_eq!(&out[40..44], &8u32.to_le_bytes());
+    assert_eq!(&out[44..48], &[0x01, 0x00, 0xFF, 0xFF]);
+
+    assert_eq!(stem_path(Path::new("/tmp/run.wav"), 3), PathBuf::from("/tmp/run.ch3.wav"));
+}
+
+#[test]
+fn frame_range() {
+    let mut recorder = Recorder::new(44_100, Some((1, 2)), true);
+    recorder.record(1, 1, &[0.0; 4], 1.0);
+    recorder.frame = 1;
+    recorder.record(2, 2, &[0.0; 4], 1.0);
+    recorder.frame = 2;
+    recorder.record(3, 3, &[0.0; 4], 1.0);
+    assert_eq!(recorder.mix, vec![2, 2]);
+    assert_eq!(recorder.stems.unwrap()[3].len(), 1);
+}
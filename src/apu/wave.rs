@@ -0,0 +1,134 @@
+/*
+ * Wave channel, plays the 32 4-bit samples of the wave RAM (0xFF30-0xFF3F)
+ *
+ * NR30  DAC power (7)
+ * NR31  length load
+ * NR32  output level (6-5): mute, 100%, 50%, 25%
+ * NR33  frequency low
+ * NR34  trigger (7), length enable (6), frequency high (2-0)
+ */
+
+use std::io;
+use apu::Length;
+use state::{StateReader, StateWriter};
+
+pub struct Wave {
+    pub enabled: bool,
+    pub dac: bool,
+    pub length: Length,
+    pub level: u8,
+    pub frequency: u16,
+    timer: u32,
+    /// Sample played, 0-31
+    position: u8,
+    pub ram: [u8; 16],
+}
+
+impl Default for Wave {
+    fn default() -> Wave {
+        Wave::new()
+    }
+}
+
+impl Wave {
+    pub fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac: false,
+            length: Length::new(256),
+            level: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            ram: [0; 16],
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.dac);
+        self.length.save_state(w);
+        w.write_u8(self.level);
+        w.write_u16(self.frequency);
+        w.write_u32(self.timer);
+        w.write_u8(self.position);
+        w.write_bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.read_bool()?;
+        self.dac = r.read_bool()?;
+        self.length.load_state(r)?;
+        self.level = r.read_u8()? & 0x03;
+        self.frequency = r.read_u16()? & 0x7FF;
+        self.timer = r.read_u32()?;
+        self.position = r.read_u8()? & 0x1F;
+        r.read_into(&mut self.ram)
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    pub fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => (self.dac as u8) << 7 | 0x7F,
+            2 => self.level << 5 | 0x9F,
+            4 => 0xBF | (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u16, v: u8) {
+        match reg {
+            0 => {
+                self.dac = v & 0x80 != 0;
+                if !self.dac {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(v as u16),
+            2 => self.level = (v >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | v as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (v as u16 & 0x07) << 8;
+                self.length.enabled = v & 0x40 != 0;
+                if v & 0x80 != 0 {
+                    self.enabled = self.dac;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= cycles;
+    }
+
+    /// Digital output (0-15), None while the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.dac {
+            return None;
+        }
+        if !self.enabled || self.level == 0 {
+            return Some(0);
+        }
+        let byte = self.ram[self.position as usize / 2];
+        let sample = if self.position & 0x01 == 0 { byte >> 4 } else { byte & 0x0F };
+        Some(sample >> (self.level - 1))
+    }
+}
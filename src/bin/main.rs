@@ -4,17 +4,21 @@ extern crate lazy_static;
 extern crate rustyline;
 extern crate gameboy_emu;
 
-use std::{env, io};
+use std::{env, fs, io};
 use std::io::{stdout, Write};
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
-use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 use gameboy_emu::cartridge::Cartridge;
 use gameboy_emu::cpu::{Cpu, Register16};
-use gameboy_emu::cpu::opcodes::decode;
-use gameboy_emu::debug::debug;
 use gameboy_emu::gameboy::GameBoy;
+use gameboy_emu::joypad::{Button, ButtonState};
+use gameboy_emu::link::StreamLink;
 use gameboy_emu::memory::Memory;
+use gameboy_emu::movie::Movie;
+use gameboy_emu::ppu::Renderer;
+use gameboy_emu::printer::Printer;
+use gameboy_emu::serial::TextSink;
 
 /*use cartridge::Cartridge;
 use cpu::Cpu;
@@ -30,13 +34,58 @@ mod cartridge;
 mod memory;
 mod debug;*/
 
+/// Value following a command line flag
+fn flag_value(name: &str) -> Option<String> {
+    env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+fn button_named(name: &str) -> Option<Button> {
+    match name {
+        "right" => Some(Button::Right),
+        "left" => Some(Button::Left),
+        "up" => Some(Button::Up),
+        "down" => Some(Button::Down),
+        "a" => Some(Button::A),
+        "b" => Some(Button::B),
+        "select" => Some(Button::Select),
+        "start" => Some(Button::Start),
+        _ => None,
+    }
+}
+
+/// Write the movie recorded with --movie-record, tell how the one of
+/// --movie-play went
+fn finish_movie(gb: &mut GameBoy) {
+    if let Some(run) = gb.movie.as_ref().filter(|run| run.playing) {
+        match run.desync {
+            Some(frame) => println!("movie desynced at frame {}", frame),
+            None => println!("movie played back in sync, {} frames", run.frame),
+        }
+    }
+    if let (Some(path), Some(movie)) = (flag_value("--movie-record"), gb.stop_movie()) {
+        if let Err(e) = movie.save(&path) {
+            println!("could not write {}: {}", path, e);
+        }
+    }
+}
+
+/// Write the audio captured with --wav
+fn save_recording(gb: &mut GameBoy) {
+    if let (Some(path), Some(recorder)) = (flag_value("--wav"), gb.stop_recording()) {
+        if let Err(e) = recorder.save(&path) {
+            println!("could not write {}: {}", path, e);
+        }
+    }
+}
+
 fn main() {
 
     // Load a ROM file and return a Cartridge
     let rom = Box::new(Cartridge::new(env::args().nth(1).unwrap()).unwrap());
 
-    // Init Cpu registers
-    let cpu = Box::new(Cpu::new());
+    // Init Cpu registers, --fifo draws with the accurate pixel FIFO
+    let renderer = if env::args().any(|arg| arg == "--fifo") { Renderer::Fifo } else { Renderer::Scanline };
+    let cpu = Box::new(Cpu::with_renderer(renderer));
 
     // Init memory
     let mut mem = Box::new(Memory::new());
@@ -50,6 +99,91 @@ fn main() {
     // Plug all emulated components into the GameBoy
     let mut gb = GameBoy::new(cpu, boot_rom, rom, mem);
 
+    // --wav <file> records the audio, --wav-frames <start>:<end> only
+    // keeps some frames and --wav-stems adds a file per channel
+    if flag_value("--wav").is_some() {
+        let range = flag_value("--wav-frames").and_then(|range| {
+            let mut bounds = range.split(':').map(|b| b.parse::<u64>());
+            match (bounds.next(), bounds.next()) {
+                (Some(Ok(start)), Some(Ok(end))) => Some((start, end)),
+                _ => None,
+            }
+        });
+        gb.start_recording(range, env::args().any(|arg| arg == "--wav-stems"));
+    }
+
+    // --serial prints what the ROM sent through the link port on exit
+    let serial = TextSink::new();
+    if env::args().any(|arg| arg == "--serial") {
+        gb.connect_serial(Box::new(serial.clone()));
+    }
+
+    // --printer <dir> plugs a Game Boy Printer writing its pages there
+    if let Some(dir) = flag_value("--printer") {
+        gb.connect_serial(Box::new(Printer::new(dir)));
+    }
+
+    // --link-listen <addr> waits for another emulator to connect with
+    // --link-connect <addr>, addresses with a '/' are Unix sockets
+    let link = match (flag_value("--link-listen"), flag_value("--link-connect")) {
+        #[cfg(unix)]
+        (Some(ref addr), _) if addr.contains('/') => Some(StreamLink::listen_unix(addr)),
+        #[cfg(unix)]
+        (_, Some(ref addr)) if addr.contains('/') => Some(StreamLink::connect_unix(addr)),
+        #[cfg(not(unix))]
+        (Some(ref addr), _) | (_, Some(ref addr)) if addr.contains('/') => {
+            Some(Err(io::Error::new(io::ErrorKind::Other, "Unix sockets are not supported on this system")))
+        }
+        (Some(addr), _) => Some(StreamLink::listen_tcp(addr)),
+        (_, Some(addr)) => Some(StreamLink::connect_tcp(addr)),
+        _ => None,
+    };
+    match link {
+        Some(Ok(link)) => gb.connect_serial(Box::new(link)),
+        Some(Err(e)) => println!("could not link: {}", e),
+        None => {}
+    }
+
+    // --movie-record <file> records the buttons held with the debugger k
+    // command from power on, with the RTC and the RAM seeded from the
+    // current time. --movie-play <file> plays one back, headless for its
+    // length unless told otherwise.
+    let mut movie_frames = None;
+    if let Some(path) = flag_value("--movie-play") {
+        match Movie::load(&path) {
+            Ok(movie) => {
+                movie_frames = Some(movie.inputs.len() as u64);
+                if let Err(e) = gb.play_movie(movie) {
+                    println!("could not play {}: {}", path, e);
+                }
+            }
+            Err(e) => println!("could not load {}: {}", path, e),
+        }
+    } else if flag_value("--movie-record").is_some() {
+        // Nothing presses buttons in a headless run
+        if env::args().any(|arg| arg == "--headless") {
+            println!("--movie-record needs the debugger to hold buttons, not --headless");
+            return;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        gb.start_movie(true, now.as_secs(), now.as_nanos() as u64).unwrap();
+    }
+
+    // --headless <frames> runs without the debugger and exits
+    let headless = flag_value("--headless").and_then(|f| f.parse::<u64>().ok()).or(movie_frames);
+    if let Some(frames) = headless {
+        for _ in 0..frames {
+            gb.run_frame();
+        }
+        if let Err(e) = gb.cartridge.autosave() {
+            println!("could not write the save file: {}", e);
+        }
+        finish_movie(&mut gb);
+        save_recording(&mut gb);
+        print!("{}", serial.text());
+        return;
+    }
+
     // let pointer = gb.clone();
 
     //let (tx, rx): (Sender<String>, Receiver<String>) = channel();
@@ -61,6 +195,9 @@ fn main() {
     let mut breakpoint: u16 = 0x64;
     let mut stepping = true;
 
+    // A snapshot every 10 frames for a minute, to step back in the debugger
+    gb.enable_rewind(10, 360);
+
     loop {
 
         if stepping || gb.cpu.get_16(Register16::PC) == breakpoint {
@@ -85,6 +222,50 @@ fn main() {
             if buffer.eq("c") {
                 stepping = false;
             }
+            if buffer.eq("q") {
+                finish_movie(&mut gb);
+                save_recording(&mut gb);
+                break;
+            }
+            // s <file> saves the machine state, l <file> loads it back
+            if let Some(path) = buffer.strip_prefix("s ") {
+                match fs::write(path, gb.save_state()) {
+                    Ok(()) => println!("State saved to {}", path),
+                    Err(e) => println!("could not write {}: {}", path, e),
+                }
+            }
+            if let Some(path) = buffer.strip_prefix("l ") {
+                match fs::read(path).and_then(|state| gb.load_state(&state)) {
+                    Ok(()) => println!("State loaded from {}", path),
+                    Err(e) => println!("could not load {}: {}", path, e),
+                }
+            }
+            // k <buttons> holds buttons until the next k, as in "k a right"
+            if buffer == "k" || buffer.starts_with("k ") {
+                let mut buttons = ButtonState::default();
+                for name in buffer.split_whitespace().skip(1) {
+                    match button_named(name) {
+                        Some(button) => buttons.set(button, true),
+                        None => println!("Unknown button {}", name),
+                    }
+                }
+                gb.set_buttons(buttons);
+            }
+            // r steps back one instruction, rw <frames> rewinds
+            if buffer.eq("r") {
+                match gb.step_back() {
+                    Ok(true) => {}
+                    Ok(false) => println!("No snapshot to go back to"),
+                    Err(e) => println!("could not step back: {}", e),
+                }
+            }
+            if let Some(frames) = buffer.strip_prefix("rw ") {
+                let frames = frames.parse::<u64>().unwrap_or(0);
+                match gb.rewind(frames) {
+                    Ok(frames) => println!("Rewound {} frames", frames),
+                    Err(e) => println!("could not rewind: {}", e),
+                }
+            }
             if buffer.starts_with("p") {
                 buffer = buffer.replace("p 0x", "");
                 let addr = u16::from_str_radix(buffer.as_str(), 16).unwrap_or(0);
@@ -102,9 +283,11 @@ fn main() {
         println!("SCL = {}", gameboy.cpu.ppu.scanline);
         println!("ppu_tick : {}", gameboy.cpu.ppu.tick);
 
-        decode(&mut gameboy);
-        gameboy.cpu.ppu_step();
-        gameboy.interrupt_step();
+        gameboy.step();
+
+        if let Err(e) = gameboy.cartridge.autosave() {
+            println!("could not write the save file: {}", e);
+        }
     }
 
 
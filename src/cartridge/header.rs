@@ -0,0 +1,275 @@
+/*
+ * Cartridge header, 0x0100-0x014F
+ *
+ * http://gbdev.gg8.se/wiki/articles/The_Cartridge_Header
+ */
+
+use std::fmt;
+use std::io;
+use cartridge::NINTENDO_LOGO;
+
+pub const HEADER_SIZE: usize = 0x0150;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CgbSupport {
+    /// Monochrome game
+    None,
+    /// Runs on both DMG and CGB (0x80)
+    Compatible,
+    /// CGB only (0xC0)
+    Only,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    Japanese,
+    Overseas,
+}
+
+#[derive(Debug, Clone)]
+pub struct CartridgeHeader {
+    pub entry_point: [u8; 4],
+    pub logo: [u8; 48],
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb: CgbSupport,
+    pub new_licensee: [u8; 2],
+    pub sgb: bool,
+    pub cartridge_type: u8,
+    /// ROM size in bytes, as declared
+    pub rom_size: usize,
+    /// External RAM size in bytes, as declared
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub old_licensee: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+/// Outcome of the integrity checks run against a rom image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeaderVerification {
+    pub logo: bool,
+    pub header_checksum: bool,
+    pub global_checksum: bool,
+    /// The image is as large as the size declared in the header
+    pub rom_size: bool,
+}
+
+impl HeaderVerification {
+    /// What the boot rom checks, a real console refuses to start otherwise
+    pub fn bootable(&self) -> bool {
+        self.logo && self.header_checksum
+    }
+
+    /// Every check passed, the dump can be trusted
+    pub fn is_valid(&self) -> bool {
+        self.bootable() && self.global_checksum && self.rom_size
+    }
+}
+
+/// Size in bytes of the external RAM for a header RAM size code
+pub fn ram_size_from(code: u8) -> usize {
+    match code {
+        0x01 => 0x800,
+        0x02 => 0x2000,
+        0x03 => 0x8000,
+        0x04 => 0x20000,
+        0x05 => 0x10000,
+        _ => 0,
+    }
+}
+
+/// Size in bytes of the ROM for a header ROM size code
+pub fn rom_size_from(code: u8) -> usize {
+    match code {
+        0x00..=0x08 => 0x8000 << code,
+        0x52 => 72 * 0x4000,
+        0x53 => 80 * 0x4000,
+        0x54 => 96 * 0x4000,
+        _ => 0,
+    }
+}
+
+/// Whether a cartridge type keeps its RAM powered by a battery
+pub fn has_battery(cartridge_type: u8) -> bool {
+    matches!(cartridge_type, 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFC | 0xFF)
+}
+
+/// Checksum of 0x0134-0x014C, verified by the boot rom
+pub fn header_checksum(rom: &[u8]) -> u8 {
+    rom[0x134..0x14D]
+        .iter()
+        .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+}
+
+/// Sum of every byte of the rom but the global checksum itself
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes.iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+impl CartridgeHeader {
+    /// Decode the header of a rom image
+    pub fn parse(rom: &[u8]) -> io::Result<CartridgeHeader> {
+        if rom.len() < HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("rom is {} bytes, too small to hold a header", rom.len()),
+            ));
+        }
+
+        let cgb = match rom[0x143] {
+            0xC0 => CgbSupport::Only,
+            0x80 => CgbSupport::Compatible,
+            _ => CgbSupport::None,
+        };
+
+        // Newer titles shrank to 11 characters to make room for a
+        // manufacturer code, older CGB ones stop before the CGB flag
+        let code = &rom[0x13F..0x143];
+        let has_code = cgb != CgbSupport::None
+            && code.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit());
+        let (title, manufacturer_code) = match (cgb, has_code) {
+            (_, true) => (ascii(&rom[0x134..0x13F]), Some(ascii(code))),
+            (CgbSupport::None, _) => (ascii(&rom[0x134..0x144]), None),
+            _ => (ascii(&rom[0x134..0x143]), None),
+        };
+
+        let mut entry_point = [0u8; 4];
+        entry_point.copy_from_slice(&rom[0x100..0x104]);
+        let mut logo = [0u8; 48];
+        logo.copy_from_slice(&rom[0x104..0x134]);
+
+        Ok(CartridgeHeader {
+            entry_point,
+            logo,
+            title,
+            manufacturer_code,
+            cgb,
+            new_licensee: [rom[0x144], rom[0x145]],
+            sgb: rom[0x146] == 0x03,
+            cartridge_type: rom[0x147],
+            rom_size: rom_size_from(rom[0x148]),
+            ram_size: ram_size_from(rom[0x149]),
+            destination: if rom[0x14A] == 0x00 { Destination::Japanese } else { Destination::Overseas },
+            old_licensee: rom[0x14B],
+            version: rom[0x14C],
+            header_checksum: rom[0x14D],
+            global_checksum: ((rom[0x14E] as u16) << 8) | rom[0x14F] as u16,
+        })
+    }
+
+    /// Licensee code, taken from the new field when the old one says so
+    pub fn licensee(&self) -> String {
+        if self.old_licensee == 0x33 {
+            ascii(&self.new_licensee)
+        } else {
+            format!("{:02X}", self.old_licensee)
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        has_battery(self.cartridge_type)
+    }
+
+    /// Check the header against the full rom image it was read from
+    pub fn verify(&self, rom: &[u8]) -> HeaderVerification {
+        HeaderVerification {
+            logo: self.logo[..] == NINTENDO_LOGO[..],
+            header_checksum: rom.len() >= HEADER_SIZE && header_checksum(rom) == self.header_checksum,
+            global_checksum: global_checksum(rom) == self.global_checksum,
+            rom_size: self.rom_size != 0 && rom.len() >= self.rom_size,
+        }
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "> title: {}\n\
+            > manufacturer: {}\n\
+            > licensee: {}\n\
+            > cgb: {:?} sgb: {}\n\
+            > cartridge type: {:#04x}\n\
+            > ROM size: {} bytes\n\
+            > RAM size: {} bytes\n\
+            > destination: {:?}\n\
+            > version: {}\n\
+            > header checksum: {:#04x}\n\
+            > global checksum: {:#06x}",
+               self.title,
+               self.manufacturer_code.as_deref().unwrap_or("-"),
+               self.licensee(),
+               self.cgb,
+               self.sgb,
+               self.cartridge_type,
+               self.rom_size,
+               self.ram_size,
+               self.destination,
+               self.version,
+               self.header_checksum,
+               self.global_checksum)
+    }
+}
+
+#[cfg(test)]
+fn valid_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+    rom[0x134..0x13F].copy_from_slice(b"POKEMON_SLV");
+    rom[0x13F..0x143].copy_from_slice(b"AAXE");
+    rom[0x143] = 0x80;
+    rom[0x14A] = 0x01;
+    rom[0x14B] = 0x33;
+    rom[0x144..0x146].copy_from_slice(b"01");
+    rom[0x14D] = header_checksum(&rom);
+    let sum = global_checksum(&rom);
+    rom[0x14E] = (sum >> 8) as u8;
+    rom[0x14F] = sum as u8;
+    rom
+}
+
+#[test]
+fn parse() {
+    let rom = valid_rom();
+    let header = CartridgeHeader::parse(&rom).unwrap();
+
+    assert_eq!(header.title, "POKEMON_SLV");
+    assert_eq!(header.manufacturer_code, Some(String::from("AAXE")));
+    assert_eq!(header.cgb, CgbSupport::Compatible);
+    assert_eq!(header.licensee(), "01");
+    assert_eq!(header.rom_size, 0x8000);
+    assert_eq!(header.destination, Destination::Overseas);
+    assert!(header.verify(&rom).is_valid());
+
+    assert!(CartridgeHeader::parse(&rom[..0x100]).is_err());
+}
+
+#[test]
+fn corrupt_dump() {
+    let mut rom = valid_rom();
+    rom[0x4000] = 0x42;
+    let checks = CartridgeHeader::parse(&rom).unwrap().verify(&rom);
+    assert!(checks.bootable());
+    assert!(!checks.global_checksum);
+
+    rom[0x134] = b'Q';
+    rom[0x110] = 0xFF;
+    let checks = CartridgeHeader::parse(&rom).unwrap().verify(&rom);
+    assert!(!checks.logo);
+    assert!(!checks.header_checksum);
+    assert!(!checks.is_valid());
+}
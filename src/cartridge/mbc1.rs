@@ -0,0 +1,209 @@
+/*
+ * MBC1 memory bank controller
+ *
+ * 0000-1FFF  RAM enable (0x0A in the low nibble)
+ * 2000-3FFF  ROM bank number, 5 bits (0 is read as 1)
+ * 4000-5FFF  RAM bank number / upper ROM bank bits, 2 bits
+ * 6000-7FFF  Banking mode select
+ *
+ * MBC1M multicarts wire the upper bits one position lower, so only
+ * 4 bits of the ROM bank register reach the ROM.
+ */
+
+use std::io;
+use cartridge::{Mapper, NINTENDO_LOGO};
+use state::{StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    upper_bank: u8,
+    advanced_mode: bool,
+    rom_banks: usize,
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        Mbc1 {
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            upper_bank: 0,
+            advanced_mode: false,
+            rom_banks: (rom.len() / ROM_BANK_SIZE).max(2),
+            multicart: Self::is_multicart(&rom),
+            rom,
+        }
+    }
+
+    /// MBC1M carts are 1MiB and repeat the Nintendo logo at the start of
+    /// every sub-game, the first one being at bank 0x10
+    fn is_multicart(rom: &[u8]) -> bool {
+        if rom.len() != 64 * ROM_BANK_SIZE {
+            return false;
+        }
+        let logo = 0x10 * ROM_BANK_SIZE + 0x104;
+        rom[logo..logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO[..]
+    }
+
+    fn upper_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    /// Bank mapped at 0x0000-0x3FFF
+    fn low_rom_bank(&self) -> usize {
+        if !self.advanced_mode {
+            return 0;
+        }
+        ((self.upper_bank as usize) << self.upper_shift()) % self.rom_banks
+    }
+
+    /// Bank mapped at 0x4000-0x7FFF
+    fn high_rom_bank(&self) -> usize {
+        let low = if self.multicart {
+            self.rom_bank & 0x0F
+        } else {
+            self.rom_bank
+        };
+        let bank = ((self.upper_bank as usize) << self.upper_shift()) | low as usize;
+        bank % self.rom_banks
+    }
+
+    fn ram_address(&self, addr: u16) -> usize {
+        let bank = if self.advanced_mode { self.upper_bank as usize } else { 0 };
+        (bank * RAM_BANK_SIZE + (addr as usize - 0xA000)) % self.ram.len()
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3FFF => self.low_rom_bank(),
+            _ => self.high_rom_bank(),
+        };
+        let offset = bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).cloned().unwrap_or(0xFF)
+    }
+
+    fn write_register(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = v & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = v & 0x1F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.upper_bank = v & 0x03,
+            0x6000..=0x7FFF => self.advanced_mode = v & 0x01 == 1,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+        self.ram[self.ram_address(addr)]
+    }
+
+    fn write_ram(&mut self, addr: u16, v: u8) -> bool {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return false;
+        }
+        let i = self.ram_address(addr);
+        let changed = self.ram[i] != v;
+        self.ram[i] = v;
+        changed
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.ram_enabled);
+        w.write_u8(self.rom_bank);
+        w.write_u8(self.upper_bank);
+        w.write_bool(self.advanced_mode);
+        w.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.ram_enabled = r.read_bool()?;
+        self.rom_bank = r.read_u8()?;
+        self.upper_bank = r.read_u8()?;
+        self.advanced_mode = r.read_bool()?;
+        r.read_into(&mut self.ram)
+    }
+
+    fn battery_dump(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn battery_load(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+fn banked_rom(banks: usize) -> Vec<u8> {
+    let mut rom = vec![0; banks * ROM_BANK_SIZE];
+    for bank in 0..banks {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+    }
+    rom
+}
+
+#[test]
+fn rom_banking() {
+    let rom = banked_rom(128);
+    let mut mbc = Mbc1::new(rom, 0);
+
+    assert_eq!(mbc.read_rom(0x4000), 1);
+    mbc.write_register(0x2000, 0);
+    assert_eq!(mbc.read_rom(0x4000), 1);
+    mbc.write_register(0x2000, 0x25);
+    assert_eq!(mbc.read_rom(0x4000), 0x05);
+    mbc.write_register(0x4000, 0x02);
+    assert_eq!(mbc.read_rom(0x4000), 0x45);
+    assert_eq!(mbc.read_rom(0x0000), 0);
+
+    mbc.write_register(0x6000, 1);
+    assert_eq!(mbc.read_rom(0x0000), 0x40);
+}
+
+#[test]
+fn ram_banking() {
+    let rom = banked_rom(4);
+    let mut mbc = Mbc1::new(rom, 4 * RAM_BANK_SIZE);
+
+    mbc.write_ram(0xA000, 0x42);
+    assert_eq!(mbc.read_ram(0xA000), 0xFF);
+
+    mbc.write_register(0x0000, 0x0A);
+    mbc.write_ram(0xA000, 0x42);
+    assert_eq!(mbc.read_ram(0xA000), 0x42);
+
+    mbc.write_register(0x4000, 1);
+    assert_eq!(mbc.read_ram(0xA000), 0x42);
+    mbc.write_register(0x6000, 1);
+    assert_eq!(mbc.read_ram(0xA000), 0x00);
+}
+
+#[test]
+fn multicart() {
+    let mut rom = banked_rom(64);
+    let logo = 0x10 * ROM_BANK_SIZE + 0x104;
+    rom[logo..logo + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+    let mut mbc = Mbc1::new(rom, 0);
+
+    mbc.write_register(0x2000, 0x12);
+    mbc.write_register(0x4000, 0x01);
+    assert_eq!(mbc.read_rom(0x4000), 0x12);
+    mbc.write_register(0x6000, 1);
+    assert_eq!(mbc.read_rom(0x0000), 0x10);
+}
@@ -0,0 +1,134 @@
+/*
+ * MBC2 memory bank controller
+ *
+ * 0000-3FFF  Address bit 8 clear: RAM enable (0x0A in the low nibble)
+ *            Address bit 8 set: ROM bank number, 4 bits (0 is read as 1)
+ * A000-A1FF  512 x 4 bits built-in RAM, echoed up to 0xBFFF
+ */
+
+use std::io;
+use cartridge::Mapper;
+use state::{StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_SIZE: usize = 0x200;
+
+pub struct Mbc2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    rom_banks: usize,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            ram: vec![0; RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+            rom_banks: (rom.len() / ROM_BANK_SIZE).max(2),
+            rom,
+        }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize % self.rom_banks,
+        };
+        let offset = bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).cloned().unwrap_or(0xFF)
+    }
+
+    /// The register is selected by address bit 8
+    fn write_register(&mut self, addr: u16, v: u8) {
+        if addr > 0x3FFF {
+            return;
+        }
+        if addr & 0x0100 == 0 {
+            self.ram_enabled = v & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = v & 0x0F;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    /// Only the low nibble is stored, the upper bits are open bus
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram[addr as usize & (RAM_SIZE - 1)] | 0xF0
+    }
+
+    fn write_ram(&mut self, addr: u16, v: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        let cell = &mut self.ram[addr as usize & (RAM_SIZE - 1)];
+        let changed = *cell != v & 0x0F;
+        *cell = v & 0x0F;
+        changed
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.ram_enabled);
+        w.write_u8(self.rom_bank);
+        w.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.ram_enabled = r.read_bool()?;
+        self.rom_bank = r.read_u8()?;
+        r.read_into(&mut self.ram)
+    }
+
+    /// One byte per nibble, like other emulators do
+    fn battery_dump(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn battery_load(&mut self, data: &[u8]) {
+        for (cell, &v) in self.ram.iter_mut().zip(data) {
+            *cell = v & 0x0F;
+        }
+    }
+}
+
+#[test]
+fn registers() {
+    let mut rom = vec![0; 16 * ROM_BANK_SIZE];
+    for bank in 0..16 {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+    }
+    let mut mbc = Mbc2::new(rom);
+
+    mbc.write_register(0x2100, 0x05);
+    assert_eq!(mbc.read_rom(0x4000), 5);
+    mbc.write_register(0x0100, 0x00);
+    assert_eq!(mbc.read_rom(0x4000), 1);
+
+    // bit 8 clear selects the RAM enable register, not the ROM bank
+    mbc.write_register(0x2000, 0x0A);
+    assert_eq!(mbc.read_rom(0x4000), 1);
+    mbc.write_ram(0xA000, 0x42);
+    assert_eq!(mbc.read_ram(0xA000), 0xF2);
+}
+
+#[test]
+fn ram_echo() {
+    let mut mbc = Mbc2::new(vec![0; 2 * ROM_BANK_SIZE]);
+
+    mbc.write_register(0x0000, 0x0A);
+    mbc.write_ram(0xA1FF, 0x07);
+    assert_eq!(mbc.read_ram(0xA3FF), 0xF7);
+    assert_eq!(mbc.read_ram(0xBFFF), 0xF7);
+
+    mbc.write_register(0x0000, 0x00);
+    assert_eq!(mbc.read_ram(0xA1FF), 0xFF);
+}
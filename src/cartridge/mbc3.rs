@@ -0,0 +1,405 @@
+/*
+ * MBC3 memory bank controller with its real-time clock
+ *
+ * 0000-1FFF  RAM and RTC enable (0x0A in the low nibble)
+ * 2000-3FFF  ROM bank number, 7 bits (0 is read as 1)
+ * 4000-5FFF  RAM bank number (00-03) or RTC register select (08-0C)
+ * 6000-7FFF  Latch clock data (write 0x00 then 0x01)
+ */
+
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use cartridge::Mapper;
+use state::{StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+/// RTC footer appended to the RAM in .sav files (BGB / VBA-M layout)
+const RTC_FOOTER_SIZE: usize = 48;
+
+/// Seconds since the unix epoch on the host
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,
+    pub halt: bool,
+    pub day_carry: bool,
+}
+
+impl RtcRegisters {
+    /// Value of a register as seen by the cpu (0x08-0x0C)
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            0x0C => {
+                ((self.days >> 8) as u8 & 0x01)
+                    | if self.halt { 0x40 } else { 0 }
+                    | if self.day_carry { 0x80 } else { 0 }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, v: u8) {
+        match reg {
+            0x08 => self.seconds = v & 0x3F,
+            0x09 => self.minutes = v & 0x3F,
+            0x0A => self.hours = v & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | v as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((v as u16 & 0x01) << 8);
+                self.halt = v & 0x40 != 0;
+                self.day_carry = v & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// Move the clock forward by some seconds
+    pub fn advance(&mut self, elapsed: u64) {
+        let total = self.seconds as u64 + elapsed;
+        self.seconds = (total % 60) as u8;
+        let total = self.minutes as u64 + total / 60;
+        self.minutes = (total % 60) as u8;
+        let total = self.hours as u64 + total / 60;
+        self.hours = (total % 24) as u8;
+        let days = self.days as u64 + total / 24;
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+}
+
+#[derive(Clone)]
+pub struct Rtc {
+    pub current: RtcRegisters,
+    pub latched: RtcRegisters,
+    /// Host timestamp the current registers are valid at
+    pub timestamp: u64,
+    latch_armed: bool,
+}
+
+impl Rtc {
+    pub fn new(now: u64) -> Rtc {
+        Rtc {
+            current: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            timestamp: now,
+            latch_armed: false,
+        }
+    }
+
+    /// Catch the clock up with the host time
+    pub fn update(&mut self, now: u64) {
+        if !self.current.halt && now > self.timestamp {
+            self.current.advance(now - self.timestamp);
+        }
+        self.timestamp = now;
+    }
+
+    pub fn write_latch(&mut self, v: u8, now: u64) {
+        if self.latch_armed && v == 0x01 {
+            self.update(now);
+            self.latched = self.current;
+        }
+        self.latch_armed = v == 0x00;
+    }
+
+    pub fn write(&mut self, reg: u8, v: u8, now: u64) {
+        self.update(now);
+        self.current.write(reg, v);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for regs in &[self.current, self.latched] {
+            for reg in 0x08..=0x0C {
+                w.write_u8(regs.read(reg));
+            }
+        }
+        w.write_u64(self.timestamp);
+        w.write_bool(self.latch_armed);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        for reg in 0x08..=0x0C {
+            let v = r.read_u8()?;
+            self.current.write(reg, v);
+        }
+        for reg in 0x08..=0x0C {
+            let v = r.read_u8()?;
+            self.latched.write(reg, v);
+        }
+        self.timestamp = r.read_u64()?;
+        self.latch_armed = r.read_bool()?;
+        Ok(())
+    }
+
+    /// Serialize the clock the way other emulators append it to the save
+    pub fn dump(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for regs in &[self.current, self.latched] {
+            for reg in 0x08..=0x0C {
+                out.extend_from_slice(&(regs.read(reg) as u32).to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&self.timestamp.to_le_bytes());
+        out
+    }
+
+    /// Restore a clock footer, either the 48 bytes or the older 44 bytes one
+    pub fn load(&mut self, footer: &[u8]) {
+        if footer.len() < 44 {
+            return;
+        }
+        let word = |i: usize| footer[i * 4];
+        for reg in 0..5 {
+            self.current.write(0x08 + reg as u8, word(reg));
+            self.latched.write(0x08 + reg as u8, word(reg + 5));
+        }
+        let mut timestamp = [0u8; 8];
+        let len = if footer.len() >= RTC_FOOTER_SIZE { 8 } else { 4 };
+        timestamp[..len].copy_from_slice(&footer[40..40 + len]);
+        self.timestamp = u64::from_le_bytes(timestamp);
+    }
+}
+
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    /// Either a RAM bank (0x00-0x07) or a RTC register (0x08-0x0C)
+    ram_select: u8,
+    rom_banks: usize,
+    pub rtc: Option<Rtc>,
+    /// Time set by the emulator, the host clock is used when None
+    clock: Option<u64>,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> Mbc3 {
+        Mbc3 {
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_select: 0,
+            rom_banks: (rom.len() / ROM_BANK_SIZE).max(2),
+            rtc: if has_rtc { Some(Rtc::new(unix_now())) } else { None },
+            clock: None,
+            rom,
+        }
+    }
+
+    /// Seconds since the unix epoch, as seen by the RTC
+    fn now(&self) -> u64 {
+        self.clock.unwrap_or_else(unix_now)
+    }
+
+    fn ram_address(&self, addr: u16) -> Option<usize> {
+        if self.ram.is_empty() || self.ram_select > 0x07 {
+            return None;
+        }
+        let offset = self.ram_select as usize * RAM_BANK_SIZE + (addr as usize - 0xA000);
+        Some(offset % self.ram.len())
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize % self.rom_banks,
+        };
+        let offset = bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).cloned().unwrap_or(0xFF)
+    }
+
+    fn write_register(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = v & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = v & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_select = v & 0x0F,
+            0x6000..=0x7FFF => {
+                let now = self.now();
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.write_latch(v, now);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Either the RAM or the latched RTC register
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match (self.ram_select, &self.rtc) {
+            (0x08..=0x0C, Some(rtc)) => rtc.latched.read(self.ram_select),
+            _ => match self.ram_address(addr) {
+                Some(i) => self.ram[i],
+                None => 0xFF,
+            },
+        }
+    }
+
+    /// Any write to a clock register counts as a change
+    fn write_ram(&mut self, addr: u16, v: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        let now = self.now();
+        match (self.ram_select, &mut self.rtc) {
+            (0x08..=0x0C, &mut Some(ref mut rtc)) => {
+                rtc.write(self.ram_select, v, now);
+                true
+            }
+            _ => match self.ram_address(addr) {
+                Some(i) => {
+                    let changed = self.ram[i] != v;
+                    self.ram[i] = v;
+                    changed
+                }
+                None => false,
+            },
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.ram_enabled);
+        w.write_u8(self.rom_bank);
+        w.write_u8(self.ram_select);
+        w.write_bytes(&self.ram);
+        if let Some(ref rtc) = self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.ram_enabled = r.read_bool()?;
+        self.rom_bank = r.read_u8()?;
+        self.ram_select = r.read_u8()?;
+        r.read_into(&mut self.ram)?;
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load_state(r)?;
+        }
+        Ok(())
+    }
+
+    /// RAM content followed by the RTC footer
+    fn battery_dump(&self) -> Vec<u8> {
+        let mut out = self.ram.clone();
+        if let Some(ref rtc) = self.rtc {
+            let mut rtc = rtc.clone();
+            rtc.update(self.now());
+            out.extend(rtc.dump());
+        }
+        out
+    }
+
+    /// Restore the RAM and the clock, then let the clock catch up with the
+    /// time spent while the emulator was closed
+    fn battery_load(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        let now = self.now();
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load(&data[len..]);
+            rtc.update(now);
+        }
+    }
+
+    /// Switching between the host clock and an emulated one, the RTC
+    /// carries on from where the previous clock left it
+    fn set_rtc_time(&mut self, now: Option<u64>) {
+        if self.clock.is_some() != now.is_some() {
+            let (before, after) = (self.now(), now.unwrap_or_else(unix_now));
+            if let Some(ref mut rtc) = self.rtc {
+                rtc.update(before);
+                rtc.timestamp = after;
+            }
+        }
+        self.clock = now;
+    }
+}
+
+#[test]
+fn rtc_advance() {
+    let mut regs = RtcRegisters::default();
+    regs.advance(59);
+    assert_eq!(regs.seconds, 59);
+    regs.advance(1);
+    assert_eq!((regs.seconds, regs.minutes), (0, 1));
+    regs.advance(24 * 3600 * 511);
+    assert_eq!((regs.days, regs.day_carry), (511, false));
+    regs.advance(24 * 3600);
+    assert_eq!((regs.days, regs.day_carry), (0, true));
+    assert_eq!(regs.read(0x0C), 0x80);
+}
+
+#[test]
+fn rtc_latch_and_halt() {
+    let mut rtc = Rtc::new(1000);
+
+    rtc.write_latch(0x00, 1010);
+    rtc.write_latch(0x01, 1010);
+    assert_eq!(rtc.latched.seconds, 10);
+
+    rtc.write(0x0C, 0x40, 1020);
+    rtc.write_latch(0x00, 1050);
+    rtc.write_latch(0x01, 1050);
+    assert_eq!(rtc.latched.seconds, 20);
+    assert!(rtc.latched.halt);
+}
+
+#[test]
+fn rtc_footer_round_trip() {
+    let mut rtc = Rtc::new(1_600_000_000);
+    rtc.current.advance(90061);
+    rtc.latched = rtc.current;
+    let footer = rtc.dump();
+    assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+
+    let mut restored = Rtc::new(0);
+    restored.load(&footer);
+    assert_eq!(restored.current, rtc.current);
+    assert_eq!(restored.latched, rtc.latched);
+    assert_eq!(restored.timestamp, 1_600_000_000);
+}
+
+#[test]
+fn emulated_clock() {
+    let mut mbc = Mbc3::new(vec![0; 0x8000], 0x2000, true);
+    mbc.set_rtc_time(Some(100));
+    mbc.write_register(0x0000, 0x0A);
+    mbc.write_register(0x4000, 0x08);
+    mbc.write_register(0x6000, 0x00);
+    mbc.write_register(0x6000, 0x01);
+    let start = mbc.read_ram(0xA000);
+
+    // Only the emulated time moves the clock
+    mbc.set_rtc_time(Some(105));
+    mbc.write_register(0x6000, 0x00);
+    mbc.write_register(0x6000, 0x01);
+    assert_eq!(mbc.read_ram(0xA000), (start + 5) % 60);
+    assert_eq!(&mbc.battery_dump()[0x2000 + 40..0x2000 + 48], &105u64.to_le_bytes());
+}
@@ -0,0 +1,152 @@
+/*
+ * MBC5 memory bank controller
+ *
+ * 0000-1FFF  RAM enable (0x0A in the low nibble)
+ * 2000-2FFF  ROM bank number, low 8 bits (0 is a valid bank)
+ * 3000-3FFF  ROM bank number, 9th bit
+ * 4000-5FFF  RAM bank number, 4 bits
+ *            (bit 3 drives the motor on rumble cartridges)
+ */
+
+use std::io;
+use cartridge::Mapper;
+use state::{StateReader, StateWriter};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    rom_banks: usize,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rom_banks: (rom.len() / ROM_BANK_SIZE).max(2),
+            has_rumble,
+            rumble: false,
+            rom,
+        }
+    }
+
+    fn ram_address(&self, addr: u16) -> usize {
+        (self.ram_bank as usize * RAM_BANK_SIZE + (addr as usize - 0xA000)) % self.ram.len()
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize % self.rom_banks,
+        };
+        let offset = bank * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).cloned().unwrap_or(0xFF)
+    }
+
+    fn write_register(&mut self, addr: u16, v: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = v & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | v as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((v as u16 & 0x01) << 8),
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumble = v & 0x08 != 0;
+                    self.ram_bank = v & 0x07;
+                } else {
+                    self.ram_bank = v & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xFF;
+        }
+        self.ram[self.ram_address(addr)]
+    }
+
+    fn write_ram(&mut self, addr: u16, v: u8) -> bool {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return false;
+        }
+        let i = self.ram_address(addr);
+        let changed = self.ram[i] != v;
+        self.ram[i] = v;
+        changed
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.ram_enabled);
+        w.write_u16(self.rom_bank);
+        w.write_u8(self.ram_bank);
+        w.write_bool(self.rumble);
+        w.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.ram_enabled = r.read_bool()?;
+        self.rom_bank = r.read_u16()?;
+        self.ram_bank = r.read_u8()?;
+        self.rumble = r.read_bool()?;
+        r.read_into(&mut self.ram)
+    }
+
+    fn battery_dump(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn battery_load(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+#[test]
+fn rom_banking() {
+    let mut rom = vec![0; 512 * ROM_BANK_SIZE];
+    for bank in 0..512 {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+        rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+    }
+    let mut mbc = Mbc5::new(rom, 0, false);
+
+    mbc.write_register(0x2000, 0);
+    assert_eq!(mbc.read_rom(0x4000), 0);
+    mbc.write_register(0x2000, 0x42);
+    mbc.write_register(0x3000, 0x01);
+    assert_eq!(mbc.read_rom(0x4000), 0x42);
+    assert_eq!(mbc.read_rom(0x4001), 0x01);
+    mbc.write_register(0x3000, 0x00);
+    assert_eq!(mbc.read_rom(0x4001), 0x00);
+}
+
+#[test]
+fn rumble() {
+    let mut mbc = Mbc5::new(vec![0; 4 * ROM_BANK_SIZE], 8 * RAM_BANK_SIZE, true);
+
+    mbc.write_register(0x0000, 0x0A);
+    mbc.write_register(0x4000, 0x09);
+    assert!(mbc.rumble());
+    mbc.write_ram(0xA000, 0x42);
+    mbc.write_register(0x4000, 0x01);
+    assert!(!mbc.rumble());
+    assert_eq!(mbc.read_ram(0xA000), 0x42);
+}
@@ -1,15 +1,40 @@
 use std::fmt;
 use std::fs::File;
 use std::io;
-use std::io::Read;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use join_bytes;
+use state::{StateReader, StateWriter};
+use self::header::{CartridgeHeader, HeaderVerification, HEADER_SIZE, global_checksum, has_battery, ram_size_from};
+use self::mbc1::Mbc1;
+use self::mbc2::Mbc2;
+use self::mbc3::Mbc3;
+use self::mbc5::Mbc5;
+use self::rom_only::RomOnly;
+
+pub mod header;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod rom_only;
 
 const TITLE: (u16, u16) = (0x0134, 0x0143);
 const CARTRIDGE_TYPE: u16 = 0x0147;
-const ROM_SIZE: u16 = 0x0148;
 const RAM_SIZE: u16 = 0x0149;
 
+/// How long RAM writes may stay only in memory before being saved
+const AUTOSAVE_DELAY: Duration = Duration::from_secs(5);
+
+/// Logo bitmap every licensed cartridge carries at 0x0104-0x0133
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
 #[derive(Debug)]
 pub enum MBC {
     ROM,
@@ -18,17 +43,143 @@ pub enum MBC {
     MBC3,
     MBC4,
     MBC5,
+    Unknown(u8),
+}
+
+/// A memory bank controller, owning the ROM and the external RAM of the
+/// cartridge. Implement it to plug a controller the emulator doesn't know.
+pub trait Mapper {
+    /// Read a byte from the 0x0000-0x7FFF window
+    fn read_rom(&self, addr: u16) -> u8;
+
+    /// Write to the controller registers mapped over 0x0000-0x7FFF
+    fn write_register(&mut self, addr: u16, v: u8);
+
+    /// Read a byte from the external RAM at 0xA000-0xBFFF
+    fn read_ram(&self, addr: u16) -> u8;
+
+    /// Write a byte to the external RAM at 0xA000-0xBFFF, returns whether
+    /// what the battery keeps changed
+    fn write_ram(&mut self, addr: u16, v: u8) -> bool;
+
+    /// Snapshot of the registers and the RAM, used by save states
+    fn save_state(&self, w: &mut StateWriter);
+
+    /// Restore a snapshot written by `save_state`
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()>;
+
+    /// Content of the battery-backed RAM, as stored in .sav files
+    fn battery_dump(&self) -> Vec<u8>;
+
+    /// Restore the battery-backed RAM from a .sav file content
+    fn battery_load(&mut self, data: &[u8]);
+
+    /// Whether the rumble motor is currently on
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    /// Run the real-time clock, if any, on a time given in seconds since
+    /// the unix epoch in place of the host clock, or back on the host
+    /// clock with None
+    fn set_rtc_time(&mut self, _now: Option<u64>) {}
 }
 
-pub struct Cartridge(Vec<u8>, usize);
+pub struct Cartridge {
+    header: Vec<u8>,
+    /// Header decoded once at load
+    info: CartridgeHeader,
+    verification: HeaderVerification,
+    size: usize,
+    /// Sum of every rom byte, identifies the game in save states
+    checksum: u16,
+    mapper: Box<dyn Mapper>,
+    /// .sav file backing the battery RAM
+    save_path: Option<PathBuf>,
+    /// RAM was written since the last save
+    dirty: bool,
+    last_save: Instant,
+}
 
 impl Cartridge {
-    /// Load rom file
+    /// Load rom file, and its `.sav` next to it when the cartridge has
+    /// a battery
     pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Cartridge> {
         let mut rom: Vec<u8> = Vec::new();
-        let mut file = File::open(path)?;
-        let size = file.read_to_end(&mut rom)?;
-        Ok(Cartridge(rom, size))
+        let mut file = File::open(&path)?;
+        file.read_to_end(&mut rom)?;
+        let mut cartridge = Cartridge::from_bytes(rom)?;
+
+        if cartridge.has_battery() {
+            let save_path = path.as_ref().with_extension("sav");
+            if save_path.exists() {
+                cartridge.load_battery(&save_path)?;
+            }
+            cartridge.save_path = Some(save_path);
+        }
+        Ok(cartridge)
+    }
+
+    /// Build a cartridge from a rom image already in memory, picking the
+    /// mapper from the header
+    pub fn from_bytes(rom: Vec<u8>) -> io::Result<Cartridge> {
+        let header = Self::copy_header(&rom);
+        let info = Self::parse_header(&header);
+        let verification = info.verify(&rom);
+        let size = rom.len();
+        let checksum = global_checksum(&rom);
+        let kind = header[CARTRIDGE_TYPE as usize];
+        let ram_size = ram_size_from(header[RAM_SIZE as usize]);
+
+        let mapper: Box<dyn Mapper> = match Self::type_from(kind) {
+            MBC::ROM => Box::new(RomOnly::new(rom, ram_size)),
+            MBC::MBC1 => Box::new(Mbc1::new(rom, ram_size)),
+            MBC::MBC2 => Box::new(Mbc2::new(rom)),
+            MBC::MBC3 => {
+                let has_rtc = matches!(kind, 0x0F | 0x10);
+                Box::new(Mbc3::new(rom, ram_size, has_rtc))
+            }
+            MBC::MBC5 => {
+                let has_rumble = matches!(kind, 0x1C..=0x1E);
+                Box::new(Mbc5::new(rom, ram_size, has_rumble))
+            }
+            mbc => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported cartridge type {:#04x} ({:?})", kind, mbc),
+                ));
+            }
+        };
+
+        Ok(Cartridge {
+            header,
+            info,
+            verification,
+            size,
+            checksum,
+            mapper,
+            save_path: None,
+            dirty: false,
+            last_save: Instant::now(),
+        })
+    }
+
+    /// Build a cartridge around a custom mapper, the rom is only used
+    /// for its header
+    pub fn with_mapper(rom: &[u8], mapper: Box<dyn Mapper>) -> Cartridge {
+        let header = Self::copy_header(rom);
+        let info = Self::parse_header(&header);
+        Cartridge {
+            header,
+            verification: info.verify(rom),
+            info,
+            size: rom.len(),
+            checksum: global_checksum(rom),
+            mapper,
+            save_path: None,
+            dirty: false,
+            last_save: Instant::now(),
+        }
     }
 
     pub fn empty(size: usize) -> io::Result<Box<Cartridge>> {
@@ -36,24 +187,137 @@ impl Cartridge {
         for _ in 0..size {
             rom.push(0);
         }
-        Ok(Box::new(Cartridge(rom, size)))
+        Ok(Box::new(Cartridge::from_bytes(rom)?))
+    }
+
+    fn copy_header(rom: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; HEADER_SIZE];
+        let len = rom.len().min(HEADER_SIZE);
+        header[..len].copy_from_slice(&rom[..len]);
+        header
+    }
+
+    /// The copied header is never too small to parse
+    fn parse_header(header: &[u8]) -> CartridgeHeader {
+        CartridgeHeader::parse(header).unwrap()
+    }
+
+    /// Decoded cartridge header
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.info
+    }
+
+    /// Logo, checksums and size checks of the loaded rom image
+    pub fn verification(&self) -> HeaderVerification {
+        self.verification
+    }
+
+    pub fn mapper(&self) -> &dyn Mapper {
+        &*self.mapper
     }
 
-    /// Read a byte from the rom
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        &mut *self.mapper
+    }
+
+    /// Read a byte from the 0x0000-0x7FFF window, through the mapper
     pub fn read_byte(&self, addr: u16) -> u8 {
-        self.0[addr as usize]
+        self.mapper.read_rom(addr)
     }
 
+    /// Write to the mapper registers
     pub fn write_byte(&mut self, addr: u16, data: u8) {
-        self.0[addr as usize] = data;
+        self.mapper.write_register(addr, data)
+    }
+
+    /// Read a byte from the external RAM (0xA000-0xBFFF)
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.mapper.read_ram(addr)
+    }
+
+    /// Write a byte to the external RAM (0xA000-0xBFFF)
+    pub fn write_ram(&mut self, addr: u16, data: u8) {
+        if self.mapper.write_ram(addr, data) {
+            self.dirty = true;
+        }
+    }
+
+    /// Content of the battery-backed RAM, with the RTC footer if any
+    pub fn battery_dump(&self) -> Vec<u8> {
+        self.mapper.battery_dump()
     }
 
-    pub fn write_bytes(&mut self, addr: u16, bytes: Vec<u8>) {
-        let mut ad = addr;
-        for b in bytes {
-            self.0[ad as usize] = b;
-            ad += 1;
+    /// Restore the battery-backed RAM from a previous dump
+    pub fn battery_load(&mut self, data: &[u8]) {
+        self.mapper.battery_load(data)
+    }
+
+    /// Mapper registers and RAM, loading counts as a RAM write for the
+    /// .sav file
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.mapper.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.mapper.load_state(r)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Whether the cartridge rumble motor is currently on
+    pub fn rumble(&self) -> bool {
+        self.mapper.rumble()
+    }
+
+    pub fn set_rtc_time(&mut self, now: Option<u64>) {
+        self.mapper.set_rtc_time(now)
+    }
+
+    /// Load the battery-backed RAM from a save file
+    pub fn load_battery<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut data: Vec<u8> = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        self.battery_load(&data);
+        Ok(())
+    }
+
+    /// Write the battery-backed RAM to a save file
+    pub fn save_battery<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let data = self.battery_dump();
+        File::create(path)?.write_all(&data)
+    }
+
+    /// Whether the cartridge RAM survives power off
+    pub fn has_battery(&self) -> bool {
+        has_battery(self.header[CARTRIDGE_TYPE as usize])
+    }
+
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    /// Change where the battery RAM is saved, `None` disables saving
+    pub fn set_save_path(&mut self, path: Option<PathBuf>) {
+        self.save_path = path;
+    }
+
+    /// Write the battery RAM to its .sav file
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.save_path {
+            self.save_battery(path)?;
+        }
+        self.dirty = false;
+        self.last_save = Instant::now();
+        Ok(())
+    }
+
+    /// Flush the battery RAM if it was written a while ago, cheap enough
+    /// to be called after every instruction
+    pub fn autosave(&mut self) -> io::Result<()> {
+        if self.dirty && self.last_save.elapsed() >= AUTOSAVE_DELAY {
+            return self.flush();
         }
+        Ok(())
     }
 
     pub fn read_word(&self, addr: u16) -> u16 {
@@ -62,11 +326,11 @@ impl Cartridge {
         join_bytes!(b, a)
     }
 
-    /// Read a range of bytes from the rom
+    /// Read a range of bytes from the rom header (0x0000-0x014F)
     pub fn read_range(&self, addr: (u16, u16)) -> Vec<u8> {
         let mut a: Vec<u8> = Vec::with_capacity((addr.1 - addr.0) as usize);
         for x in addr.0..addr.1 {
-            a.push(self.0[x as usize])
+            a.push(self.header[x as usize])
         }
         a
     }
@@ -85,20 +349,45 @@ impl Cartridge {
 
     /// Read mem type
     pub fn cartridge_type(&self) -> MBC {
-        match self.read_byte(CARTRIDGE_TYPE) {
+        Self::type_from(self.header[CARTRIDGE_TYPE as usize])
+    }
+
+    fn type_from(kind: u8) -> MBC {
+        match kind {
             0x00 | 0x8 | 0x9 => MBC::ROM,
             0x1 | 0x2 | 0x3 => MBC::MBC1,
             0x5 | 0x6 => MBC::MBC2,
             0xF | 0x10 | 0x11 | 0x12 | 0x13 => MBC::MBC3,
             0x15 | 0x16 | 0x17 => MBC::MBC4,
-            0x19 | 0x1B | 0x1C | 0x1D | 0x1E => MBC::MBC5,
-            _ => unreachable!(),
+            0x19..=0x1E => MBC::MBC5,
+            _ => MBC::Unknown(kind),
         }
     }
 
     /// Return the size of the cartridge
     pub fn size(&self) -> usize {
-        self.1
+        self.size
+    }
+
+    /// Sum of the rom bytes but the global checksum ones
+    pub fn checksum(&self) -> u16 {
+        self.checksum
+    }
+
+    /// Size in bytes of the external RAM declared in the header
+    pub fn ram_size(&self) -> usize {
+        ram_size_from(self.header[RAM_SIZE as usize])
+    }
+}
+
+/// Writes the RAM left unsaved since the last flush
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        if self.dirty && self.save_path.is_some() {
+            if let Err(e) = self.flush() {
+                eprintln!("could not write the save file: {}", e);
+            }
+        }
     }
 }
 
@@ -106,15 +395,79 @@ impl fmt::Debug for Cartridge {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
                "=== ROM DEBUG ===\n\
-            > title game {}\n\
-            > cartridge type: {:?}\n\
+            {}\n\
+            > mapper: {:?}\n\
             > cartridge size: {}\n\
-            > ROM size: {:#x}\n\
-            > RAM size: {:#x}",
-               self.read_title(),
+            > {:?}",
+               self.header(),
                self.cartridge_type(),
                self.size(),
-               self.read_byte(ROM_SIZE),
-               self.read_byte(RAM_SIZE))
+               self.verification)
+    }
+}
+
+#[test]
+fn mapper_from_header() {
+    let mut rom = vec![0; 0x8000];
+    rom[CARTRIDGE_TYPE as usize] = 0x03;
+    rom[RAM_SIZE as usize] = 0x02;
+    let mut cartridge = Cartridge::from_bytes(rom.clone()).unwrap();
+    cartridge.write_byte(0x0000, 0x0A);
+    cartridge.write_ram(0xA000, 0x42);
+    assert_eq!(cartridge.read_ram(0xA000), 0x42);
+
+    rom[CARTRIDGE_TYPE as usize] = 0xFC;
+    let err = Cartridge::from_bytes(rom).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn save_file() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("gameboy-save-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rom_path = dir.join("game.gb");
+    let mut rom = vec![0; 0x8000];
+    rom[CARTRIDGE_TYPE as usize] = 0x03;
+    rom[RAM_SIZE as usize] = 0x02;
+    File::create(&rom_path).unwrap().write_all(&rom).unwrap();
+
+    // Nothing written, nothing saved: the RAM is disabled, then the
+    // byte written is the one already there
+    {
+        let mut cartridge = Cartridge::new(&rom_path).unwrap();
+        cartridge.write_ram(0xA123, 0x42);
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_ram(0xA123, 0x00);
+    }
+    assert!(!dir.join("game.sav").exists());
+
+    {
+        let mut cartridge = Cartridge::new(&rom_path).unwrap();
+        assert_eq!(cartridge.save_path(), Some(dir.join("game.sav").as_path()));
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_ram(0xA123, 0x42);
+    }
+    assert_eq!(fs::read(dir.join("game.sav")).unwrap()[0x123], 0x42);
+
+    let mut cartridge = Cartridge::new(&rom_path).unwrap();
+    cartridge.write_byte(0x0000, 0x0A);
+    assert_eq!(cartridge.read_ram(0xA123), 0x42);
+
+    cartridge.set_save_path(None);
+
+    // Setting the RTC is saved as well
+    let rom_path = dir.join("clock.gb");
+    rom[CARTRIDGE_TYPE as usize] = 0x10;
+    File::create(&rom_path).unwrap().write_all(&rom).unwrap();
+    {
+        let mut cartridge = Cartridge::new(&rom_path).unwrap();
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0x4000, 0x08);
+        cartridge.write_ram(0xA000, 30);
     }
+    assert!(dir.join("clock.sav").exists());
+    fs::remove_dir_all(&dir).unwrap();
 }
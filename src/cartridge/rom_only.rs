@@ -0,0 +1,63 @@
+/*
+ * Cartridge without bank controller: 32KiB of ROM and up to 8KiB of RAM
+ */
+
+use std::io;
+use cartridge::Mapper;
+use state::{StateReader, StateWriter};
+
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom,
+            ram: vec![0; ram_size.min(0x2000)],
+        }
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_rom(&self, addr: u16) -> u8 {
+        self.rom.get(addr as usize).cloned().unwrap_or(0xFF)
+    }
+
+    fn write_register(&mut self, _addr: u16, _v: u8) {}
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self.ram.get(addr as usize - 0xA000) {
+            Some(&v) => v,
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, v: u8) -> bool {
+        match self.ram.get_mut(addr as usize - 0xA000) {
+            Some(cell) if *cell != v => {
+                *cell = v;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.ram)
+    }
+
+    fn battery_dump(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn battery_load(&mut self, data: &[u8]) {
+        let len = self.ram.len().min(data.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
@@ -1,8 +1,12 @@
 use std::fmt;
-use bitlab::SingleBits;
+use std::io;
+use apu::Apu;
 use ::{high_byte, low_byte};
 use join_bytes;
-use ppu::{GpuMode, Ppu};
+use ppu::{GpuMode, Ppu, Renderer};
+use serial::Serial;
+use state::{StateReader, StateWriter};
+use timer::Timer;
 
 pub mod opcodes;
 pub mod opcode;
@@ -38,6 +42,8 @@ pub enum Flag {
     NC,
 }
 
+/// Interrupt sources, in priority order
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum IterFlag {
     VBLANK,
     LCDSTAT,
@@ -46,6 +52,30 @@ pub enum IterFlag {
     JOYPAD
 }
 
+impl IterFlag {
+    /// Bit of the source in IE and IF
+    pub fn mask(&self) -> u8 {
+        match *self {
+            IterFlag::VBLANK => 0b0000_0001,
+            IterFlag::LCDSTAT => 0b0000_0010,
+            IterFlag::TIMER => 0b0000_0100,
+            IterFlag::SERIAL => 0b0000_1000,
+            IterFlag::JOYPAD => 0b0001_0000,
+        }
+    }
+
+    /// Address the cpu jumps to when servicing the interrupt
+    pub fn vector(&self) -> u16 {
+        match *self {
+            IterFlag::VBLANK => 0x40,
+            IterFlag::LCDSTAT => 0x48,
+            IterFlag::TIMER => 0x50,
+            IterFlag::SERIAL => 0x58,
+            IterFlag::JOYPAD => 0x60,
+        }
+    }
+}
+
 pub struct Cpu {
     /// Accumulator register
     a: u8,
@@ -59,18 +89,32 @@ pub struct Cpu {
     l: u8,
     sp: u16,
     pc: u16,
-    timer: u8,
     iter_master: bool,
+    /// EI was executed, IME is set once the next instruction starts
+    iter_master_delay: bool,
     iter_enable: u8,
     iter_flags: u8,
     ticks: u64,
+    /// Clock ticks at the normal speed since power on, the time that
+    /// passed whatever the speed of the cpu
+    clock: u64,
+    /// CGB double speed mode, the cpu runs twice as fast as the PPU
+    double_speed: bool,
     pub ppu: Ppu,
+    pub timer: Timer,
+    pub apu: Apu,
+    pub serial: Serial,
 }
 
 
 impl Cpu {
     /// Init a new Cpu instance
     pub fn new() -> Cpu {
+        Cpu::with_renderer(Renderer::Scanline)
+    }
+
+    /// Cpu whose PPU draws with the given renderer
+    pub fn with_renderer(renderer: Renderer) -> Cpu {
         let mut c = Cpu {
             a: 0,
             f: 0,
@@ -82,12 +126,17 @@ impl Cpu {
             l: 0,
             sp: 0,
             pc: 0,
-            timer: 0,
             iter_master: false,
+            iter_master_delay: false,
             iter_enable: 0,
             iter_flags: 0,
             ticks: 0,
-            ppu: Ppu::new()
+            clock: 0,
+            double_speed: false,
+            ppu: Ppu::with_renderer(renderer),
+            timer: Timer::new(),
+            apu: Apu::new(),
+            serial: Serial::new(),
         };
 
         c
@@ -109,11 +158,65 @@ impl Cpu {
         self.iter_enable = 0x0;
     }
 
+    /// Registers, interrupts, clock and the hardware the cpu drives
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for &v in &[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l] {
+            w.write_u8(v);
+        }
+        w.write_u16(self.sp);
+        w.write_u16(self.pc);
+        w.write_bool(self.iter_master);
+        w.write_bool(self.iter_master_delay);
+        w.write_u8(self.iter_enable);
+        w.write_u8(self.iter_flags);
+        w.write_u64(self.ticks);
+        w.write_u64(self.clock);
+        w.write_bool(self.double_speed);
+        self.ppu.save_state(w);
+        self.timer.save_state(w);
+        self.apu.save_state(w);
+        self.serial.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.a = r.read_u8()?;
+        self.f = r.read_u8()?;
+        self.b = r.read_u8()?;
+        self.c = r.read_u8()?;
+        self.d = r.read_u8()?;
+        self.e = r.read_u8()?;
+        self.h = r.read_u8()?;
+        self.l = r.read_u8()?;
+        self.sp = r.read_u16()?;
+        self.pc = r.read_u16()?;
+        self.iter_master = r.read_bool()?;
+        self.iter_master_delay = r.read_bool()?;
+        self.iter_enable = r.read_u8()?;
+        self.iter_flags = r.read_u8()? & 0x1F;
+        self.ticks = r.read_u64()?;
+        self.clock = r.read_u64()?;
+        self.double_speed = r.read_bool()?;
+        self.ppu.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.serial.load_state(r)
+    }
+
     pub fn ppu_step(&mut self) {
+        if !self.ppu.lcd_enabled() {
+            return;
+        }
+
+        if self.ppu.renderer == Renderer::Fifo {
+            self.fifo_step();
+            return;
+        }
+
         match self.ppu.mode {
             GpuMode::VRAM => {
                 if self.ppu.tick >= 172 {
                     self.ppu.tick %= 172;
+                    self.ppu.render_scanline();
                     self.ppu.mode = GpuMode::HBLANK;
                 }
             }
@@ -129,19 +232,30 @@ impl Cpu {
 
                     self.ppu.tick %= 456;
 
-                    if self.ppu.scanline > 154 {
+                    if self.ppu.scanline > 153 {
                         self.ppu.scanline = 0;
+                        self.ppu.window_line = 0;
+                        self.ppu.blank_frame = false;
                         self.ppu.mode = GpuMode::OAM;
                     }
 
                 }
             }
+            // Line 0 after the LCD is turned on reports HBLANK for the
+            // length of the OAM scan
+            GpuMode::HBLANK if self.ppu.first_line => {
+                if self.ppu.tick >= 80 {
+                    self.ppu.first_line = false;
+                    self.ppu.mode = GpuMode::VRAM;
+                    self.ppu.tick %= 80;
+                }
+            }
             GpuMode::HBLANK => {
                 if self.ppu.tick >= 204 {
                     self.ppu_hblank();
 
                     if self.ppu.scanline == 144 {
-                        self.set_iter_flag(IterFlag::VBLANK, true);
+                        self.enter_vblank();
                         self.ppu.mode = GpuMode::VBLANK;
                     } else {
                         self.ppu.mode = GpuMode::OAM;
@@ -151,32 +265,97 @@ impl Cpu {
                 }
             }
         }
+
+        if self.ppu.update_stat_line() {
+            self.set_iter_flag(IterFlag::LCDSTAT, true);
+        }
+    }
+
+    /// A frame is complete
+    fn enter_vblank(&mut self) {
+        self.set_iter_flag(IterFlag::VBLANK, true);
+        self.ppu.frames += 1;
+        self.apu.end_frame();
+    }
+
+    /// Run the FIFO renderer for the dots elapsed since the last step,
+    /// the STAT line is checked after every dot
+    fn fifo_step(&mut self) {
+        while self.ppu.tick > 0 {
+            self.ppu.tick -= 1;
+            if self.ppu.fifo_dot() {
+                self.enter_vblank();
+            }
+            if self.ppu.update_stat_line() {
+                self.set_iter_flag(IterFlag::LCDSTAT, true);
+            }
+        }
     }
 
     pub fn ppu_hblank(&mut self) {
         self.ppu.scanline += 1;
     }
 
-    pub fn interrupt_step(&mut self) {
-        if self.get_iter_flag_enable(IterFlag::VBLANK) && self.get_iter_flag(IterFlag::VBLANK) {
+    pub fn set_iter_master(&mut self, state: bool) {
+        self.iter_master = state;
+        self.iter_master_delay = false;
+    }
+
+    /// EI, interrupts are enabled after the following instruction
+    pub fn set_iter_master_delayed(&mut self) {
+        self.iter_master_delay = true;
+    }
 
+    /// Apply a pending EI, called before each instruction
+    pub fn update_iter_master(&mut self) {
+        if self.iter_master_delay {
+            self.iter_master_delay = false;
+            self.iter_master = true;
         }
     }
 
-    pub fn handle_vblank_inter(&mut self) {
-        self.iter_master = false;
+    pub fn get_iter_master(&self) -> bool {
+        self.iter_master
     }
 
-    pub fn write_to_stack(&mut self, addr: u16) {
+    /// An enabled interrupt is requested, whatever IME says
+    pub fn interrupt_pending(&self) -> bool {
+        self.next_interrupt().is_some()
+    }
 
+    /// Highest priority interrupt both enabled and requested
+    pub fn next_interrupt(&self) -> Option<IterFlag> {
+        let pending = self.iter_enable & self.iter_flags;
+        [IterFlag::VBLANK, IterFlag::LCDSTAT, IterFlag::TIMER, IterFlag::SERIAL, IterFlag::JOYPAD]
+            .iter()
+            .cloned()
+            .find(|flag| pending & flag.mask() != 0)
     }
 
-    pub fn set_iter_master(&mut self, state: bool) {
-        self.iter_master = state;
+    /// IE, 0xFFFF
+    pub fn get_iter_enable(&self) -> u8 {
+        self.iter_enable
     }
 
-    pub fn get_iter_master(&self) -> bool {
-        self.iter_master
+    pub fn set_iter_enable(&mut self, v: u8) {
+        self.iter_enable = v;
+    }
+
+    /// IF, 0xFF0F, the 3 upper bits are not wired and read as 1
+    pub fn get_iter_flags(&self) -> u8 {
+        self.iter_flags | 0xE0
+    }
+
+    pub fn set_iter_flags(&mut self, v: u8) {
+        self.iter_flags = v & 0x1F;
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    pub fn toggle_speed(&mut self) {
+        self.double_speed = !self.double_speed;
     }
 
     /// Get a 8bit value from register
@@ -243,54 +422,29 @@ impl Cpu {
         }
     }
 
+    /// Request or acknowledge an interrupt
     pub fn set_iter_flag(&mut self, flag: IterFlag, set: bool) {
-        match (flag, set) {
-            (IterFlag::VBLANK, true) => self.iter_flags = self.iter_flags | 0b1000_0000,
-            (IterFlag::LCDSTAT, true) => self.iter_flags = self.iter_flags | 0b0100_0000,
-            (IterFlag::TIMER, true) => self.iter_flags = self.iter_flags | 0b0010_0000,
-            (IterFlag::SERIAL, true) => self.iter_flags = self.iter_flags | 0b0001_0000,
-            (IterFlag::JOYPAD, true) => self.iter_flags = self.iter_flags | 0b0000_1000,
-            (IterFlag::VBLANK, false) => self.iter_flags = self.iter_flags & 0b1000_0000,
-            (IterFlag::LCDSTAT, false) => self.iter_flags = self.iter_flags & 0b0100_0000,
-            (IterFlag::TIMER, false) => self.iter_flags = self.iter_flags & 0b0010_0000,
-            (IterFlag::SERIAL, false) => self.iter_flags = self.iter_flags & 0b0001_0000,
-            (IterFlag::JOYPAD, false) => self.iter_flags = self.iter_flags & 0b0000_1000,
+        if set {
+            self.iter_flags |= flag.mask();
+        } else {
+            self.iter_flags &= !flag.mask();
         }
     }
 
     pub fn get_iter_flag(&self, flag: IterFlag) -> bool {
-        match flag {
-            IterFlag::VBLANK => ((self.iter_flags & 0b1000_0000) as u8 >> 7 ) == 1,
-            IterFlag::LCDSTAT => ((self.iter_flags & 0b0100_0000) as u8 >> 6 ) == 1,
-            IterFlag::TIMER => ((self.iter_flags & 0b0010_0000) as u8 >> 5 ) == 1,
-            IterFlag::SERIAL => ((self.iter_flags & 0b0001_0000) as u8 >> 4 ) == 1,
-            IterFlag::JOYPAD => ((self.iter_flags & 0b0000_1000) as u8 >> 3 ) == 1,
-        }
+        self.iter_flags & flag.mask() != 0
     }
 
     pub fn set_iter_flag_enable(&mut self, flag: IterFlag, set: bool) {
-        match (flag, set) {
-            (IterFlag::VBLANK, true) => self.iter_enable = self.iter_enable | 0b1000_0000,
-            (IterFlag::LCDSTAT, true) => self.iter_enable = self.iter_enable | 0b0100_0000,
-            (IterFlag::TIMER, true) => self.iter_enable = self.iter_enable | 0b0010_0000,
-            (IterFlag::SERIAL, true) => self.iter_enable = self.iter_enable | 0b0001_0000,
-            (IterFlag::JOYPAD, true) => self.iter_enable = self.iter_enable | 0b0000_1000,
-            (IterFlag::VBLANK, false) => self.iter_enable = self.iter_enable & 0b1000_0000,
-            (IterFlag::LCDSTAT, false) => self.iter_enable = self.iter_enable & 0b0100_0000,
-            (IterFlag::TIMER, false) => self.iter_enable = self.iter_enable & 0b0010_0000,
-            (IterFlag::SERIAL, false) => self.iter_enable = self.iter_enable & 0b0001_0000,
-            (IterFlag::JOYPAD, false) => self.iter_enable = self.iter_enable & 0b0000_1000,
+        if set {
+            self.iter_enable |= flag.mask();
+        } else {
+            self.iter_enable &= !flag.mask();
         }
     }
 
     pub fn get_iter_flag_enable(&self, flag: IterFlag) -> bool {
-        match flag {
-            IterFlag::VBLANK => ((self.iter_enable & 0b1000_0000) as u8 >> 7 ) == 1,
-            IterFlag::LCDSTAT => ((self.iter_enable & 0b0100_0000) as u8 >> 6 ) == 1,
-            IterFlag::TIMER => ((self.iter_enable & 0b0010_0000) as u8 >> 5 ) == 1,
-            IterFlag::SERIAL => ((self.iter_enable & 0b0001_0000) as u8 >> 4 ) == 1,
-            IterFlag::JOYPAD => ((self.iter_enable & 0b0000_1000) as u8 >> 3 ) == 1,
-        }
+        self.iter_enable & flag.mask() != 0
     }
 
     /// Set a flags
@@ -371,15 +525,58 @@ impl Cpu {
 
     /// Inc PC by x
     pub fn inc_pc(&mut self, inc: u8) {
-        self.pc += inc as u16;
+        self.pc = self.pc.wrapping_add(inc as u16);
+    }
+
+    /// Clock ticks elapsed since power on
+    pub fn ticks(&self) -> u64 {
+        self.ticks
     }
 
+    /// Clock ticks at the normal speed since power on, they go at half
+    /// the rate of `ticks` in double speed mode
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Divider bit whose falling edge clocks the APU frame sequencer
+    fn frame_sequencer_bit(&self) -> bool {
+        let bit = if self.double_speed { 0x2000 } else { 0x1000 };
+        self.timer.divider & bit != 0
+    }
+
+    /// Write a timer register, resetting DIV may clock the frame sequencer
+    pub fn write_timer(&mut self, addr: u16, v: u8) {
+        let before = self.frame_sequencer_bit();
+        self.timer.write_byte(addr, v);
+        if before && !self.frame_sequencer_bit() {
+            self.apu.clock_frame_sequencer();
+        }
+    }
+
+    /// Advance the clock by some machine cycles (4 clock ticks each,
+    /// only 2 for the PPU and the APU in double speed mode)
     pub fn inc_ticks(&mut self, inc: u8) {
-        self.ticks += inc as u64;
-        if self.ppu.control.get_bit(0).unwrap() {
-            self.ppu.tick += inc as u64;
+        let ticks = inc as u64 * 4;
+        self.ticks += ticks;
+        for _ in 0..inc {
+            let before = self.frame_sequencer_bit();
+            if self.timer.step() {
+                self.set_iter_flag(IterFlag::TIMER, true);
+            }
+            if before && !self.frame_sequencer_bit() {
+                self.apu.clock_frame_sequencer();
+            }
+            self.apu.step(if self.double_speed { 2 } else { 4 });
+            if self.serial.step(4, self.ticks) {
+                self.set_iter_flag(IterFlag::SERIAL, true);
+            }
+        }
+        let elapsed = if self.double_speed { ticks / 2 } else { ticks };
+        self.clock += elapsed;
+        if self.ppu.lcd_enabled() {
+            self.ppu.tick += elapsed;
         }
-        println!("added ticks +{} = {}", inc, self.ticks * 8);
     }
 }
 
@@ -484,3 +681,80 @@ fn flags() {
     cpu.set_flag(Flag::C, false);
     assert_eq!(cpu.get_8(Register8::F), 0b00000000);
 }
+
+#[test]
+fn interrupt_flags() {
+    let mut cpu = Cpu::new();
+
+    cpu.set_iter_flag(IterFlag::TIMER, true);
+    cpu.set_iter_flag(IterFlag::JOYPAD, true);
+    assert_eq!(cpu.get_iter_flags(), 0xF4);
+    cpu.set_iter_flag(IterFlag::JOYPAD, false);
+    assert_eq!(cpu.get_iter_flags(), 0xE4);
+    assert_eq!(cpu.next_interrupt(), None);
+
+    cpu.set_iter_enable(0xFF);
+    cpu.set_iter_flag(IterFlag::LCDSTAT, true);
+    assert_eq!(cpu.next_interrupt(), Some(IterFlag::LCDSTAT));
+}
+
+#[test]
+fn lcd_first_line() {
+    let mut cpu = Cpu::new();
+    cpu.inc_ticks(100);
+    cpu.ppu_step();
+    assert_eq!(cpu.ppu.tick, 0);
+
+    cpu.ppu.write_byte(0xFF40, 0x80);
+    cpu.inc_ticks(20);
+    cpu.ppu_step();
+    assert_eq!(cpu.ppu.mode, GpuMode::VRAM);
+    cpu.inc_ticks(43);
+    cpu.ppu_step();
+    cpu.inc_ticks(51);
+    cpu.ppu_step();
+    assert_eq!((cpu.ppu.scanline, cpu.ppu.mode), (1, GpuMode::OAM));
+}
+
+#[test]
+fn frame_sequencer_from_div() {
+    let mut cpu = Cpu::new();
+    cpu.apu.write_byte(0xFF26, 0x80);
+    cpu.apu.write_byte(0xFF21, 0xF0);
+    cpu.apu.write_byte(0xFF20, 63);
+    cpu.apu.write_byte(0xFF23, 0xC0);
+
+    // DIV bit 4 goes back low after 8192 clock ticks
+    cpu.inc_ticks(255);
+    for _ in 0..7 {
+        cpu.inc_ticks(255);
+    }
+    assert!(cpu.apu.noise.enabled);
+    cpu.inc_ticks(8);
+    assert!(!cpu.apu.noise.enabled);
+
+    // Resetting DIV while the bit is high clocks it too
+    cpu.apu.write_byte(0xFF23, 0xC0);
+    cpu.inc_ticks(255);
+    cpu.inc_ticks(255);
+    cpu.inc_ticks(255);
+    cpu.inc_ticks(255);
+    cpu.inc_ticks(10);
+    cpu.write_timer(0xFF04, 0);
+    assert_eq!(cpu.apu.sequencer_step, 2);
+}
+
+#[test]
+fn clock_at_normal_speed() {
+    let mut cpu = Cpu::new();
+    cpu.inc_ticks(10);
+    cpu.toggle_speed();
+    cpu.inc_ticks(10);
+    assert_eq!((cpu.ticks(), cpu.clock()), (80, 60));
+
+    let mut w = StateWriter::new();
+    cpu.save_state(&mut w);
+    let mut restored = Cpu::new();
+    restored.load_state(&mut StateReader::new(&w.into_inner())).unwrap();
+    assert_eq!((restored.ticks(), restored.clock(), restored.double_speed()), (80, 60, true));
+}
@@ -6,8 +6,8 @@ static SIZE_TABLE: [u8; 256] = [
  // 0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
     1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0
     2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 1
-    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 2
-    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 3
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 2
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 3
     1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 4
     1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 5
     1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 6
@@ -19,9 +19,10 @@ static SIZE_TABLE: [u8; 256] = [
     1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // c
     1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // d
     2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // e
-    2, 1, 2, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // f
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // f
 ];
 
+/// Machine cycles, for conditional opcodes when the branch is not taken
 static TICK_TABLE: [u8; 256] = [
  // 0  1  2  3  4  5  6  7  8   9  a  b  c  d  e  f
     1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,// 0
@@ -60,7 +61,8 @@ static TICK_TABLE: [u8; 256] = [
 3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,e
 3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4 f
 */
-static CB_TICK_TABLE: [u8; 256] = [
+/// Machine cycles of the 0xCB prefixed opcodes, prefix included
+pub static CB_TICK_TABLE: [u8; 256] = [
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
     2, 2, 2, 2, 2, 2, 4, 2, 2, 2, 2, 2, 2, 2, 4, 2,
@@ -95,8 +97,8 @@ impl Opcode {
     pub fn fetch_param(&mut self, gb: &GameBoy) -> u16 {
         let param = match self.length {
             1 => 0,
-            2 => gb.read_byte(gb.cpu.get_16(Register16::PC) + 1) as u16,
-            3 => gb.read_word(gb.cpu.get_16(Register16::PC) + 1),
+            2 => gb.read_byte(gb.cpu.get_16(Register16::PC).wrapping_add(1)) as u16,
+            3 => gb.read_word(gb.cpu.get_16(Register16::PC).wrapping_add(1)),
             _ => unreachable!()
         };
         self.param = param;
@@ -119,7 +121,7 @@ impl From<u8> for Opcode {
             opcode: op,
             length: SIZE_TABLE[op as usize],
             param: 0,
-            ticks: if op == 0xcb {CB_TICK_TABLE[op as usize]}else{TICK_TABLE[op as usize]},
+            ticks: TICK_TABLE[op as usize],
             x: (op & 0b1100_0000) as u8 >> 6,
             y,
             z: (op & 0b0000_0111) as u8,
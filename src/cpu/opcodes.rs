@@ -1,30 +1,48 @@
-use std::borrow::BorrowMut;
-use std::num::Wrapping;
-use std::process::exit;
 use bitlab::SingleBits;
 use gameboy::GameBoy;
 use cpu::{Flag, Register16, Register8};
-use cpu::opcode::Opcode;
-use ::{high_byte, reverse_endian, low_byte, join_bytes};
+use cpu::opcode::{Opcode, CB_TICK_TABLE};
+use low_byte;
 
 /// Iterate the ROM
 pub fn decode(gb: &mut GameBoy) {
+    // An illegal opcode froze the cpu, only a reset brings it back
+    if gb.locked {
+        gb.cpu.inc_ticks(1);
+        return;
+    }
+
+    gb.cpu.update_iter_master();
+
+    if gb.stopped {
+        if !gb.joypad_line_low() {
+            return;
+        }
+        gb.stopped = false;
+    }
+
+    if gb.halted {
+        if gb.cpu.interrupt_pending() {
+            gb.halted = false;
+        }
+        gb.cpu.inc_ticks(1);
+        return;
+    }
+
     let pc = gb.cpu.get_16(Register16::PC);
     let mut op = Opcode::from(gb.read_byte(pc));
-    op.fetch_param(&gb);
-
+    if gb.halt_bug {
+        // The opcode byte is read again as the next one, or as its own
+        // first operand
+        gb.halt_bug = false;
+        gb.cpu.set_16(Register16::PC, pc.wrapping_sub(1));
+    }
+    op.fetch_param(gb);
 
     // Debug
     // println!("op = {:#02x}, pc = {:#04x}", op.opcode, gb.cpu.get_16(Register16::PC));
     // println!("{:?}", gb.cpu);
 
-    // Call a function who get the current opcode and a mutable gameboy instance
-    // should incr the PC and returns arguments*
-
-    if gb.cpu.get_16(Register16::PC) == 0xA3 {
-        op_nop();
-    }
-
     let mut add_op_length: bool = true;
 
     // X, Y, Z, P, Q
@@ -33,18 +51,15 @@ pub fn decode(gb: &mut GameBoy) {
         // Z = 0
         (0, 0, 0, _, _) => {
             // NOOP
-            op_nop()
         },
         (0, 1, 0, _, _) => {
             // LD (nn), SP
             op_ld_mem16(gb, op.param, gb.cpu.get_16(Register16::SP));
         },
-       /* (0, 2, 0, _, _) => {
+        (0, 2, 0, _, _) => {
             // STOP
-            gb.stopped = true;
-            println!("STOP");
-            exit(1);
-        },*/
+            gb.stop();
+        },
         (0, 3, 0, _, _) => {
             // JR d
             let pc_val = gb.cpu.get_16(Register16::PC);
@@ -62,22 +77,13 @@ pub fn decode(gb: &mut GameBoy) {
             }
         },
         // Z = 1
-        (0, 4, 2, 2, 0) => {
-            // LD (HL+), A
-            gb.write_byte(
-                gb.cpu.get_16(Register16::HL),
-                gb.cpu.get_8(Register8::A)
-            );
-            gb.cpu.set_16(Register16::HL, gb.cpu.get_16(Register16::HL) + 1);
-        }
         (0, _, 1, _, 0) => {
             // LD rp[p], nn
             op_ld_reg16(gb, op.p, op.param);
-
         },
         (0, _, 1, _, 1) => {
             // ADD HL, rp[p]
-            op_add16(gb, Register16::HL, op.p)
+            op_add16(gb, op.p)
         },
 
         // Z = 2
@@ -86,51 +92,51 @@ pub fn decode(gb: &mut GameBoy) {
             // LD (BC), A
             op_ld_mem8(gb, gb.cpu.get_16(Register16::BC), gb.cpu.get_8(Register8::A))
         },
-        (0, _, 2, 1, 0)  => {
+        (0, _, 2, 1, 0) => {
             // LD (DE), A
             op_ld_mem8(gb, gb.cpu.get_16(Register16::DE), gb.cpu.get_8(Register8::A))
         },
-        (0, _, 2, 2, 0)  => {
-            // LD (nn), HL
-            gb.write_word(op.param, gb.cpu.get_16(Register16::HL))
+        (0, _, 2, 2, 0) => {
+            // LD (HL+), A
+            let hl = gb.cpu.get_16(Register16::HL);
+            op_ld_mem8(gb, hl, gb.cpu.get_8(Register8::A));
+            gb.cpu.set_16(Register16::HL, hl.wrapping_add(1));
         },
-        (0, _, 2, 3, 0)  => {
+        (0, _, 2, 3, 0) => {
             // LD (HL-), A
-            gb.write_byte(gb.cpu.get_16(Register16::HL), gb.cpu.get_8(Register8::A));
-            gb.cpu.set_16(Register16::HL, gb.cpu.get_16(Register16::HL) - 1);
+            let hl = gb.cpu.get_16(Register16::HL);
+            op_ld_mem8(gb, hl, gb.cpu.get_8(Register8::A));
+            gb.cpu.set_16(Register16::HL, hl.wrapping_sub(1));
         },
         // Q = 1
-        (0, _, 2, 0, 1)  => {
+        (0, _, 2, 0, 1) => {
             // LD A, (BC)
             gb.cpu.set_8(Register8::A, gb.read_byte(gb.cpu.get_16(Register16::BC)));
         },
-        (0, _, 2, 1, 1)  => {
+        (0, _, 2, 1, 1) => {
             // LD A, (DE)
             gb.cpu.set_8(Register8::A, gb.read_byte(gb.cpu.get_16(Register16::DE)));
         },
-        (0, _, 2, 2, 1)  => {
-            // LD HL, (nn)
-            gb.cpu.set_16(Register16::HL, gb.read_word(op.param));
+        (0, _, 2, 2, 1) => {
+            // LD A, (HL+)
+            let hl = gb.cpu.get_16(Register16::HL);
+            gb.cpu.set_8(Register8::A, gb.read_byte(hl));
+            gb.cpu.set_16(Register16::HL, hl.wrapping_add(1));
         },
         (0, _, 2, 3, 1) => {
-            // LD A, (nn)
-            gb.cpu.set_8(Register8::A, gb.read_byte(op.param));
-
+            // LD A, (HL-)
+            let hl = gb.cpu.get_16(Register16::HL);
+            gb.cpu.set_8(Register8::A, gb.read_byte(hl));
+            gb.cpu.set_16(Register16::HL, hl.wrapping_sub(1));
         },
         // Z = 3
         (0, _, 3, _, 0) => {
             // INC rp[p]
-            // op_inc16(gb, op.p)
-            gb.set_table_rp(op.p, (Wrapping(gb.get_table_rp(op.p)) + Wrapping(1)).0);
+            gb.set_table_rp(op.p, gb.get_table_rp(op.p).wrapping_add(1));
         },
         (0, _, 3, _, 1) => {
             // DEC rp[p]
-            let mut value = gb.get_table_rp(op.p);
-            gb.cpu.set_flag(Flag::H, value & 0x0F == 0);
-            value -= 1;
-            gb.cpu.set_flag(Flag::Z, value == 0);
-            gb.cpu.set_flag(Flag::N, true);
-            gb.set_table_rp(op.p, value);
+            gb.set_table_rp(op.p, gb.get_table_rp(op.p).wrapping_sub(1));
         },
         // Z = 4
         (0, _, 4, _, _) => {
@@ -139,13 +145,7 @@ pub fn decode(gb: &mut GameBoy) {
         },
         (0, _, 5, _, _) => {
             // DEC r[y]
-            let mut value = gb.get_table_r(op.y);
-            gb.cpu.set_flag(Flag::H, value & 0x0F == 0);
-            value = (Wrapping(value) - Wrapping(1)).0;
-            gb.cpu.set_flag(Flag::Z, value == 0);
-            gb.cpu.set_flag(Flag::N, true);
-
-            gb.set_table_r(op.y, value);
+            op_dec8(gb, op.y)
         },
         (0, _, 6, _, _) => {
             // LD r[y], n
@@ -156,12 +156,12 @@ pub fn decode(gb: &mut GameBoy) {
         (0, 1, 7, _, _) => rrca(gb), // RRCA
         (0, 2, 7, _, _) => rla(gb), // RLA
         (0, 3, 7, _, _) => rra(gb), // RRA
-        (0, 4, 7, _, _) => daa(gb), // DAA //TODO: Impl DAA
+        (0, 4, 7, _, _) => daa(gb), // DAA
         (0, 5, 7, _, _) => cpl(gb), // CPL
         (0, 6, 7, _, _) => scf(gb), // SCF
         (0, 7, 7, _, _) => ccf(gb), // CCF
         // X = 1
-        (1, 6, 6, _, _) => exit(2), // HALT
+        (1, 6, 6, _, _) => op_halt(gb), // HALT
         (1, _, _, _, _) => {
             // LD r[y], r[z]
             let operand = gb.get_table_r(op.z);
@@ -172,51 +172,44 @@ pub fn decode(gb: &mut GameBoy) {
             call_alu_table_r(gb, op.z, op.y)
         }, // alu[y] r[z]
         // X = 3
+        // Z = 0
         (3, 0..=3, 0, _, _) => {
+            // RET cc[y]
             if gb.get_table_cc(op.y) {
                 op_ret(gb);
                 add_op_length = false;
-                op.ticks = 20;
+                op.ticks = 5;
             } else {
-                op.ticks = 8;
+                op.ticks = 2;
             }
-        }, // RET cc[y]
-        (3, 4, 0, 2, 0) => {
-            //LDH (n), a
-            gb.write_byte(
-                0xff00 + op.param,
-                gb.cpu.get_8(Register8::A)
-            )
-        }
+        },
+        (3, 4, 0, _, _) => {
+            // LD (0xFF00 + n), A
+            gb.write_byte(0xFF00 | op.param, gb.cpu.get_8(Register8::A))
+        },
         (3, 5, 0, _, _) => {
-            //LDH (C), a
-            gb.write_byte(
-                0xff00 + gb.cpu.get_8(Register8::C) as u16,
-                gb.cpu.get_8(Register8::A)
-            )
-        }
+            // ADD SP, d
+            let res = add_sp_d(gb, low_byte!(op.param));
+            gb.cpu.set_16(Register16::SP, res);
+        },
         (3, 6, 0, _, _) => {
-            //LD A, 0Xff00 + n
-            gb.cpu.set_8(
-                Register8::A,
-                gb.read_byte(
-                    0xff00 + op.param
-                )
-            );
-        }
+            // LD A, (0xFF00 + n)
+            gb.cpu.set_8(Register8::A, gb.read_byte(0xFF00 | op.param));
+        },
         (3, 7, 0, _, _) => {
-            //LD A, 0Xff00 + C
-            gb.cpu.set_8(
-                Register8::A,
-                gb.read_byte(
-                    0xff00 + gb.cpu.get_8(Register8::C) as u16
-                )
-            );
-        }
+            // LD HL, SP + d
+            let res = add_sp_d(gb, low_byte!(op.param));
+            gb.cpu.set_16(Register16::HL, res);
+        },
+        // Z = 1
+        (3, _, 1, 3, 0) => {
+            // POP AF, the low nibble of F does not exist
+            let poped = gb.pop_stack();
+            gb.set_table_rp2(3, poped & 0xFFF0);
+        },
         (3, _, 1, _, 0) => {
-            //POP rp2[p]
-            let poped = gb.read_word(gb.cpu.get_16(Register16::SP));
-            gb.cpu.set_16(Register16::SP, gb.cpu.get_16(Register16::SP) + 2);
+            // POP rp2[p]
+            let poped = gb.pop_stack();
             gb.set_table_rp2(op.p, poped);
         },
         (3, _, 1, 0, 1) => {
@@ -224,42 +217,54 @@ pub fn decode(gb: &mut GameBoy) {
             op_ret(gb);
             add_op_length = false;
         },
-        /*(3, _, 1, 1, 1) => {
-            //EXX
-            op_nop()
-        },*/
+        (3, _, 1, 1, 1) => {
+            // RETI
+            gb.reti();
+            add_op_length = false;
+        },
         (3, _, 1, 2, 1) => {
-            //JP HL
+            // JP HL
             gb.cpu.set_16(Register16::PC, gb.cpu.get_16(Register16::HL));
             add_op_length = false;
         },
         (3, _, 1, 3, 1) => {
-            //LD SP,HL
+            // LD SP, HL
             gb.cpu.set_16(Register16::SP, gb.cpu.get_16(Register16::HL));
         },
-        (3, 4, 2, 2, 0) => {
-            // LD (FF00h+C), A
-            gb.write_byte(
-                0xFF00 + gb.cpu.get_8(Register8::C) as u16,
-                gb.cpu.get_8(Register8::A)
-            );
-        },
+        // Z = 2
         (3, 0..=3, 2, _, _) => {
             // JP cc[y], nn
             if gb.get_table_cc(op.y) {
                 gb.cpu.set_16(Register16::PC, op.param);
                 add_op_length = false;
-                op.ticks = 16;
+                op.ticks = 4;
             } else {
-                op.ticks = 12;
+                op.ticks = 3;
             }
         },
-        (3, 5, 2, 2, 1) => {
-            //LD (nn), a
+        (3, 4, 2, _, _) => {
+            // LD (0xFF00 + C), A
+            gb.write_byte(
+                0xFF00 | gb.cpu.get_8(Register8::C) as u16,
+                gb.cpu.get_8(Register8::A)
+            );
+        },
+        (3, 5, 2, _, _) => {
+            // LD (nn), A
             gb.write_byte(op.param, gb.cpu.get_8(Register8::A));
-        }
+        },
+        (3, 6, 2, _, _) => {
+            // LD A, (0xFF00 + C)
+            let addr = 0xFF00 | gb.cpu.get_8(Register8::C) as u16;
+            gb.cpu.set_8(Register8::A, gb.read_byte(addr));
+        },
+        (3, 7, 2, _, _) => {
+            // LD A, (nn)
+            gb.cpu.set_8(Register8::A, gb.read_byte(op.param));
+        },
+        // Z = 3
         (3, 0, 3, _, _) => {
-            //JP nn
+            // JP nn
             gb.cpu.set_16(Register16::PC, op.param);
             add_op_length = false;
         },
@@ -268,6 +273,7 @@ pub fn decode(gb: &mut GameBoy) {
             let x = (op.param & 0b1100_0000) as u8 >> 6;
             let y = (op.param & 0b0011_1000) as u8 >> 3;
             let z = (op.param & 0b0000_0111) as u8;
+            op.ticks = CB_TICK_TABLE[op.param as usize];
             match x {
                 0 => {
                     let res = gb.exec_table_rot(y, gb.get_table_r(z));
@@ -276,133 +282,111 @@ pub fn decode(gb: &mut GameBoy) {
                 1 => op_bit(gb, y, z),
                 2 => op_res(gb, y, z),
                 3 => op_set(gb, y, z),
-                _ => op_nop(),
+                _ => unreachable!(),
             }
         },
         (3, 6, 3, _, _) => {
-            //DI
+            // DI
             gb.cpu.set_iter_master(false);
         },
         (3, 7, 3, _, _) => {
-            //EI
-            gb.cpu.set_iter_master(true);
+            // EI
+            gb.cpu.set_iter_master_delayed();
         },
+        // Z = 4
         (3, 0..=3, 4, _, _) => {
+            // CALL cc[y], nn
             if gb.get_table_cc(op.y) {
-                gb.cpu.set_16(Register16::SP, gb.cpu.get_16(Register16::PC) + op.length as u16);
+                let ret = gb.cpu.get_16(Register16::PC).wrapping_add(op.length as u16);
+                gb.write_to_stack(ret);
                 gb.cpu.set_16(Register16::PC, op.param);
                 add_op_length = false;
-                op.ticks = 24;
+                op.ticks = 6;
             } else {
-                op.ticks = 12;
+                op.ticks = 3;
             }
-        }, // CALL cc[y], nn
+        },
+        // Z = 5
         (3, _, 5, _, 0) => {
-            //PUSH rp2[p]
-            gb.cpu.set_16(Register16::SP, gb.cpu.get_16(Register16::SP) - 2);
-            gb.write_word(gb.cpu.get_16(Register16::SP), gb.get_table_rp2(op.p));
+            // PUSH rp2[p]
+            gb.write_to_stack(gb.get_table_rp2(op.p));
         },
         (3, _, 5, 0, 1) => {
             // CALL nn
-            gb.cpu.set_16(Register16::SP, gb.cpu.get_16(Register16::SP) - 2);
-            gb.write_word(gb.cpu.get_16(Register16::SP), gb.cpu.get_16(Register16::PC) + op.length as u16);
+            let ret = gb.cpu.get_16(Register16::PC).wrapping_add(op.length as u16);
+            gb.write_to_stack(ret);
             gb.cpu.set_16(Register16::PC, op.param);
             add_op_length = false;
         },
-        /*(3, _, 5, 1, 1) => {
-            // DD
-        },
-        (3, _, 5, 2, 1) => {
-            // ED
-        },
-        (3, _, 5, 3, 1) => {
-            // FD
-        },*/
+        // Z = 6
         (3, _, 6, _, _) => {
             // alu[y] n
             exec_alu(gb, op.y, op.param as u8);
         },
+        // Z = 7
         (3, _, 7, _, _) => {
             // RST y*8
-            gb.cpu.set_16(Register16::SP, gb.cpu.get_16(Register16::PC) + op.length as u16);
+            let ret = gb.cpu.get_16(Register16::PC).wrapping_add(op.length as u16);
+            gb.write_to_stack(ret);
             gb.cpu.set_16(Register16::PC, op.y as u16 * 8);
             add_op_length = false;
         },
-        _ => op_not_implemented(op.opcode),
-    }
-
-    if op.ticks == 0 {
-        println!("OP HAS NO TICKS");
-        exit(100);
+        // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD
+        _ => {
+            op_illegal(gb);
+            add_op_length = false;
+            op.ticks = 1;
+        },
     }
 
-
     if add_op_length {
         gb.cpu.inc_pc(op.length);
     }
-    println!("OP {:#02x} ADDED ", op.opcode);
     gb.cpu.inc_ticks(op.ticks);
 }
 
-fn op_not_implemented(op: u8) {
-    println!("OP {:#02x} NOT IMPLEMENTED", op);
-    exit(1);
+/// Without IME, HALT does not wait for an interrupt already pending and
+/// triggers the HALT bug instead
+fn op_halt(gb: &mut GameBoy) {
+    if !gb.cpu.get_iter_master() && gb.cpu.interrupt_pending() {
+        gb.halt_bug = true;
+    } else {
+        gb.halted = true;
+    }
 }
 
-fn op_nop(){
-    println!("nop")
+/// The hardware hangs on the unused opcodes, interrupts included
+fn op_illegal(gb: &mut GameBoy) {
+    gb.locked = true;
 }
 
 fn op_res(gb: &mut GameBoy, y: u8, z: u8) {
-    let mut r = gb.get_table_r(z);
-    gb.set_table_r(z, r.clear_bit(y as u32).unwrap());
+    let r = gb.get_table_r(z);
+    gb.set_table_r(z, r.clear_bit(7 - y as u32).unwrap());
 }
 
 fn op_set(gb: &mut GameBoy, y: u8, z: u8) {
-    let mut r = gb.get_table_r(z);
-    gb.set_table_r(z, r.set_bit(y as u32).unwrap());
+    let r = gb.get_table_r(z);
+    gb.set_table_r(z, r.set_bit(7 - y as u32).unwrap());
 }
 
 fn op_bit(gb: &mut GameBoy, y: u8, z: u8) {
     let value = gb.get_table_r(z);
-    if value.get_bit(7 - y as u32).unwrap() {
-        gb.cpu.set_flag(Flag::Z, false);
-    } else {
-        gb.cpu.set_flag(Flag::Z, true);
-    }
+    gb.cpu.set_flag(Flag::Z, !value.get_bit(7 - y as u32).unwrap());
     gb.cpu.set_flag(Flag::N, false);
     gb.cpu.set_flag(Flag::H, true);
 }
 
-fn add8(gb: &mut GameBoy, val: u8, add: u8) -> u8 {
-    gb.cpu.set_flag(Flag::H, (val & 0x0F) == 0x0F);
-    let val = (val + add) as u16;
-    gb.cpu.set_flag(Flag::C, val > 0xFF);
-    gb.cpu.set_flag(Flag::Z, val == 0);
-    //gb.cpu.set_flag(Flag::S, (val > 0x7F) && (val < 0xFF));
-    gb.cpu.set_flag(Flag::N, false);
-    val as u8
-}
-
-fn add16(gb: &mut GameBoy, val: u16, add: u16) -> u16 {
-    gb.cpu.set_flag(Flag::H, ((val & 0x0F) + (add & 0x0F)) > 0x0F);
-    let val = (val + add) as u32;
-    gb.cpu.set_flag(Flag::C, val > 0xFFFF);
-    gb.cpu.set_flag(Flag::N, false);
-    val as u16
-}
+/// SP plus a signed byte, flags are computed on the low byte as for an
+/// unsigned 8bit add
+fn add_sp_d(gb: &mut GameBoy, d: u8) -> u16 {
+    let sp = gb.cpu.get_16(Register16::SP);
+    let d = d as i8 as u16;
 
-fn minus8(gb: &mut GameBoy, val: u8, minus: u8) -> u8 {
-    gb.cpu.set_flag(Flag::H, val & 0x0F == 0);
-    let val = (val - minus) as i16;
-    gb.cpu.set_flag(Flag::C, val < 0);
-    gb.cpu.set_flag(Flag::Z, val == 0);
-    gb.cpu.set_flag(Flag::N, true);
-    val as u8
-}
-
-fn op_ld_reg8(gb: &mut GameBoy, reg: Register8, operand: u8) {
-    gb.cpu.set_8(reg, operand);
+    gb.cpu.reset_flags();
+    gb.cpu.set_flag(Flag::H, (sp & 0x0F) + (d & 0x0F) > 0x0F);
+    gb.cpu.set_flag(Flag::C, (sp & 0xFF) + (d & 0xFF) > 0xFF);
+    sp.wrapping_add(d)
 }
 
 fn op_ld_reg16(gb: &mut GameBoy, index: u8, operand: u16) {
@@ -421,29 +405,38 @@ fn op_ld_8(gb: &mut GameBoy, index: u8, operand: u8) {
     gb.set_table_r(index, operand);
 }
 
+/// The carry flag is left untouched
 fn op_inc8(gb: &mut GameBoy, index: u8) {
-    let res = add8(gb, gb.get_table_r(index), 1);
-    gb.set_table_r(index, res);
-}
+    let value = gb.get_table_r(index);
+    let res = value.wrapping_add(1);
 
-fn op_dec16(gb: &mut GameBoy, index: u8) {
-    let res = gb.get_table_rp(index) - 1;
-    gb.set_table_rp(index, res);
+    gb.cpu.set_flag(Flag::Z, res == 0);
+    gb.cpu.set_flag(Flag::N, false);
+    gb.cpu.set_flag(Flag::H, value & 0x0F == 0x0F);
+    gb.set_table_r(index, res);
 }
 
+/// The carry flag is left untouched
 fn op_dec8(gb: &mut GameBoy, index: u8) {
-    let res = minus8(gb, gb.get_table_r(index), 1);
-    gb.set_table_r(index, res);
-}
+    let value = gb.get_table_r(index);
+    let res = value.wrapping_sub(1);
 
-fn op_add8(gb: &mut GameBoy, index: u8, operand: u8) {
-    let res = add8(gb, gb.get_table_r(index), operand);
+    gb.cpu.set_flag(Flag::Z, res == 0);
+    gb.cpu.set_flag(Flag::N, true);
+    gb.cpu.set_flag(Flag::H, value & 0x0F == 0);
     gb.set_table_r(index, res);
 }
 
-fn op_add16(gb: &mut GameBoy, reg: Register16, index: u8) {
-        let res = add16(gb, gb.get_table_rp(index), gb.cpu.get_16(reg));
-        gb.cpu.set_16(reg, res);
+/// ADD HL, rp[p], the zero flag is left untouched
+fn op_add16(gb: &mut GameBoy, index: u8) {
+    let hl = gb.cpu.get_16(Register16::HL);
+    let value = gb.get_table_rp(index);
+    let res = hl as u32 + value as u32;
+
+    gb.cpu.set_flag(Flag::N, false);
+    gb.cpu.set_flag(Flag::H, (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF);
+    gb.cpu.set_flag(Flag::C, res > 0xFFFF);
+    gb.cpu.set_16(Register16::HL, res as u16);
 }
 
 pub fn call_alu_table_r(gb: &mut GameBoy, z: u8, y: u8) {
@@ -483,83 +476,66 @@ fn exec_alu(gb: &mut GameBoy, y: u8, operand: u8) {
 
 /// RLCA rotates the A register to the left one place. The 7th bit is
 /// put back into the 0 position.  The 7th bit also goes to the carry flag.
+/// Unlike RLC A, the Z flag is always reset.
 fn rlca(gb: &mut GameBoy) {
-    let a = gb.cpu.get_8(Register8::A);
-    let seventh = a >> 7;
-    let rotated = a << 1 | seventh;
-
-    gb.cpu.set_8(Register8::A, rotated);
-    gb.cpu.set_flag(Flag::C, seventh == 0b1);
-    gb.cpu.set_flag(Flag::N, false);
-    gb.cpu.set_flag(Flag::H, false);
+    let res = gb.exec_table_rot(0, gb.cpu.get_8(Register8::A));
+    gb.cpu.set_8(Register8::A, res);
+    gb.cpu.set_flag(Flag::Z, false);
 }
 
 /// The  register is shifted right by one,  and the 0 bit goes to the
 /// carry flag and to the 7th bit.  Flags apart from the carry are as for RLCA.
 fn rrca(gb: &mut GameBoy) {
-    let a = gb.cpu.get_8(Register8::A);
-    let first = a << 7;
-    let rotated = a >> 1 | first;
-
-    gb.cpu.set_8(Register8::A, rotated);
-    gb.cpu.set_flag(Flag::C, first == 0b1);
-    gb.cpu.set_flag(Flag::N, false);
-    gb.cpu.set_flag(Flag::H, false);
+    let res = gb.exec_table_rot(1, gb.cpu.get_8(Register8::A));
+    gb.cpu.set_8(Register8::A, res);
+    gb.cpu.set_flag(Flag::Z, false);
 }
 
 /// The  bits in the register are all rotated left,  the 7th bit goes
 /// to the carry flag and the carry flag goes to bit 0. Flags apart from the
 /// carry flag are as for RLCA.
 fn rla(gb: &mut GameBoy) {
-    let a = gb.cpu.get_8(Register8::A);
-    let carry = gb.cpu.get_flag(Flag::C) as u8;
-    let seventh = a >> 7;
-    let rotated = a << 1 | carry;
-
-    gb.cpu.set_8(Register8::A, rotated);
-    gb.cpu.set_flag(Flag::C, seventh == 0b1);
-    gb.cpu.set_flag(Flag::N, false);
-    gb.cpu.set_flag(Flag::H, false);
+    let res = gb.exec_table_rot(2, gb.cpu.get_8(Register8::A));
+    gb.cpu.set_8(Register8::A, res);
+    gb.cpu.set_flag(Flag::Z, false);
 }
 
 /// The register is shifted right by one, the 0 bit goes to the carry
 /// flag, and  the carry flag goes to bit 7. Flags apart from the
 /// carry flag are as for RLCA.
 fn rra(gb: &mut GameBoy) {
-    let a = gb.cpu.get_8(Register8::A);
-    let carry = gb.cpu.get_flag(Flag::C) as u8;
-    let first = a << 7;
-    let rotated = a >> 1 | carry;
-
-    gb.cpu.set_8(Register8::A, rotated);
-    gb.cpu.set_flag(Flag::C, first == 0b1);
-    gb.cpu.set_flag(Flag::N, false);
-    gb.cpu.set_flag(Flag::H, false);
+    let res = gb.exec_table_rot(3, gb.cpu.get_8(Register8::A));
+    gb.cpu.set_8(Register8::A, res);
+    gb.cpu.set_flag(Flag::Z, false);
 }
 
+/// DAA adjusts A to a valid BCD number after an addition or a subtraction,
+/// using the N, H and C flags left by that operation.
 fn daa(gb: &mut GameBoy) {
-    let mut s: u16 = gb.cpu.get_8(Register8::A) as u16;
+    let mut a = gb.cpu.get_8(Register8::A);
+    let mut carry = gb.cpu.get_flag(Flag::C);
+
     if gb.cpu.get_flag(Flag::N) {
-        if gb.cpu.get_flag(Flag::H){
-            s = (s - 0x06) & 0xFF;
+        if carry {
+            a = a.wrapping_sub(0x60);
         }
-        if gb.cpu.get_flag(Flag::C) {
-            s -= 0x60;
+        if gb.cpu.get_flag(Flag::H) {
+            a = a.wrapping_sub(0x06);
         }
     } else {
-        if gb.cpu.get_flag(Flag::H) || (s & 0xF) > 9{
-            s += 0x06
+        if carry || a > 0x99 {
+            a = a.wrapping_add(0x60);
+            carry = true;
         }
-        if gb.cpu.get_flag(Flag::C) || s > 0x9F {
-            s -= 0x60;
+        if gb.cpu.get_flag(Flag::H) || (a & 0x0F) > 0x09 {
+            a = a.wrapping_add(0x06);
         }
     }
-    gb.cpu.set_8(Register8::A, s as u8);
+
+    gb.cpu.set_8(Register8::A, a);
+    gb.cpu.set_flag(Flag::Z, a == 0);
     gb.cpu.set_flag(Flag::H, false);
-    gb.cpu.set_flag(Flag::Z, s as u8 == 0);
-    if s >= 0x100 {
-        gb.cpu.set_flag(Flag::C, true);
-    }
+    gb.cpu.set_flag(Flag::C, carry);
 }
 
 ///CPL  complements the A register.  All 0's become 1's and all  1's
@@ -583,12 +559,13 @@ fn scf(gb: &mut GameBoy) {
 
 
 /// CCF complements the carry flag. If the flag was 1 it is now 0 and
-/// vice  versa.  The Z P and S flags are unaffected.  The N flag  is
-/// reset to 0. The H flag may be anything.
+/// vice  versa.  The Z flag is unaffected.  The N and H flags are
+/// reset to 0.
 fn ccf(gb: &mut GameBoy) {
     let carry = gb.cpu.get_flag(Flag::C);
     gb.cpu.set_flag(Flag::C, !carry);
     gb.cpu.set_flag(Flag::N, false);
+    gb.cpu.set_flag(Flag::H, false);
 }
 
 /* ------------------------
@@ -598,58 +575,24 @@ fn ccf(gb: &mut GameBoy) {
 ---------------------------*/
 
 fn alu_add_a(gb: &mut GameBoy, operand: u8) {
-    let res : u16 = (gb.cpu.get_8(Register8::A) as u16 + operand as u16) as u16;
-    let final_res : u8 = res as u8;
-
-    gb.cpu.set_flag(Flag::Z, final_res == 0);
-    gb.cpu.set_flag(Flag::N, false);
-    gb.cpu.set_flag(Flag::H, ((gb.cpu.get_8(Register8::A) & 0xF) + (operand & 0xF)) > 0xF);
-    gb.cpu.set_flag(Flag::C, res > 0xFF);
-
-    gb.cpu.set_8(Register8::A, res as u8)
+    let res = add8(gb, operand, 0);
+    gb.cpu.set_8(Register8::A, res);
 }
 
 fn alu_adc_a(gb: &mut GameBoy, operand: u8) {
-    let mut carry : u16 = 0;
-    if gb.cpu.get_flag(Flag::C) {
-        carry = 1;
-    }
-    let res : u16 = gb.cpu.get_8(Register8::A) as u16 + operand as u16 + carry;
-    let final_res : u8 = res as u8;
-
-    gb.cpu.set_flag(Flag::Z, final_res == 0);
-    gb.cpu.set_flag(Flag::N, false);
-    gb.cpu.set_flag(Flag::H, ((gb.cpu.get_8(Register8::A) & 0xF) as u16 + (operand as u16 & 0xF) + carry) > 0xF);
-    gb.cpu.set_flag(Flag::C, res > 0xFF);
-
-    gb.cpu.set_8(Register8::A, final_res as u8)
+    let carry = gb.cpu.get_flag(Flag::C) as u8;
+    let res = add8(gb, operand, carry);
+    gb.cpu.set_8(Register8::A, res);
 }
 
-#[allow(arithmetic_overflow)]
 fn alu_sub(gb: &mut GameBoy, operand: u8) {
-    let res : u8 = (Wrapping(gb.cpu.get_8(Register8::A)) - Wrapping(operand)).0;
-
-    gb.cpu.set_flag(Flag::Z, res == 0);
-    gb.cpu.set_flag(Flag::N, true);
-    gb.cpu.set_flag(Flag::H, (Wrapping(gb.cpu.get_8(Register8::A) & 0xf) - Wrapping(operand & 0xf)).0 < 0);
-    gb.cpu.set_flag(Flag::C, gb.cpu.get_8(Register8::A) < operand);
-
+    let res = sub8(gb, operand, 0);
     gb.cpu.set_8(Register8::A, res);
 }
 
 fn alu_sbc(gb: &mut GameBoy, operand: u8) {
-    let mut carry : u8 = 0;
-    if gb.cpu.get_flag(Flag::C) {
-        carry = 1;
-    }
-    let full_res : u16 = (gb.cpu.get_8(Register8::A) - operand - carry) as u16;
-    let res : u8 = full_res as u8;
-
-    gb.cpu.set_flag(Flag::Z, res == 0);
-    gb.cpu.set_flag(Flag::N, true);
-    gb.cpu.set_flag(Flag::H, ((gb.cpu.get_8(Register8::A) & 0xf) - (operand & 0xf) - carry) < 0);
-    gb.cpu.set_flag(Flag::C, full_res < 0);
-
+    let carry = gb.cpu.get_flag(Flag::C) as u8;
+    let res = sub8(gb, operand, carry);
     gb.cpu.set_8(Register8::A, res);
 }
 
@@ -686,23 +629,37 @@ fn alu_or(gb: &mut GameBoy, operand: u8) {
     gb.cpu.set_8(Register8::A, res);
 }
 
+/// Same as SUB, only the flags are kept
 fn alu_cp(gb: &mut GameBoy, operand: u8) {
-    let res: u8 = (Wrapping(gb.cpu.get_8(Register8::A)) - Wrapping(operand)).0;
+    sub8(gb, operand, 0);
+}
 
-    gb.cpu.reset_flags();
+/// A + operand + carry, sets the flags and returns the result
+fn add8(gb: &mut GameBoy, operand: u8, carry: u8) -> u8 {
+    let a = gb.cpu.get_8(Register8::A);
+    let res = a as u16 + operand as u16 + carry as u16;
 
-    gb.cpu.set_flag(Flag::Z, res == 0);
+    gb.cpu.set_flag(Flag::Z, res as u8 == 0);
+    gb.cpu.set_flag(Flag::N, false);
+    gb.cpu.set_flag(Flag::H, (a & 0x0F) + (operand & 0x0F) + carry > 0x0F);
+    gb.cpu.set_flag(Flag::C, res > 0xFF);
+    res as u8
+}
+
+/// A - operand - carry, sets the flags and returns the result
+fn sub8(gb: &mut GameBoy, operand: u8, carry: u8) -> u8 {
+    let a = gb.cpu.get_8(Register8::A);
+    let res = a as i16 - operand as i16 - carry as i16;
+
+    gb.cpu.set_flag(Flag::Z, res as u8 == 0);
     gb.cpu.set_flag(Flag::N, true);
-    gb.cpu.set_flag(Flag::H, gb.cpu.get_8(Register8::A) & 0x0F < (res & 0x0F));
-    gb.cpu.set_flag(Flag::C, gb.cpu.get_8(Register8::A) < operand);
+    gb.cpu.set_flag(Flag::H, ((a & 0x0F) as i16 - (operand & 0x0F) as i16 - carry as i16) < 0);
+    gb.cpu.set_flag(Flag::C, res < 0);
+    res as u8
 }
 
 fn op_ret(gb: &mut GameBoy) {
-    gb.cpu.set_16(
-        Register16::PC,
-        gb.read_word(gb.cpu.get_16(Register16::SP))
-    );
-    gb.cpu.set_16(Register16::SP, gb.cpu.get_16(Register16::SP) + 2);
+    gb.read_from_stack();
 }
 
 /*#[test]
@@ -1,21 +1,56 @@
-use std::process::exit;
+use std::io;
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
 use bitlab::SingleBits;
-use ::cpu::Cpu;
+use apu::CLOCK_RATE;
+use apu::wav::Recorder;
+use ::cpu::{Cpu, IterFlag};
+use cpu::opcodes::decode;
 use ::cpu::{Register8, Register16, Flag};
+use joypad::{Button, ButtonState, Joypad};
 use ::memory::Memory;
+use movie::{state_hash, MovieRun};
+use rewind::Rewind;
+use serial::SerialDevice;
+use state::{StateHeader, StateReader, StateWriter, STATE_VERSION};
 use cartridge::Cartridge;
-use cpu::IterFlag;
 use ::{high_byte, join_bytes};
 use low_byte;
 
+/// Clock ticks in a frame, 154 lines of 456
+pub const FRAME_TICKS: u64 = 70224;
+
 pub struct GameBoy {
     pub cpu: Box<Cpu>,
     pub boot_rom: Vec<u8>,
+    /// The boot rom overlays 0x0000-0x00FF until 0xFF50 is written
+    pub boot_rom_mapped: bool,
     pub cartridge: Box<Cartridge>,
     pub mem: Box<Memory>,
+    /// STOP low power mode, left when a joypad line goes low
     pub stopped: bool,
+    /// HALT, left when an enabled interrupt is requested
+    pub halted: bool,
+    /// HALT was executed with IME off and an interrupt pending, the next
+    /// opcode fetch does not move PC
+    pub halt_bug: bool,
+    /// Set by an illegal opcode, the cpu stops fetching instructions
+    pub locked: bool,
+    /// Run as a Color console, only the speed switch is modelled so far
+    pub cgb_mode: bool,
+    /// KEY1 bit 0, a speed switch happens on the next STOP
+    speed_switch_armed: bool,
+    pub joypad: Joypad,
+    /// Snapshots to go back to, recorded while it is set
+    pub history: Option<Rewind>,
+    /// Unix time the RTC shows at tick 0, it runs on the emulated clock
+    /// rather than the host one while it is set
+    pub rtc_epoch: Option<u64>,
+    /// Time last given to the RTC
+    rtc_time: u64,
+    /// Input movie being recorded or played back
+    pub movie: Option<MovieRun>,
+    rumble_callback: Option<Box<dyn FnMut(bool)>>,
 }
 
 impl GameBoy {
@@ -27,73 +62,439 @@ impl GameBoy {
     ) -> GameBoy {
         GameBoy {
             cpu,
+            boot_rom_mapped: !boot_rom.is_empty(),
             boot_rom,
             cartridge,
             mem,
             stopped: false,
+            halted: false,
+            halt_bug: false,
+            locked: false,
+            cgb_mode: false,
+            speed_switch_armed: false,
+            joypad: Joypad::new(),
+            history: None,
+            rtc_epoch: None,
+            rtc_time: 0,
+            movie: None,
+            rumble_callback: None,
+        }
+    }
+
+    /// Register a callback called each time the cartridge rumble motor
+    /// is switched on or off
+    pub fn on_rumble<F: FnMut(bool) + 'static>(&mut self, callback: F) {
+        self.rumble_callback = Some(Box::new(callback));
+    }
+
+    /// Enter the STOP mode, or switch the cpu speed when a CGB game
+    /// prepared it through KEY1
+    pub fn stop(&mut self) {
+        if self.cgb_mode && self.speed_switch_armed {
+            self.speed_switch_armed = false;
+            self.cpu.toggle_speed();
+        } else {
+            self.stopped = true;
+        }
+        // The divider is reset when entering STOP
+        self.cpu.write_timer(0xFF04, 0);
+    }
+
+    /// A joypad line is low, the selected buttons are not all released
+    pub fn joypad_line_low(&self) -> bool {
+        self.joypad.lines() != 0x0F
+    }
+
+    /// Update every button at once, as polled by a frontend. While a
+    /// movie is recorded they change when the next movie frame starts,
+    /// they are ignored while one is played back.
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        match self.movie {
+            Some(ref mut run) if !run.playing => run.held = buttons,
+            Some(ref run) if !run.finished() => {}
+            _ => self.apply_buttons(buttons),
+        }
+    }
+
+    /// Buttons the player holds, a movie being recorded may not have
+    /// applied them yet
+    fn held_buttons(&self) -> ButtonState {
+        match self.movie {
+            Some(ref run) if !run.playing => run.held,
+            _ => self.joypad.buttons,
+        }
+    }
+
+    fn apply_buttons(&mut self, buttons: ButtonState) {
+        if self.joypad.set_buttons(buttons) {
+            self.cpu.set_iter_flag(IterFlag::JOYPAD, true);
+        }
+    }
+
+    pub fn press(&mut self, button: Button) {
+        let mut buttons = self.held_buttons();
+        buttons.set(button, true);
+        self.set_buttons(buttons);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        let mut buttons = self.held_buttons();
+        buttons.set(button, false);
+        self.set_buttons(buttons);
+    }
+
+    /// Last frame drawn by the PPU, 160x144 shades from 0 (white) to 3 (black)
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.cpu.ppu.frame
+    }
+
+    /// Run one instruction, or one cycle of HALT, and the hardware
+    /// alongside it
+    pub fn step(&mut self) {
+        let (clock, buttons, stopped) = (self.cpu.clock(), self.joypad.buttons, self.stopped);
+        if self.movie.as_ref().map_or(false, |run| run.frame_due(clock) || stopped && run.input_changes(buttons)) {
+            self.movie_frame();
+        }
+        decode(self);
+        self.cpu.ppu_step();
+        self.interrupt_step();
+
+        // The RTC only needs to hear about whole seconds
+        if let Some(epoch) = self.rtc_epoch {
+            let now = epoch + self.cpu.clock() / CLOCK_RATE as u64;
+            if now != self.rtc_time {
+                self.rtc_time = now;
+                self.cartridge.set_rtc_time(Some(now));
+            }
+        }
+
+        let frame = self.cpu.ppu.frames;
+        if self.history.as_ref().map_or(false, |h| h.due(frame)) {
+            let state = self.save_state();
+            let ticks = self.cpu.ticks();
+            self.history.as_mut().unwrap().push(frame, ticks, state);
+        }
+    }
+
+    /// A movie frame starts: check or record the state the last one
+    /// ended on, then hold the buttons of the new one
+    fn movie_frame(&mut self) {
+        if self.movie.as_ref().map_or(false, |run| run.sync_due()) {
+            let hash = state_hash(&self.save_state());
+            self.movie.as_mut().unwrap().sync(hash);
+        }
+        if let Some(buttons) = self.movie.as_mut().and_then(|run| run.begin_frame()) {
+            self.apply_buttons(buttons);
+        }
+    }
+
+    /// Run the cartridge RTC on the emulated time, from the unix time
+    /// `epoch` at tick 0, or back on the host clock with None
+    pub fn set_rtc_epoch(&mut self, epoch: Option<u64>) {
+        self.rtc_epoch = epoch;
+        let now = epoch.map(|e| e + self.cpu.clock() / CLOCK_RATE as u64);
+        self.rtc_time = now.unwrap_or(0);
+        self.cartridge.set_rtc_time(now);
+    }
+
+    /// Start recording a snapshot every `interval` frames, keeping the
+    /// `capacity` last ones
+    pub fn enable_rewind(&mut self, interval: u64, capacity: usize) {
+        let mut history = Rewind::new(interval, capacity);
+        history.push(self.cpu.ppu.frames, self.cpu.ticks(), self.save_state());
+        self.history = Some(history);
+    }
+
+    /// Go back to the latest snapshot at least `frames` frames ago, or the
+    /// oldest one. Returns how many frames were actually rewound. Refused
+    /// while a device is plugged into the link port or a movie runs.
+    pub fn rewind(&mut self, frames: u64) -> io::Result<u64> {
+        self.check_unplugged()?;
+        self.check_no_movie()?;
+        let now = self.cpu.ppu.frames;
+        let target = now.saturating_sub(frames);
+        let state = match self.history {
+            Some(ref history) => match history.at_frame(target) {
+                Some(index) => history.state(index)?,
+                None => return Ok(0),
+            },
+            None => return Ok(0),
+        };
+        self.load_state(&state)?;
+        self.forget_future();
+        Ok(now.saturating_sub(self.cpu.ppu.frames))
+    }
+
+    /// Undo the last instruction: the run from the snapshot before it is
+    /// replayed, with the buttons currently held, up to the instruction
+    /// before. Returns false when no snapshot goes back that far.
+    pub fn step_back(&mut self) -> io::Result<bool> {
+        self.check_unplugged()?;
+        self.check_no_movie()?;
+        let now = self.cpu.ticks();
+        let state = match self.history {
+            Some(ref history) => match history.before(now) {
+                Some(index) => history.state(index)?,
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        };
+
+        // Nothing is recorded during the replay
+        let history = self.history.take();
+        let recorder = self.cpu.apu.recorder.take();
+        let replayed = self.replay_until(&state, now);
+        self.history = history;
+        self.cpu.apu.recorder = recorder;
+        replayed?;
+        self.forget_future();
+        Ok(true)
+    }
+
+    /// Run from a state up to the last instruction ending before a tick
+    fn replay_until(&mut self, state: &[u8], ticks: u64) -> io::Result<()> {
+        // Count the steps to get back there, then stop one short of it
+        self.load_state(state)?;
+        let mut steps = 0;
+        while self.cpu.ticks() < ticks && !self.stopped {
+            self.step();
+            steps += 1;
+        }
+        self.load_state(state)?;
+        for _ in 1..steps {
+            self.step();
+        }
+        Ok(())
+    }
+
+    /// The device on the link port already got what the console sent,
+    /// going back would send it again
+    fn check_unplugged(&self) -> io::Result<()> {
+        if self.cpu.serial.device.plugged() {
+            return Err(io::Error::new(io::ErrorKind::Other, "a device is plugged into the link port"));
         }
+        Ok(())
+    }
+
+    /// The movie frames and sync points would not match the run anymore
+    fn check_no_movie(&self) -> io::Result<()> {
+        if self.movie.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Other, "a movie is recorded or played back"));
+        }
+        Ok(())
+    }
+
+    /// Drop the snapshots taken after the current point
+    fn forget_future(&mut self) {
+        let (ticks, frame) = (self.cpu.ticks(), self.cpu.ppu.frames);
+        if let Some(ref mut history) = self.history {
+            history.truncate_after(ticks, frame);
+        }
+    }
+
+    /// Run until the PPU completes a frame. Returns after a frame worth of
+    /// clock ticks while the LCD is off, and right away in STOP mode.
+    pub fn run_frame(&mut self) {
+        let frames = self.cpu.ppu.frames;
+        let start = self.cpu.ticks();
+        let length = if self.cpu.double_speed() { FRAME_TICKS * 2 } else { FRAME_TICKS };
+        while !self.stopped && self.cpu.ppu.frames == frames && self.cpu.ticks() - start < length {
+            self.step();
+        }
+    }
+
+    /// Start capturing the audio output, from `range.0` to `range.1`
+    /// frames after this call when given, with one more track per
+    /// channel when `stems` is set
+    pub fn start_recording(&mut self, range: Option<(u64, u64)>, stems: bool) {
+        let rate = self.cpu.apu.sample_rate;
+        self.cpu.apu.recorder = Some(Recorder::new(rate, range, stems));
+    }
+
+    /// Stop the capture, the recorder can then be saved to WAV files
+    pub fn stop_recording(&mut self) -> Option<Recorder> {
+        self.cpu.apu.recorder.take()
+    }
+
+    /// Plug a device into the link port, replacing the previous one
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.cpu.serial.device = device;
+    }
+
+    /// Host rate of the audio samples, 44100 Hz by default
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.cpu.apu.set_sample_rate(rate);
+    }
+
+    /// Stereo samples waiting to be pulled, left and right count as two
+    pub fn samples_available(&self) -> usize {
+        self.cpu.apu.samples.len()
+    }
+
+    /// Pull interleaved left/right samples, returns how many were written
+    pub fn read_samples(&mut self, out: &mut [i16]) -> usize {
+        self.cpu.apu.read_samples(out)
+    }
+
+    /// Snapshot of the whole machine, tagged with the format version and
+    /// the rom it was taken on. The boot rom content and the host side
+    /// (link device, audio capture, buttons held) are not included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        StateHeader {
+            version: STATE_VERSION,
+            rom_checksum: self.cartridge.checksum(),
+            rom_size: self.cartridge.size() as u32,
+        }.write(&mut w);
+
+        w.write_bool(self.boot_rom_mapped);
+        w.write_bool(self.stopped);
+        w.write_bool(self.halted);
+        w.write_bool(self.halt_bug);
+        w.write_bool(self.locked);
+        w.write_bool(self.cgb_mode);
+        w.write_bool(self.speed_switch_armed);
+        self.cpu.save_state(&mut w);
+        self.joypad.save_state(&mut w);
+        self.mem.save_state(&mut w);
+        self.cartridge.save_state(&mut w);
+        w.into_inner()
+    }
+
+    /// Restore a snapshot of `save_state`, older format versions are
+    /// migrated. A state of another rom is refused, as is any state while
+    /// a movie runs, and the machine is left untouched when the state is
+    /// rejected or corrupted.
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        self.check_no_movie()?;
+        let mut r = StateReader::new(data);
+        let header = StateHeader::read(&mut r)?;
+        if header.rom_checksum != self.cartridge.checksum() || header.rom_size as usize != self.cartridge.size() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "save state of another rom"));
+        }
+        r.version = header.version;
+
+        let backup = self.save_state();
+        if let Err(e) = self.load_components(&mut r) {
+            let mut r = StateReader::new(&backup);
+            StateHeader::read(&mut r)
+                .and_then(|_| self.load_components(&mut r))
+                .expect("the state just saved loads back");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn load_components(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.boot_rom_mapped = r.read_bool()? && !self.boot_rom.is_empty();
+        self.stopped = r.read_bool()?;
+        self.halted = r.read_bool()?;
+        self.halt_bug = r.read_bool()?;
+        self.locked = r.read_bool()?;
+        self.cgb_mode = r.read_bool()?;
+        self.speed_switch_armed = r.read_bool()?;
+        self.cpu.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.mem.load_state(r)?;
+        self.cartridge.load_state(r)
+    }
+
+    /// Whether the cartridge rumble motor is currently on
+    pub fn rumble(&self) -> bool {
+        self.cartridge.rumble()
     }
 
     pub fn read_byte(&self, addr: u16) -> u8 {
         return match addr {
             0..=0x3FFF => {
-                if addr <= 0xFF {
+                if addr <= 0xFF && self.boot_rom_mapped {
                     return self.boot_rom[addr as usize];
                 }
                 return self.cartridge.read_byte(addr);
             },
             0x4000..=0x7FFF => self.cartridge.read_byte(addr), //self.cartridge[addr as usize],
+            0x8000..=0x9FFF => self.cpu.ppu.vram[addr as usize - 0x8000],
+            0xA000..=0xBFFF => self.cartridge.read_ram(addr),
             0xE000..=0xFDFF => self.mem.read_byte(addr - 0x2000), //- 0x2000
-            0xFF04..=0xFF07 => {
-                println!("UNIMPLEMENTED READ FROM TIMER");
-                exit(101);
-            }
-            0xFF40 => self.cpu.ppu.control,
-            0xFF42 => self.cpu.ppu.scroll_y,
-            0xFF43 => self.cpu.ppu.scroll_x,
-            0xFF44 => self.cpu.ppu.scanline,
-            0xFF45 => {
-                exit(1000);
+            0xFE00..=0xFE9F => self.cpu.ppu.oam[addr as usize - 0xFE00],
+            0xFF00 => self.joypad.read_byte(),
+            0xFF01..=0xFF02 => self.cpu.serial.read_byte(addr),
+            0xFF04..=0xFF07 => self.cpu.timer.read_byte(addr),
+            0xFF10..=0xFF3F => self.cpu.apu.read_byte(addr),
+            0xFF0F => self.cpu.get_iter_flags(),
+            0xFF46 => self.mem.read_byte(addr),
+            0xFF40..=0xFF4B => self.cpu.ppu.read_byte(addr),
+            0xFF4D if self.cgb_mode => {
+                0x7E | if self.cpu.double_speed() { 0x80 } else { 0 }
+                    | self.speed_switch_armed as u8
             }
+            0xFF50 => 0xFF,
+            0xFFFF => self.cpu.get_iter_enable(),
             _ => self.mem.read_byte(addr),
         }
     }
 
     pub fn read_word(&self, addr: u16) -> u16 {
         let a = self.read_byte(addr);
-        let b = self.read_byte(addr + 1);
+        let b = self.read_byte(addr.wrapping_add(1));
         join_bytes!(b, a)
     }
 
     pub fn write_byte(&mut self, addr: u16, v: u8) {
         match addr {
             0..=0x3FFF => self.cartridge.write_byte(addr, v),
-            0x4000..=0x7FFF => self.cartridge.write_byte(addr, v),
+            0x4000..=0x7FFF => {
+                let rumble = self.cartridge.rumble();
+                self.cartridge.write_byte(addr, v);
+                if self.cartridge.rumble() != rumble {
+                    if let Some(ref mut callback) = self.rumble_callback {
+                        callback(!rumble);
+                    }
+                }
+            }
+            0x8000..=0x9FFF => self.cpu.ppu.vram[addr as usize - 0x8000] = v,
+            0xA000..=0xBFFF => self.cartridge.write_ram(addr, v),
             0xE000..=0xFDFF => self.mem.write_byte(addr - 0x2000, v),
-            0xFF40 => self.cpu.ppu.control = v,
-            0xFF42 => self.cpu.ppu.scroll_y = v,
-            0xFF43 => self.cpu.ppu.scroll_x = v,
-            0xFF44 => self.cpu.ppu.scanline = 0,
-            0xFF45 => {
-                exit(1000);
+            0xFE00..=0xFE9F => self.cpu.ppu.oam[addr as usize - 0xFE00] = v,
+            0xFF00 => {
+                if self.joypad.write_byte(v) {
+                    self.cpu.set_iter_flag(IterFlag::JOYPAD, true);
+                }
             }
+            0xFF01..=0xFF02 => self.cpu.serial.write_byte(addr, v),
+            0xFF04..=0xFF07 => self.cpu.write_timer(addr, v),
+            0xFF10..=0xFF3F => self.cpu.apu.write_byte(addr, v),
+            0xFF0F => self.cpu.set_iter_flags(v),
             0xFF46 => {
                 self.mem.write_byte(addr, v);
                 self.dma_transfer(v);
             }
+            0xFF40..=0xFF4B => {
+                self.cpu.ppu.write_byte(addr, v);
+                if self.cpu.ppu.update_stat_line() {
+                    self.cpu.set_iter_flag(IterFlag::LCDSTAT, true);
+                }
+            }
+            0xFF4D if self.cgb_mode => self.speed_switch_armed = v & 0x01 != 0,
+            0xFF50 => {
+                if v != 0 {
+                    self.boot_rom_mapped = false;
+                }
+            }
+            0xFFFF => self.cpu.set_iter_enable(v),
             _ =>  self.mem.write_byte(addr, v),
         }
     }
 
     pub fn write_word(&mut self, addr: u16, v: u16) {
-        self.write_byte(addr + 1, high_byte!(v));
+        self.write_byte(addr.wrapping_add(1), high_byte!(v));
         self.write_byte(addr, low_byte!(v));
     }
 
     pub fn dma_transfer(&mut self, addr: u8) {
         let offset_addr: u16 = addr as u16 * 0x100;
 
-        for i in 0..0x9F {
+        for i in 0..0xA0 {
             let from = offset_addr + i;
             let to = 0xFE00 + i;
 
@@ -101,40 +502,34 @@ impl GameBoy {
         }
     }
 
+    /// Service the highest priority pending interrupt, if IME allows it
     pub fn interrupt_step(&mut self) {
-        let mut pc = 0;
-        if self.cpu.get_iter_flag_enable(IterFlag::VBLANK) &&
-            self.cpu.get_iter_flag(IterFlag::VBLANK) {
-            pc = 0x40;
-            self.cpu.set_iter_flag(IterFlag::VBLANK, false);
-        }
-        if self.cpu.get_iter_flag_enable(IterFlag::LCDSTAT) &&
-            self.cpu.get_iter_flag(IterFlag::LCDSTAT) {
-            pc = 0x48;
-            self.cpu.set_iter_flag(IterFlag::LCDSTAT, false);
-        }
-        if self.cpu.get_iter_flag_enable(IterFlag::TIMER) &&
-            self.cpu.get_iter_flag(IterFlag::TIMER) {
-            pc = 0x50;
-            self.cpu.set_iter_flag(IterFlag::TIMER, false);
-        }
-        if self.cpu.get_iter_flag_enable(IterFlag::SERIAL) &&
-            self.cpu.get_iter_flag(IterFlag::SERIAL) {
-            pc = 0x58;
-            self.cpu.set_iter_flag(IterFlag::SERIAL, false);
-        }
-        if self.cpu.get_iter_flag_enable(IterFlag::JOYPAD) &&
-            self.cpu.get_iter_flag(IterFlag::JOYPAD) {
-            pc = 0x60;
-            self.cpu.set_iter_flag(IterFlag::JOYPAD, false);
+        if self.locked || !self.cpu.get_iter_master() || !self.cpu.interrupt_pending() {
+            return;
         }
+        self.cpu.set_iter_master(false);
+        self.halted = false;
+
+        let pc = self.cpu.get_16(Register16::PC);
+        let sp = self.cpu.get_16(Register16::SP).wrapping_sub(1);
+        self.write_byte(sp, high_byte!(pc));
+
+        // Pushing the high byte of PC over IE can cancel the interrupt,
+        // the cpu then jumps to 0x0000
+        let flag = self.cpu.next_interrupt();
 
-        if pc != 0 {
-            self.cpu.set_iter_master(false);
-            self.write_to_stack(self.cpu.get_16(Register16::PC));
-            self.cpu.set_16(Register16::PC, pc);
-            self.cpu.inc_ticks(12);
+        let sp = sp.wrapping_sub(1);
+        self.write_byte(sp, low_byte!(pc));
+        self.cpu.set_16(Register16::SP, sp);
+
+        match flag {
+            Some(flag) => {
+                self.cpu.set_iter_flag(flag, false);
+                self.cpu.set_16(Register16::PC, flag.vector());
+            }
+            None => self.cpu.set_16(Register16::PC, 0x0000),
         }
+        self.cpu.inc_ticks(5);
     }
 
     pub fn reti(&mut self) {
@@ -142,17 +537,25 @@ impl GameBoy {
         self.read_from_stack();
     }
 
-    pub fn write_to_stack(&mut self, addr: u16) {
-        self.cpu.set_16(Register16::SP, self.cpu.get_16(Register16::SP) - 2);
-        self.mem.write_word(self.cpu.get_16(Register16::SP), addr);
+    /// Push a word on the stack
+    pub fn write_to_stack(&mut self, v: u16) {
+        let sp = self.cpu.get_16(Register16::SP).wrapping_sub(2);
+        self.cpu.set_16(Register16::SP, sp);
+        self.write_word(sp, v);
+    }
+
+    /// Pop a word from the stack
+    pub fn pop_stack(&mut self) -> u16 {
+        let sp = self.cpu.get_16(Register16::SP);
+        let v = self.read_word(sp);
+        self.cpu.set_16(Register16::SP, sp.wrapping_add(2));
+        v
     }
 
+    /// Pop the return address into PC
     pub fn read_from_stack(&mut self) {
-        self.cpu.set_16(
-            Register16::PC,
-            self.mem.read_word(self.cpu.get_16(Register16::SP))
-        );
-        self.cpu.set_16(Register16::SP, self.cpu.get_16(Register16::SP) + 2);
+        let pc = self.pop_stack();
+        self.cpu.set_16(Register16::PC, pc);
     }
 
     pub fn get_table_cc(&self, index: u8) -> bool {
@@ -173,7 +576,7 @@ impl GameBoy {
             3 => self.cpu.get_8(Register8::E),
             4 => self.cpu.get_8(Register8::H),
             5 => self.cpu.get_8(Register8::L),
-            6 => self.mem.read_byte(self.cpu.get_16(Register16::HL)),
+            6 => self.read_byte(self.cpu.get_16(Register16::HL)),
             7 => self.cpu.get_8(Register8::A),
             _ => unreachable!()
         }
@@ -218,7 +621,10 @@ impl GameBoy {
             3 => self.cpu.set_8(Register8::E, value),
             4 => self.cpu.set_8(Register8::H, value),
             5 => self.cpu.set_8(Register8::L, value),
-            6 => self.mem.write_byte(self.cpu.get_16(Register16::HL), value),
+            6 => {
+                let addr = self.cpu.get_16(Register16::HL);
+                self.write_byte(addr, value)
+            }
             7 => self.cpu.set_8(Register8::A, value),
             _ => unreachable!()
         }
@@ -0,0 +1,168 @@
+/*
+ * Joypad, P1/JOYP 0xFF00
+ *
+ * Bit 5  select the action buttons (0 = selected)
+ * Bit 4  select the direction buttons (0 = selected)
+ * Bit 3  down or start (0 = pressed)
+ * Bit 2  up or select
+ * Bit 1  left or B
+ * Bit 0  right or A
+ */
+
+use std::io;
+use state::{StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+/// Buttons held down by the player
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ButtonState {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl ButtonState {
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::Right => self.right = pressed,
+            Button::Left => self.left = pressed,
+            Button::Up => self.up = pressed,
+            Button::Down => self.down = pressed,
+            Button::A => self.a = pressed,
+            Button::B => self.b = pressed,
+            Button::Select => self.select = pressed,
+            Button::Start => self.start = pressed,
+        }
+    }
+
+    /// Pressed directions as a nibble, right in bit 0
+    pub fn directions(&self) -> u8 {
+        self.right as u8 | (self.left as u8) << 1 | (self.up as u8) << 2 | (self.down as u8) << 3
+    }
+
+    /// Pressed action buttons as a nibble, A in bit 0
+    pub fn actions(&self) -> u8 {
+        self.a as u8 | (self.b as u8) << 1 | (self.select as u8) << 2 | (self.start as u8) << 3
+    }
+
+    /// Buttons pressed in `directions` and `actions` nibbles
+    pub fn from_nibbles(directions: u8, actions: u8) -> ButtonState {
+        ButtonState {
+            right: directions & 0x01 != 0,
+            left: directions & 0x02 != 0,
+            up: directions & 0x04 != 0,
+            down: directions & 0x08 != 0,
+            a: actions & 0x01 != 0,
+            b: actions & 0x02 != 0,
+            select: actions & 0x04 != 0,
+            start: actions & 0x08 != 0,
+        }
+    }
+}
+
+pub struct Joypad {
+    pub buttons: ButtonState,
+    /// Bits 4-5 of P1
+    select: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            buttons: ButtonState::default(),
+            select: 0x30,
+        }
+    }
+
+    /// State of the 4 input lines, a pressed button of a selected group
+    /// pulls its line low
+    pub fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & 0x10 == 0 {
+            pressed |= self.buttons.directions();
+        }
+        if self.select & 0x20 == 0 {
+            pressed |= self.buttons.actions();
+        }
+        !pressed & 0x0F
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    /// Returns true when a line went low, requesting the JOYPAD interrupt
+    pub fn write_byte(&mut self, v: u8) -> bool {
+        let before = self.lines();
+        self.select = v & 0x30;
+        before & !self.lines() != 0
+    }
+
+    /// Only the selection, the buttons belong to the player
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.select);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.select = r.read_u8()? & 0x30;
+        Ok(())
+    }
+
+    /// Returns true when a line went low, requesting the JOYPAD interrupt
+    pub fn set_buttons(&mut self, buttons: ButtonState) -> bool {
+        let before = self.lines();
+        self.buttons = buttons;
+        before & !self.lines() != 0
+    }
+}
+
+#[test]
+fn matrix() {
+    let mut joypad = Joypad::new();
+    joypad.buttons.set(Button::Start, true);
+    joypad.buttons.set(Button::Left, true);
+    assert_eq!(joypad.read_byte(), 0xFF);
+
+    joypad.write_byte(0x20);
+    assert_eq!(joypad.read_byte(), 0xED);
+    joypad.write_byte(0x10);
+    assert_eq!(joypad.read_byte(), 0xD7);
+    joypad.write_byte(0x00);
+    assert_eq!(joypad.read_byte(), 0xC5);
+}
+
+#[test]
+fn interrupt_on_falling_edge() {
+    let mut joypad = Joypad::new();
+    let mut buttons = ButtonState { a: true, ..ButtonState::default() };
+    // Nothing selected, the line stays high
+    assert!(!joypad.set_buttons(buttons));
+    assert!(joypad.write_byte(0x10));
+
+    buttons.b = true;
+    assert!(joypad.set_buttons(buttons));
+    buttons.a = false;
+    assert!(!joypad.set_buttons(buttons));
+}
@@ -1,10 +1,19 @@
 extern crate bitlab;
 
+pub mod apu;
 pub mod cartridge;
 pub mod cpu;
 pub mod debug;
 pub mod gameboy;
+pub mod joypad;
+pub mod link;
 pub mod memory;
+pub mod movie;
 pub mod ppu;
+pub mod printer;
+pub mod rewind;
+pub mod serial;
+pub mod state;
+pub mod timer;
 #[macro_use]
 pub mod utils;
\ No newline at end of file
@@ -0,0 +1,390 @@
+/*
+ * Link cable between two emulated consoles
+ *
+ * The side writing SC with the internal clock is the clock master of the
+ * transfer, the other one receives the byte when its clock reaches the
+ * start of the transfer, if it waits for one with the external clock.
+ *
+ * In-process, both consoles share a cable and are stepped in lockstep
+ * with `step_linked`. Across processes, `StreamLink` exchanges messages
+ * over a TCP or Unix socket and keeps both clocks within `MAX_LEAD`.
+ */
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use gameboy::GameBoy;
+use serial::SerialDevice;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+const PROTOCOL_VERSION: u8 = 1;
+
+/// Clock ticks between two clock updates sent to the other side
+const SYNC_TICKS: u64 = 4096;
+/// A side waits when its clock gets this far ahead of the other one
+pub const MAX_LEAD: u64 = 70224;
+/// The other side is considered gone after this long without a message
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct CableState {
+    /// SB of each side, as of its last cycle
+    data: [u8; 2],
+    /// Byte sent to each side by the other one, with the transfer start
+    sent: [Option<(u8, u64)>; 2],
+}
+
+/// One end of an in-process link cable
+pub struct CableEnd {
+    side: usize,
+    state: Rc<RefCell<CableState>>,
+    ticks: u64,
+}
+
+impl CableEnd {
+    /// Both ends of a new cable, to plug with `GameBoy::connect_serial`
+    pub fn pair() -> (CableEnd, CableEnd) {
+        let state = Rc::new(RefCell::new(CableState::default()));
+        (
+            CableEnd { side: 0, state: state.clone(), ticks: 0 },
+            CableEnd { side: 1, state, ticks: 0 },
+        )
+    }
+}
+
+impl SerialDevice for CableEnd {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut state = self.state.borrow_mut();
+        state.data[self.side] = byte;
+        state.sent[1 - self.side] = Some((byte, self.ticks));
+        state.data[1 - self.side]
+    }
+
+    fn poll(&mut self, ticks: u64, byte: u8) -> Option<u8> {
+        self.ticks = ticks;
+        let mut state = self.state.borrow_mut();
+        state.data[self.side] = byte;
+        match state.sent[self.side] {
+            Some((byte, start)) if ticks >= start => {
+                state.sent[self.side] = None;
+                Some(byte)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Run one instruction on the console whose clock is behind, keeps two
+/// linked consoles in lockstep
+pub fn step_linked(a: &mut GameBoy, b: &mut GameBoy) {
+    if a.cpu.ticks() <= b.cpu.ticks() && !a.stopped || b.stopped {
+        a.step();
+    } else {
+        b.step();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Hello,
+    /// Clock of the sender
+    Sync,
+    /// Transfer started by the clock master
+    Transfer,
+    /// SB of the other side, answering a transfer
+    Reply,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Message {
+    kind: Kind,
+    byte: u8,
+    ticks: u64,
+}
+
+impl Message {
+    fn encode(&self) -> [u8; 10] {
+        let mut out = [0u8; 10];
+        out[0] = match self.kind {
+            Kind::Hello => 0,
+            Kind::Sync => 1,
+            Kind::Transfer => 2,
+            Kind::Reply => 3,
+        };
+        out[1] = self.byte;
+        out[2..].copy_from_slice(&self.ticks.to_le_bytes());
+        out
+    }
+
+    fn decode(data: &[u8; 10]) -> io::Result<Message> {
+        let kind = match data[0] {
+            0 => Kind::Hello,
+            1 => Kind::Sync,
+            2 => Kind::Transfer,
+            3 => Kind::Reply,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown link message")),
+        };
+        let mut ticks = [0u8; 8];
+        ticks.copy_from_slice(&data[2..]);
+        Ok(Message { kind, byte: data[1], ticks: u64::from_le_bytes(ticks) })
+    }
+}
+
+/// Link cable to a console running in another process
+pub struct StreamLink {
+    writer: Box<dyn Write + Send>,
+    messages: Receiver<io::Result<Message>>,
+    /// The listening side wins when both sides start a transfer at once
+    primary: bool,
+    connected: bool,
+    ticks: u64,
+    next_sync: u64,
+    /// Last clock received from the other side
+    peer_ticks: u64,
+    /// Transfer clocked by the other side, answered once our clock
+    /// reaches it
+    incoming: Option<(u8, u64)>,
+}
+
+impl StreamLink {
+    /// Link over an already connected stream, `reader` being a clone of it.
+    /// Both sides must agree on which one is the primary.
+    pub fn new<R, W>(mut reader: R, writer: W, primary: bool) -> io::Result<StreamLink>
+        where R: Read + Send + 'static, W: Write + Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut data = [0u8; 10];
+            loop {
+                let message = reader.read_exact(&mut data).and_then(|_| Message::decode(&data));
+                let failed = message.is_err();
+                if tx.send(message).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let mut link = StreamLink {
+            writer: Box::new(writer),
+            messages: rx,
+            primary,
+            connected: true,
+            ticks: 0,
+            next_sync: 0,
+            peer_ticks: 0,
+            incoming: None,
+        };
+
+        link.send(Kind::Hello, PROTOCOL_VERSION)?;
+        match link.messages.recv_timeout(TIMEOUT) {
+            Ok(Ok(Message { kind: Kind::Hello, byte: PROTOCOL_VERSION, .. })) => Ok(link),
+            Ok(Err(e)) => Err(e),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "link handshake failed")),
+        }
+    }
+
+    /// Wait for the other console on a TCP port, this side is the primary
+    pub fn listen_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<StreamLink> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        stream.set_nodelay(true)?;
+        StreamLink::new(stream.try_clone()?, stream, true)
+    }
+
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<StreamLink> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        StreamLink::new(stream.try_clone()?, stream, false)
+    }
+
+    /// Wait for the other console on a Unix socket, this side is the primary
+    #[cfg(unix)]
+    pub fn listen_unix<P: AsRef<Path>>(path: P) -> io::Result<StreamLink> {
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        StreamLink::new(stream.try_clone()?, stream, true)
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<StreamLink> {
+        let stream = UnixStream::connect(path)?;
+        StreamLink::new(stream.try_clone()?, stream, false)
+    }
+
+    pub fn connected(&self) -> bool {
+        self.connected
+    }
+
+    fn send(&mut self, kind: Kind, byte: u8) -> io::Result<()> {
+        let message = Message { kind, byte, ticks: self.ticks };
+        self.writer.write_all(&message.encode())?;
+        self.writer.flush()
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+    }
+
+    /// Next message, waiting for it when `block` is set
+    fn receive(&mut self, block: bool) -> Option<Message> {
+        if !self.connected {
+            return None;
+        }
+        let message = if block {
+            match self.messages.recv_timeout(TIMEOUT) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => Err(io::Error::new(io::ErrorKind::TimedOut, "link timeout")),
+                Err(RecvTimeoutError::Disconnected) => Err(io::ErrorKind::BrokenPipe.into()),
+            }
+        } else {
+            match self.messages.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => Err(io::ErrorKind::BrokenPipe.into()),
+            }
+        };
+        match message {
+            Ok(message) => {
+                self.peer_ticks = self.peer_ticks.max(message.ticks);
+                Some(message)
+            }
+            Err(_) => {
+                self.disconnect();
+                None
+            }
+        }
+    }
+
+    /// Handle a message outside of a transfer of ours
+    fn handle(&mut self, message: Message) {
+        if message.kind == Kind::Transfer {
+            self.incoming = Some((message.byte, message.ticks));
+        }
+    }
+}
+
+impl SerialDevice for StreamLink {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        if !self.connected || self.send(Kind::Transfer, byte).is_err() {
+            self.disconnect();
+            return 0xFF;
+        }
+
+        while let Some(message) = self.receive(true) {
+            match message.kind {
+                Kind::Reply => return message.byte,
+                // Both sides started a transfer: the primary keeps its
+                // clock, the other one answers and takes its byte
+                Kind::Transfer if !self.primary => {
+                    if self.send(Kind::Reply, byte).is_err() {
+                        self.disconnect();
+                        break;
+                    }
+                    return message.byte;
+                }
+                _ => {}
+            }
+        }
+        0xFF
+    }
+
+    fn poll(&mut self, ticks: u64, byte: u8) -> Option<u8> {
+        self.ticks = ticks;
+        if self.connected && ticks >= self.next_sync {
+            self.next_sync = ticks + SYNC_TICKS;
+            if self.send(Kind::Sync, 0).is_err() {
+                self.disconnect();
+            }
+        }
+
+        while let Some(message) = self.receive(false) {
+            self.handle(message);
+        }
+        // Too far ahead, wait for the other side to catch up
+        while self.connected && ticks > self.peer_ticks + MAX_LEAD {
+            if let Some(message) = self.receive(true) {
+                self.handle(message);
+            }
+        }
+
+        // The clock master waits for the answer, SB as of the start of
+        // its transfer
+        match self.incoming {
+            Some((incoming, start)) if ticks >= start => {
+                self.incoming = None;
+                if self.send(Kind::Reply, byte).is_err() {
+                    self.disconnect();
+                }
+                Some(incoming)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn cable() {
+    let (mut a, mut b) = CableEnd::pair();
+    assert_eq!(b.poll(0, 0x42), None);
+
+    // b waits with 0x42 in SB, a clocks 0x17 at tick 100
+    a.poll(100, 0x17);
+    assert_eq!(a.transfer(0x17), 0x42);
+    assert_eq!(b.poll(96, 0x42), None);
+    assert_eq!(b.poll(100, 0x42), Some(0x17));
+    assert_eq!(b.poll(104, 0x42), None);
+}
+
+#[test]
+fn message_encoding() {
+    let message = Message { kind: Kind::Transfer, byte: 0x42, ticks: 0x0123_4567_89AB };
+    assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+    assert!(Message::decode(&[9; 10]).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn stream_link() {
+    let (left, right) = UnixStream::pair().unwrap();
+    let secondary = thread::spawn(move || {
+        let mut link = StreamLink::new(right.try_clone().unwrap(), right, false).unwrap();
+        // Waits with 0x99 in SB until the primary clocks a byte in
+        loop {
+            if let Some(byte) = link.poll(100, 0x99) {
+                return byte;
+            }
+        }
+    });
+
+    let mut link = StreamLink::new(left.try_clone().unwrap(), left, true).unwrap();
+    link.poll(8, 0x55);
+    assert_eq!(link.transfer(0x55), 0x99);
+    assert_eq!(secondary.join().unwrap(), 0x55);
+}
+
+#[cfg(unix)]
+#[test]
+fn stream_link_late_reply() {
+    let (left, right) = UnixStream::pair().unwrap();
+    let secondary = thread::spawn(move || {
+        let mut link = StreamLink::new(right.try_clone().unwrap(), right, false).unwrap();
+        // The transfer arrives while this side is still behind it
+        while link.incoming.is_none() {
+            assert_eq!(link.poll(10, 0x11), None);
+        }
+        assert_eq!(link.poll(20, 0x11), None);
+        // SB written before the clock reaches the transfer goes out
+        link.poll(60, 0x22)
+    });
+
+    let mut link = StreamLink::new(left.try_clone().unwrap(), left, true).unwrap();
+    link.poll(50, 0x55);
+    assert_eq!(link.transfer(0x55), 0x22);
+    assert_eq!(secondary.join().unwrap(), Some(0x55));
+}
@@ -9,6 +9,7 @@
  */
 
 use std::fs::File;
+use std::io;
 use std::io::Read;
 use std::rc::Rc;
 use std::sync::{Arc, RwLock};
@@ -16,8 +17,11 @@ use ::{high_byte, low_byte};
 use cartridge::Cartridge;
 use cpu::Cpu;
 use join_bytes;
+use state::{StateReader, StateWriter};
 
 const MEMORY_SIZE: usize = 65536; // 64 * 1024 (64KB)
+/// Below it, the cartridge and the PPU answer in place of the memory
+const WORK_RAM: usize = 0xC000;
 
 #[derive(Debug)]
 pub struct Memory{
@@ -68,6 +72,31 @@ impl Memory {
         a
     }
 
+    /// Fill the work RAM and the HRAM with the garbage they power on with,
+    /// the same for a given seed
+    pub fn randomize(&mut self, seed: u64) {
+        // xorshift64, its state must not be 0
+        let mut x = seed ^ 0x9E37_79B9_7F4A_7C15;
+        if x == 0 {
+            x = 1;
+        }
+        for addr in (0xC000..0xE000).chain(0xFF80..0xFFFF) {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.mem[addr] = (x >> 32) as u8;
+        }
+    }
+
+    /// From the work RAM up, the rest is never read
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.mem[WORK_RAM..]);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.mem[WORK_RAM..])
+    }
+
     pub fn get_from_file(path: String) -> Vec<u8> {
         let mut data: Vec<u8> = Vec::new();
         let mut file = File::open(path).unwrap();
@@ -96,3 +125,23 @@ fn read_write() {
     mem.write_byte(0x0000, 0xde);
     assert_eq!(mem.read_byte(0x0000), 0xde);
 }*/
+
+#[test]
+fn state_from_work_ram() {
+    let mut mem = Memory::new();
+    mem.write_byte(0xC010, 0x42);
+    mem.write_byte(0xFF80, 0x43);
+
+    // The whole 64KiB does not fit the block
+    let mut w = StateWriter::new();
+    w.write_bytes(&mem.mem);
+    assert!(Memory::new().load_state(&mut StateReader::new(&w.into_inner())).is_err());
+
+    let mut w = StateWriter::new();
+    mem.save_state(&mut w);
+    let data = w.into_inner();
+    assert_eq!(data.len(), 4 + MEMORY_SIZE - WORK_RAM);
+    let mut restored = Memory::new();
+    restored.load_state(&mut StateReader::new(&data)).unwrap();
+    assert_eq!(restored.mem, mem.mem);
+}
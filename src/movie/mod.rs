@@ -0,0 +1,366 @@
+/*
+ * Input movies, the buttons of each frame replayed from the same start
+ *
+ * "GBMV", format version (u16)
+ * rom checksum (u16) and size (u32)
+ * RTC epoch (u64), the unix time the cartridge clock shows at tick 0
+ * RAM seed (u64), for the work RAM garbage at power on
+ * start: 0 power on, followed by the battery RAM, or 1 save state
+ * inputs: count (u32), then a byte per frame, directions in the low
+ *         nibble and action buttons in the high one
+ * sync: count (u32), then a hash (u64) of the state every SYNC_INTERVAL
+ *       frames, to check the replay stays on the recorded run
+ *
+ * A movie frame lasts FRAME_TICKS of the normal speed clock from the
+ * start, whether the LCD is on or not. The buttons only change when one
+ * starts, so `step` and `run_frame` record and replay the same way. The
+ * clock does not run in STOP mode, there the next frame starts as soon
+ * as its buttons differ, so that they can wake the cpu up.
+ */
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use gameboy::{GameBoy, FRAME_TICKS};
+use joypad::ButtonState;
+use state::{StateReader, StateWriter};
+
+pub const SYNC_INTERVAL: usize = 60;
+
+const MAGIC: &[u8; 4] = b"GBMV";
+const MOVIE_VERSION: u16 = 1;
+
+/// Hash of a save state, FNV-1a
+pub fn state_hash(state: &[u8]) -> u64 {
+    state.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+/// What the machine runs the movie from
+#[derive(Clone, Debug, PartialEq)]
+pub enum MovieStart {
+    /// Power on, with the battery RAM of the cartridge
+    PowerOn(Vec<u8>),
+    /// A save state
+    State(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_checksum: u16,
+    pub rom_size: u32,
+    pub rtc_epoch: u64,
+    pub ram_seed: u64,
+    pub start: MovieStart,
+    /// Buttons held during each frame
+    pub inputs: Vec<ButtonState>,
+    /// State hash after every SYNC_INTERVAL frames
+    pub sync: Vec<u64>,
+}
+
+impl Movie {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        for &b in MAGIC {
+            w.write_u8(b);
+        }
+        w.write_u16(MOVIE_VERSION);
+        w.write_u16(self.rom_checksum);
+        w.write_u32(self.rom_size);
+        w.write_u64(self.rtc_epoch);
+        w.write_u64(self.ram_seed);
+        match self.start {
+            MovieStart::PowerOn(ref battery) => {
+                w.write_u8(0);
+                w.write_bytes(battery);
+            }
+            MovieStart::State(ref state) => {
+                w.write_u8(1);
+                w.write_bytes(state);
+            }
+        }
+        let inputs: Vec<u8> = self.inputs.iter().map(|b| b.directions() | b.actions() << 4).collect();
+        w.write_bytes(&inputs);
+        w.write_u32(self.sync.len() as u32);
+        for &hash in &self.sync {
+            w.write_u64(hash);
+        }
+        w.into_inner()
+    }
+
+    pub fn decode(data: &[u8]) -> io::Result<Movie> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut r = StateReader::new(data);
+        for &b in MAGIC {
+            if r.read_u8()? != b {
+                return Err(invalid("not a movie"));
+            }
+        }
+        if r.read_u16()? != MOVIE_VERSION {
+            return Err(invalid("unsupported movie version"));
+        }
+        let rom_checksum = r.read_u16()?;
+        let rom_size = r.read_u32()?;
+        let rtc_epoch = r.read_u64()?;
+        let ram_seed = r.read_u64()?;
+        let start = match r.read_u8()? {
+            0 => MovieStart::PowerOn(r.read_bytes()?),
+            1 => MovieStart::State(r.read_bytes()?),
+            _ => return Err(invalid("unknown movie start")),
+        };
+        let inputs = r.read_bytes()?.iter().map(|&v| ButtonState::from_nibbles(v & 0x0F, v >> 4)).collect();
+        let mut sync = Vec::new();
+        for _ in 0..r.read_u32()? {
+            sync.push(r.read_u64()?);
+        }
+        Ok(Movie { rom_checksum, rom_size, rtc_epoch, ram_seed, start, inputs, sync })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.encode())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Movie> {
+        Movie::decode(&fs::read(path)?)
+    }
+}
+
+/// A movie being recorded or played back, frame by frame
+pub struct MovieRun {
+    pub movie: Movie,
+    /// Playing the movie back, recording it otherwise
+    pub playing: bool,
+    /// Frames run since the start of the movie
+    pub frame: usize,
+    /// First frame after which the state differed from the recording
+    pub desync: Option<usize>,
+    /// Buttons the player holds, recorded when the next frame starts
+    pub held: ButtonState,
+    /// Normal speed clock the next frame starts at
+    next_frame: u64,
+    sync_pending: bool,
+    /// .sav file of the cartridge, put back once the playback stops
+    save_path: Option<PathBuf>,
+}
+
+impl MovieRun {
+    /// Movie starting at a clock of the cpu
+    pub fn new(movie: Movie, playing: bool, clock: u64, held: ButtonState) -> MovieRun {
+        MovieRun {
+            movie,
+            playing,
+            frame: 0,
+            desync: None,
+            held,
+            next_frame: clock,
+            sync_pending: false,
+            save_path: None,
+        }
+    }
+
+    /// The clock reached the start of the next frame
+    pub fn frame_due(&self, clock: u64) -> bool {
+        clock >= self.next_frame
+    }
+
+    /// The buttons of the next frame are not `buttons`
+    pub fn input_changes(&self, buttons: ButtonState) -> bool {
+        if self.playing {
+            self.movie.inputs.get(self.frame).map_or(false, |&next| next != buttons)
+        } else {
+            self.held != buttons
+        }
+    }
+
+    /// Start the next frame, returns the buttons held during it: the
+    /// recorded ones when playing back, none past the end of the movie
+    pub fn begin_frame(&mut self) -> Option<ButtonState> {
+        let input = if self.playing {
+            self.movie.inputs.get(self.frame).cloned()
+        } else {
+            self.movie.inputs.push(self.held);
+            Some(self.held)
+        };
+        let in_movie = self.frame < self.movie.inputs.len();
+        self.frame += 1;
+        self.next_frame += FRAME_TICKS;
+        self.sync_pending = in_movie && self.frame % SYNC_INTERVAL == 0;
+        input
+    }
+
+    /// A state hash has to be recorded or checked, the last frame is over
+    pub fn sync_due(&self) -> bool {
+        self.sync_pending
+    }
+
+    pub fn sync(&mut self, hash: u64) {
+        self.sync_pending = false;
+        let index = self.frame / SYNC_INTERVAL - 1;
+        if !self.playing {
+            self.movie.sync.push(hash);
+        } else if self.desync.is_none() && self.movie.sync.get(index).map_or(false, |&h| h != hash) {
+            self.desync = Some(self.frame);
+        }
+    }
+
+    /// Every recorded frame was played back
+    pub fn finished(&self) -> bool {
+        self.playing && self.frame >= self.movie.inputs.len()
+    }
+}
+
+impl GameBoy {
+    /// Start recording the buttons held during each frame. From power on,
+    /// the machine must not have run yet: its work RAM is filled from
+    /// `ram_seed`. Otherwise the movie starts from a save state. Either
+    /// way the RTC then runs on the emulated time from `rtc_epoch`.
+    pub fn start_movie(&mut self, power_on: bool, rtc_epoch: u64, ram_seed: u64) -> io::Result<()> {
+        if power_on && self.cpu.ticks() != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the machine already ran"));
+        }
+        self.end_movie();
+        self.set_rtc_epoch(Some(rtc_epoch));
+        let start = if power_on {
+            self.mem.randomize(ram_seed);
+            MovieStart::PowerOn(self.cartridge.battery_dump())
+        } else {
+            MovieStart::State(self.save_state())
+        };
+        let movie = Movie {
+            rom_checksum: self.cartridge.checksum(),
+            rom_size: self.cartridge.size() as u32,
+            rtc_epoch,
+            ram_seed,
+            start,
+            inputs: Vec::new(),
+            sync: Vec::new(),
+        };
+        self.movie = Some(MovieRun::new(movie, false, self.cpu.clock(), self.joypad.buttons));
+        Ok(())
+    }
+
+    /// Stop recording or playing back, returns the movie recorded
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match self.end_movie() {
+            Some(run) if !run.playing => Some(run.movie),
+            _ => None,
+        }
+    }
+
+    /// Take the movie off the machine, the cartridge gets its .sav file
+    /// back
+    fn end_movie(&mut self) -> Option<MovieRun> {
+        let mut run = self.movie.take();
+        if let Some(path) = run.as_mut().and_then(|run| run.save_path.take()) {
+            self.cartridge.set_save_path(Some(path));
+        }
+        run
+    }
+
+    /// Put the machine at the start of a movie and play it back on the
+    /// next frames, the buttons of the player are ignored until its end.
+    /// A power on movie needs a machine that did not run yet. The battery
+    /// RAM of the movie is not written to the .sav file of the cartridge
+    /// until the playback is stopped.
+    pub fn play_movie(&mut self, movie: Movie) -> io::Result<()> {
+        if movie.rom_checksum != self.cartridge.checksum() || movie.rom_size as usize != self.cartridge.size() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "movie of another rom"));
+        }
+        if let MovieStart::PowerOn(_) = movie.start {
+            if self.cpu.ticks() != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "the machine already ran"));
+            }
+        }
+
+        // The player's own save is flushed, then left alone
+        self.end_movie();
+        self.cartridge.flush()?;
+        let save_path = self.cartridge.save_path().map(Path::to_path_buf);
+        self.cartridge.set_save_path(None);
+
+        // The clock is set first, so that the RTC it starts from is the
+        // recorded one
+        self.set_rtc_epoch(Some(movie.rtc_epoch));
+        match movie.start {
+            MovieStart::PowerOn(ref battery) => {
+                self.cartridge.battery_load(battery);
+                self.mem.randomize(movie.ram_seed);
+            }
+            MovieStart::State(ref state) => {
+                if let Err(e) = self.load_state(state) {
+                    self.cartridge.set_save_path(save_path);
+                    return Err(e);
+                }
+            }
+        }
+        let mut run = MovieRun::new(movie, true, self.cpu.clock(), ButtonState::default());
+        run.save_path = save_path;
+        self.movie = Some(run);
+        Ok(())
+    }
+
+    /// A movie is played back and has frames left
+    pub fn movie_playing(&self) -> bool {
+        self.movie.as_ref().map_or(false, |run| run.playing && !run.finished())
+    }
+}
+
+#[test]
+fn encoding() {
+    // Left and start
+    let buttons = ButtonState::from_nibbles(0x02, 0x08);
+    let movie = Movie {
+        rom_checksum: 0x1234,
+        rom_size: 0x8000,
+        rtc_epoch: 1_700_000_000,
+        ram_seed: 42,
+        start: MovieStart::PowerOn(vec![1, 2, 3]),
+        inputs: vec![ButtonState::default(), buttons],
+        sync: vec![0xDEAD_BEEF],
+    };
+    let data = movie.encode();
+    assert_eq!(data[data.len() - 14..data.len() - 12], [0x00, 0x82]);
+    assert_eq!(Movie::decode(&data).unwrap(), movie);
+    assert!(Movie::decode(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn sync_points() {
+    let movie = Movie {
+        rom_checksum: 0,
+        rom_size: 0,
+        rtc_epoch: 0,
+        ram_seed: 0,
+        start: MovieStart::State(Vec::new()),
+        inputs: Vec::new(),
+        sync: Vec::new(),
+    };
+    let mut run = MovieRun::new(movie, false, 100, ButtonState::default());
+    assert!(!run.frame_due(99) && run.frame_due(100));
+    for frame in 0..SYNC_INTERVAL * 2 {
+        // The hash of the frame that just ended
+        assert_eq!(run.sync_due(), frame > 0 && frame % SYNC_INTERVAL == 0);
+        if run.sync_due() {
+            run.sync(frame as u64);
+        }
+        run.held.a = frame % 2 == 1;
+        assert_eq!(run.begin_frame(), Some(run.held));
+    }
+    assert!(run.frame_due(100 + FRAME_TICKS * 120) && !run.frame_due(99 + FRAME_TICKS * 120));
+    assert!(run.sync_due());
+    run.sync(120);
+    assert_eq!(run.movie.sync, vec![60, 120]);
+    assert!(run.movie.inputs[1].a && !run.movie.inputs[2].a);
+
+    // Played back with another state after frame 120
+    let mut run = MovieRun::new(run.movie, true, 0, ButtonState::default());
+    for _ in 0..SYNC_INTERVAL * 2 {
+        if run.sync_due() {
+            run.sync(SYNC_INTERVAL as u64);
+        }
+        assert!(run.begin_frame().is_some());
+    }
+    run.sync(SYNC_INTERVAL as u64);
+    assert!(run.finished());
+    assert_eq!(run.begin_frame(), None);
+    assert_eq!(run.desync, Some(120));
+}
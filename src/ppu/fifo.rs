@@ -0,0 +1,442 @@
+/*
+ * Pixel FIFO renderer, run one dot at a time
+ *
+ * Mode 2 lasts 80 dots, mode 3 outputs one pixel per dot once the
+ * background fetcher filled the FIFO, so its length depends on SCX, the
+ * window and the sprites of the line. Mode 0 pads the line to 456 dots.
+ *
+ * Fetcher: tile number, tile data low, tile data high (2 dots each), then
+ * the 8 pixels are pushed as soon as the FIFO has room for them.
+ */
+
+use std::collections::VecDeque;
+use std::io;
+use ppu::{GpuMode, Ppu, SCREEN_HEIGHT, SCREEN_WIDTH, shade};
+use state::{StateReader, StateWriter};
+
+const LINE_DOTS: u16 = 456;
+const OAM_DOTS: u16 = 80;
+/// Dots of the fetcher before its 8 pixels are ready to be pushed
+const FETCH_DOTS: u8 = 6;
+
+#[derive(Clone, Copy, Default)]
+pub struct ObjPixel {
+    pub color: u8,
+    /// OBP1 in place of OBP0
+    pub palette: bool,
+    /// BG colors 1-3 are drawn over this pixel
+    pub behind_bg: bool,
+}
+
+pub struct Fifo {
+    /// Dot of the current line, 0-455
+    pub dot: u16,
+    pub bg: VecDeque<u8>,
+    pub obj: VecDeque<ObjPixel>,
+    /// Fetcher dot, 0-5 fetching, 6 waiting for room in the FIFO
+    pub step: u8,
+    /// Tile column the fetcher reads next
+    pub fetcher_x: u8,
+    tile: u8,
+    low: u8,
+    high: u8,
+    /// Next pixel to output on the line
+    pub lx: u8,
+    /// SCX fine scroll pixels left to throw away
+    pub discard: u8,
+    /// Dots the pixel output is paused for
+    pub stall: u8,
+    /// Sprites of the line not fetched yet
+    pub sprites: Vec<usize>,
+    /// Tiles a sprite already paid the fetcher wait for
+    penalized: Vec<i16>,
+    /// The fetcher switched to the window on this line
+    pub window: bool,
+    /// LY matched WY during this frame
+    pub window_y_hit: bool,
+}
+
+impl Default for Fifo {
+    fn default() -> Fifo {
+        Fifo::new()
+    }
+}
+
+impl Fifo {
+    pub fn new() -> Fifo {
+        Fifo {
+            dot: 0,
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: 0,
+            fetcher_x: 0,
+            tile: 0,
+            low: 0,
+            high: 0,
+            lx: 0,
+            discard: 0,
+            stall: 0,
+            sprites: Vec::new(),
+            penalized: Vec::new(),
+            window: false,
+            window_y_hit: false,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.dot);
+        w.write_bytes(&self.bg.iter().cloned().collect::<Vec<u8>>());
+        let obj: Vec<u8> = self.obj.iter()
+            .map(|p| p.color | (p.palette as u8) << 2 | (p.behind_bg as u8) << 3)
+            .collect();
+        w.write_bytes(&obj);
+        w.write_u8(self.step);
+        w.write_u8(self.fetcher_x);
+        w.write_u8(self.tile);
+        w.write_u8(self.low);
+        w.write_u8(self.high);
+        w.write_u8(self.lx);
+        w.write_u8(self.discard);
+        w.write_u8(self.stall);
+        w.write_bytes(&self.sprites.iter().map(|&s| s as u8).collect::<Vec<u8>>());
+        w.write_u32(self.penalized.len() as u32);
+        for &tile in &self.penalized {
+            w.write_u16(tile as u16);
+        }
+        w.write_bool(self.window);
+        w.write_bool(self.window_y_hit);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.dot = r.read_u16()? % LINE_DOTS;
+        self.bg = r.read_bytes()?.iter().map(|&c| c & 0x03).collect();
+        self.obj = r.read_bytes()?.iter()
+            .map(|&p| ObjPixel { color: p & 0x03, palette: p & 0x04 != 0, behind_bg: p & 0x08 != 0 })
+            .collect();
+        self.step = r.read_u8()?.min(FETCH_DOTS);
+        self.fetcher_x = r.read_u8()?;
+        self.tile = r.read_u8()?;
+        self.low = r.read_u8()?;
+        self.high = r.read_u8()?;
+        self.lx = r.read_u8()?;
+        if self.lx as usize > SCREEN_WIDTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "pixel FIFO past the end of the line"));
+        }
+        self.discard = r.read_u8()?;
+        self.stall = r.read_u8()?;
+        // Indexes into the 40 OAM entries
+        self.sprites = r.read_bytes()?.iter().map(|&s| s as usize % 40).collect();
+        self.penalized.clear();
+        for _ in 0..r.read_u32()? {
+            self.penalized.push(r.read_u16()? as i16);
+        }
+        self.window = r.read_bool()?;
+        self.window_y_hit = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Ppu {
+    /// Run one dot, returns true when VBLANK starts
+    pub fn fifo_dot(&mut self) -> bool {
+        let mut vblank = false;
+
+        if (self.scanline as usize) < SCREEN_HEIGHT {
+            if self.fifo.dot == 0 {
+                if self.scanline == self.window_y {
+                    self.fifo.window_y_hit = true;
+                }
+                self.mode = if self.first_line { GpuMode::HBLANK } else { GpuMode::OAM };
+            }
+            if self.fifo.dot == OAM_DOTS {
+                self.first_line = false;
+                self.mode = GpuMode::VRAM;
+                self.start_mode3();
+            }
+            if self.mode == GpuMode::VRAM {
+                self.mode3_dot();
+            }
+        }
+
+        self.fifo.dot += 1;
+        if self.fifo.dot == LINE_DOTS {
+            self.fifo.dot = 0;
+            self.scanline += 1;
+            if self.scanline as usize == SCREEN_HEIGHT {
+                self.mode = GpuMode::VBLANK;
+                vblank = true;
+            } else if self.scanline > 153 {
+                self.scanline = 0;
+                self.window_line = 0;
+                self.blank_frame = false;
+                self.fifo.window_y_hit = false;
+            }
+        }
+        vblank
+    }
+
+    fn start_mode3(&mut self) {
+        let sprites = if self.control & 0x02 != 0 { self.scan_oam() } else { Vec::new() };
+        let fifo = &mut self.fifo;
+        fifo.bg.clear();
+        fifo.obj.clear();
+        fifo.step = 0;
+        fifo.fetcher_x = 0;
+        fifo.lx = 0;
+        fifo.discard = self.scroll_x % 8;
+        // The first fetch of the line is thrown away
+        fifo.stall = FETCH_DOTS;
+        fifo.sprites = sprites;
+        fifo.penalized.clear();
+        fifo.window = false;
+    }
+
+    fn window_triggered(&self) -> bool {
+        self.control & 0x21 == 0x21
+            && self.fifo.window_y_hit
+            && !self.fifo.window
+            && self.window_x <= 166
+            && self.fifo.lx + 7 >= self.window_x
+    }
+
+    /// Pause the output to fetch a sprite starting at the next pixel:
+    /// 6 dots, plus the wait for the background fetcher to finish its
+    /// tile for the first sprite over that tile
+    fn fetch_sprite(&mut self) -> bool {
+        let lx = self.fifo.lx as i16;
+        let found = self.fifo.sprites.iter().position(|&i| {
+            let x = self.oam[i * 4 + 1] as i16;
+            x != 0 && (x - 8).max(0) == lx
+        });
+        let index = match found {
+            Some(index) => self.fifo.sprites.remove(index),
+            None => return false,
+        };
+
+        let left = self.oam[index * 4 + 1] as i16 - 8;
+        let offset = if self.fifo.window { 7 - self.window_x as i16 } else { self.scroll_x as i16 };
+        let column = left + offset;
+        let mut penalty = FETCH_DOTS;
+        if !self.fifo.penalized.contains(&(column >> 3)) {
+            self.fifo.penalized.push(column >> 3);
+            penalty += 5 - (column & 7).min(5) as u8;
+        }
+        self.fifo.stall = penalty - 1;
+
+        // Earlier sprites keep their opaque pixels
+        let attributes = self.oam[index * 4 + 3];
+        while self.fifo.obj.len() < 8 {
+            self.fifo.obj.push_back(ObjPixel::default());
+        }
+        for i in 0..8 {
+            let x = lx + i as i16;
+            if x < left || x >= left + 8 || self.fifo.obj[i].color != 0 {
+                continue;
+            }
+            self.fifo.obj[i] = ObjPixel {
+                color: self.sprite_pixel(index, x as u8),
+                palette: attributes & 0x10 != 0,
+                behind_bg: attributes & 0x80 != 0,
+            };
+        }
+        true
+    }
+
+    fn fetcher_dot(&mut self) {
+        let step = self.fifo.step;
+        match step {
+            0 => {
+                let (high_map, x, y) = if self.fifo.window {
+                    (self.control & 0x40 != 0, self.fifo.fetcher_x, self.window_line / 8)
+                } else {
+                    (
+                        self.control & 0x08 != 0,
+                        (self.scroll_x / 8).wrapping_add(self.fifo.fetcher_x) & 0x1F,
+                        self.scanline.wrapping_add(self.scroll_y) / 8,
+                    )
+                };
+                let map = if high_map { 0x1C00 } else { 0x1800 };
+                self.fifo.tile = self.vram[map + y as usize * 32 + (x & 0x1F) as usize];
+            }
+            2 | 4 => {
+                let row = if self.fifo.window {
+                    self.window_line % 8
+                } else {
+                    self.scanline.wrapping_add(self.scroll_y) % 8
+                };
+                let data = self.vram[self.bg_tile(self.fifo.tile) + row as usize * 2 + step as usize / 4];
+                if step == 2 {
+                    self.fifo.low = data;
+                } else {
+                    self.fifo.high = data;
+                }
+            }
+            _ => {}
+        }
+
+        if step < FETCH_DOTS {
+            self.fifo.step += 1;
+        } else if self.fifo.bg.len() <= 8 {
+            for bit in (0..8).rev() {
+                let color = ((self.fifo.high >> bit) & 0x01) << 1 | ((self.fifo.low >> bit) & 0x01);
+                self.fifo.bg.push_back(color);
+            }
+            self.fifo.fetcher_x = self.fifo.fetcher_x.wrapping_add(1);
+            self.fifo.step = 0;
+        }
+    }
+
+    fn mode3_dot(&mut self) {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return;
+        }
+
+        if self.fifo.discard == 0 {
+            if self.control & 0x02 != 0 && self.fetch_sprite() {
+                return;
+            }
+            if self.window_triggered() {
+                self.fifo.window = true;
+                self.fifo.bg.clear();
+                self.fifo.fetcher_x = 0;
+                self.fifo.step = 0;
+                // Left of the screen, the window starts at column 7 - WX
+                if self.window_x < 7 {
+                    self.fifo.discard = 7 - self.window_x;
+                }
+            }
+        }
+
+        self.fetcher_dot();
+
+        let bg = match self.fifo.bg.pop_front() {
+            Some(bg) => bg,
+            None => return,
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let bg = if self.control & 0x01 != 0 { bg } else { 0 };
+        let obj = self.fifo.obj.pop_front().unwrap_or_default();
+        let color = if self.control & 0x02 != 0 && obj.color != 0 && !(obj.behind_bg && bg != 0) {
+            let palette = if obj.palette { self.obj_palette1 } else { self.obj_palette0 };
+            shade(palette, obj.color)
+        } else {
+            shade(self.bg_palette, bg)
+        };
+        if !self.blank_frame {
+            self.frame[self.scanline as usize * SCREEN_WIDTH + self.fifo.lx as usize] = color;
+        }
+
+        self.fifo.lx += 1;
+        if self.fifo.lx as usize == SCREEN_WIDTH {
+            self.mode = GpuMode::HBLANK;
+            if self.fifo.window {
+                self.window_line += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn mode3_length(ppu: &mut Ppu) -> u16 {
+    ppu.scanline = 0;
+    ppu.fifo = Fifo::new();
+    for _ in 0..OAM_DOTS {
+        ppu.fifo_dot();
+    }
+    let mut dots = 1;
+    ppu.fifo_dot();
+    while ppu.mode == GpuMode::VRAM {
+        ppu.fifo_dot();
+        dots += 1;
+    }
+    dots
+}
+
+#[test]
+fn mode3_timing() {
+    let mut ppu = Ppu::with_renderer(::ppu::Renderer::Fifo);
+    ppu.control = 0x83;
+    assert_eq!(mode3_length(&mut ppu), 172);
+
+    ppu.scroll_x = 3;
+    assert_eq!(mode3_length(&mut ppu), 175);
+
+    // A sprite at X = 0 waits for a whole tile fetch
+    ppu.scroll_x = 0;
+    ppu.oam[0..4].copy_from_slice(&[16, 8, 0, 0]);
+    assert_eq!(mode3_length(&mut ppu), 183);
+
+    // A second one on the same tile only pays for its own fetch
+    ppu.oam[4..8].copy_from_slice(&[16, 12, 0, 0]);
+    assert_eq!(mode3_length(&mut ppu), 189);
+}
+
+#[test]
+fn same_picture_as_scanline() {
+    // The window in the middle of the line, then cut by the left edge
+    for &window_x in [90, 3].iter() {
+        let mut scanline = Ppu::new();
+        let mut fifo = Ppu::with_renderer(::ppu::Renderer::Fifo);
+
+        for ppu in [&mut scanline, &mut fifo].iter_mut() {
+            ppu.control = 0xF3;
+            ppu.bg_palette = 0b1110_0100;
+            ppu.obj_palette0 = 0b1110_0100;
+            ppu.obj_palette1 = 0b0001_1011;
+            for i in 0..0x800 {
+                ppu.vram[i] = (i * 7 + i / 16) as u8;
+            }
+            for i in 0..0x800 {
+                ppu.vram[0x1800 + i] = (i % 96) as u8;
+            }
+            ppu.scroll_x = 5;
+            ppu.scroll_y = 3;
+            ppu.window_x = window_x;
+            ppu.window_y = 4;
+            let sprites: [[u8; 4]; 4] = [[16, 8, 3, 0x00], [18, 12, 4, 0x30], [20, 40, 5, 0x80], [16, 100, 6, 0x40]];
+            for (i, sprite) in sprites.iter().enumerate() {
+                ppu.oam[i * 4..i * 4 + 4].copy_from_slice(sprite);
+            }
+        }
+
+        for line in 0..8 {
+            scanline.scanline = line;
+            scanline.render_scanline();
+        }
+        for _ in 0..8 * LINE_DOTS {
+            fifo.fifo_dot();
+        }
+        assert_eq!(&fifo.frame[..8 * SCREEN_WIDTH], &scanline.frame[..8 * SCREEN_WIDTH]);
+        assert_eq!(fifo.window_line, scanline.window_line);
+    }
+}
+
+#[test]
+fn corrupted_state() {
+    let load = |fifo: &Fifo| {
+        let mut w = StateWriter::new();
+        fifo.save_state(&mut w);
+        let data = w.into_inner();
+        let mut loaded = Fifo::new();
+        loaded.load_state(&mut StateReader::new(&data)).map(|_| loaded)
+    };
+    let mut fifo = Fifo::new();
+    fifo.step = 200;
+    assert_eq!(load(&fifo).unwrap().step, FETCH_DOTS);
+    fifo.lx = SCREEN_WIDTH as u8 + 1;
+    assert!(load(&fifo).is_err());
+
+    // The line is over, mode 3 cannot go on with it
+    let mut ppu = Ppu::with_renderer(::ppu::Renderer::Fifo);
+    ppu.mode = GpuMode::VRAM;
+    ppu.fifo.lx = SCREEN_WIDTH as u8;
+    let mut w = StateWriter::new();
+    ppu.save_state(&mut w);
+    let data = w.into_inner();
+    assert!(Ppu::new().load_state(&mut StateReader::new(&data)).is_err());
+}
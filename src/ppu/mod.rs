@@ -1,3 +1,26 @@
+use std::io;
+use state::{StateReader, StateWriter};
+use self::fifo::Fifo;
+
+pub mod fifo;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// Sprites the OAM scan keeps on a single line
+const SPRITES_PER_LINE: usize = 10;
+
+/// How the PPU draws, chosen when it is built
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Renderer {
+    /// Whole lines at the end of a fixed length mode 3, fast
+    Scanline,
+    /// Dot by dot pixel FIFO, mode 3 length and mid-line writes are
+    /// accurate
+    Fifo,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum GpuMode {
     HBLANK,
     VBLANK,
@@ -5,26 +28,582 @@ pub enum GpuMode {
     VRAM,
 }
 
+impl GpuMode {
+    /// Mode number reported in the low bits of STAT
+    pub fn bits(&self) -> u8 {
+        match *self {
+            GpuMode::HBLANK => 0,
+            GpuMode::VBLANK => 1,
+            GpuMode::OAM => 2,
+            GpuMode::VRAM => 3,
+        }
+    }
+
+    pub fn from_bits(v: u8) -> GpuMode {
+        match v & 0x03 {
+            0 => GpuMode::HBLANK,
+            1 => GpuMode::VBLANK,
+            2 => GpuMode::OAM,
+            _ => GpuMode::VRAM,
+        }
+    }
+}
+
 pub struct Ppu {
+    pub renderer: Renderer,
     pub mode: GpuMode,
     pub tick: u64,
     pub scanline: u8,
+    /// LCDC, 0xFF40
     pub control: u8,
+    /// STAT interrupt sources (bits 3-6) of 0xFF41, the rest is computed
+    pub stat: u8,
+    /// State of the STAT interrupt line, the interrupt is only requested
+    /// when it goes high
+    pub stat_line: bool,
     pub scroll_x: u8,
     pub scroll_y: u8,
-    pub ly_compare: u8
+    pub ly_compare: u8,
+    /// BGP, 0xFF47
+    pub bg_palette: u8,
+    /// OBP0 and OBP1, 0xFF48-0xFF49
+    pub obj_palette0: u8,
+    pub obj_palette1: u8,
+    /// WY, 0xFF4A
+    pub window_y: u8,
+    /// WX, 0xFF4B, the window starts at WX - 7
+    pub window_x: u8,
+    /// Window line to draw next, only moves on lines the window is shown
+    pub window_line: u8,
+    /// First line after the LCD is turned on, it has no OAM scan
+    pub first_line: bool,
+    /// The first frame after the LCD is turned on is not shown
+    pub blank_frame: bool,
+    /// 0x8000-0x9FFF
+    pub vram: Vec<u8>,
+    /// 0xFE00-0xFE9F
+    pub oam: Vec<u8>,
+    /// 160x144 shades, 0 (white) to 3 (black)
+    pub frame: Vec<u8>,
+    /// Frames completed, counted when VBLANK starts
+    pub frames: u64,
+    /// State of the FIFO renderer
+    pub fifo: Fifo,
 }
 
 impl Ppu {
     pub fn new() -> Ppu {
+        Ppu::with_renderer(Renderer::Scanline)
+    }
+
+    pub fn with_renderer(renderer: Renderer) -> Ppu {
         Ppu {
+            renderer,
             mode: GpuMode::HBLANK,
             tick: 0,
             scanline: 0,
             control: 0,
+            stat: 0,
+            stat_line: false,
             scroll_x: 0,
             scroll_y: 0,
-            ly_compare: 0
+            ly_compare: 0,
+            bg_palette: 0,
+            obj_palette0: 0,
+            obj_palette1: 0,
+            window_y: 0,
+            window_x: 0,
+            window_line: 0,
+            first_line: false,
+            blank_frame: false,
+            vram: vec![0; 0x2000],
+            oam: vec![0; 0xA0],
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frames: 0,
+            fifo: Fifo::new(),
+        }
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    /// LY as seen by the cpu, held at 0 while the LCD is off
+    pub fn ly(&self) -> u8 {
+        if self.lcd_enabled() { self.scanline } else { 0 }
+    }
+
+    pub fn coincidence(&self) -> bool {
+        self.ly() == self.ly_compare
+    }
+
+    /// Enabled STAT sources ORed together
+    fn stat_signal(&self) -> bool {
+        if !self.lcd_enabled() {
+            return false;
+        }
+        (self.stat & 0x40 != 0 && self.coincidence())
+            || match self.mode {
+                GpuMode::HBLANK => self.stat & 0x08 != 0,
+                // The OAM source also fires when VBLANK starts
+                GpuMode::VBLANK => {
+                    self.stat & 0x10 != 0 || (self.stat & 0x20 != 0 && self.scanline == 144)
+                }
+                GpuMode::OAM => self.stat & 0x20 != 0,
+                GpuMode::VRAM => false,
+            }
+    }
+
+    /// Refresh the STAT line, returns true when the LCDSTAT interrupt
+    /// is requested. A source going high while another one already holds
+    /// the line does not request a new interrupt.
+    pub fn update_stat_line(&mut self) -> bool {
+        let signal = self.stat_signal();
+        let rising = signal && !self.stat_line;
+        self.stat_line = signal;
+        rising
+    }
+
+    /// Everything but the renderer, which is a choice of the host
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.mode.bits());
+        w.write_u64(self.tick);
+        w.write_u8(self.scanline);
+        w.write_u8(self.control);
+        w.write_u8(self.stat);
+        w.write_bool(self.stat_line);
+        w.write_u8(self.scroll_x);
+        w.write_u8(self.scroll_y);
+        w.write_u8(self.ly_compare);
+        w.write_u8(self.bg_palette);
+        w.write_u8(self.obj_palette0);
+        w.write_u8(self.obj_palette1);
+        w.write_u8(self.window_y);
+        w.write_u8(self.window_x);
+        w.write_u8(self.window_line);
+        w.write_bool(self.first_line);
+        w.write_bool(self.blank_frame);
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.oam);
+        w.write_bytes(&self.frame);
+        w.write_u64(self.frames);
+        self.fifo.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.mode = GpuMode::from_bits(r.read_u8()?);
+        self.tick = r.read_u64()?;
+        self.scanline = r.read_u8()?;
+        self.control = r.read_u8()?;
+        self.stat = r.read_u8()? & 0x78;
+        self.stat_line = r.read_bool()?;
+        self.scroll_x = r.read_u8()?;
+        self.scroll_y = r.read_u8()?;
+        self.ly_compare = r.read_u8()?;
+        self.bg_palette = r.read_u8()?;
+        self.obj_palette0 = r.read_u8()?;
+        self.obj_palette1 = r.read_u8()?;
+        self.window_y = r.read_u8()?;
+        self.window_x = r.read_u8()?;
+        self.window_line = r.read_u8()?;
+        self.first_line = r.read_bool()?;
+        self.blank_frame = r.read_bool()?;
+        r.read_into(&mut self.vram)?;
+        r.read_into(&mut self.oam)?;
+        r.read_into(&mut self.frame)?;
+        self.frames = r.read_u64()?;
+        self.fifo.load_state(r)?;
+        // Mode 3 still has pixels of the line to output
+        if self.mode == GpuMode::VRAM && self.fifo.lx as usize >= SCREEN_WIDTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "pixel FIFO past the end of the line"));
+        }
+        Ok(())
+    }
+
+    /// LCDC bit 7 cleared, the PPU stops on line 0 and the screen goes
+    /// white
+    fn power_off(&mut self) {
+        self.scanline = 0;
+        self.tick = 0;
+        self.mode = GpuMode::HBLANK;
+        self.window_line = 0;
+        self.stat_line = false;
+        self.fifo = Fifo::new();
+        for pixel in self.frame.iter_mut() {
+            *pixel = 0;
+        }
+    }
+
+    /// LCDC bit 7 set, line 0 starts in HBLANK in place of the OAM scan
+    fn power_on(&mut self) {
+        self.tick = 0;
+        self.mode = GpuMode::HBLANK;
+        self.first_line = true;
+        self.blank_frame = true;
+    }
+
+    /// Registers at 0xFF40-0xFF4B, but the DMA one
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF40 => self.control,
+            0xFF41 => {
+                let mode = if self.lcd_enabled() { self.mode.bits() } else { 0 };
+                0x80 | self.stat | (self.coincidence() as u8) << 2 | mode
+            }
+            0xFF42 => self.scroll_y,
+            0xFF43 => self.scroll_x,
+            0xFF44 => self.ly(),
+            0xFF45 => self.ly_compare,
+            0xFF47 => self.bg_palette,
+            0xFF48 => self.obj_palette0,
+            0xFF49 => self.obj_palette1,
+            0xFF4A => self.window_y,
+            0xFF4B => self.window_x,
+            _ => 0xFF,
+        }
+    }
+
+    /// LY and the computed bits of STAT are read only
+    pub fn write_byte(&mut self, addr: u16, v: u8) {
+        match addr {
+            0xFF40 => {
+                let was_enabled = self.lcd_enabled();
+                self.control = v;
+                match (was_enabled, self.lcd_enabled()) {
+                    (true, false) => self.power_off(),
+                    (false, true) => self.power_on(),
+                    _ => {}
+                }
+            }
+            0xFF41 => self.stat = v & 0x78,
+            0xFF42 => self.scroll_y = v,
+            0xFF43 => self.scroll_x = v,
+            0xFF45 => self.ly_compare = v,
+            0xFF47 => self.bg_palette = v,
+            0xFF48 => self.obj_palette0 = v,
+            0xFF49 => self.obj_palette1 = v,
+            0xFF4A => self.window_y = v,
+            0xFF4B => self.window_x = v,
+            _ => {}
+        }
+    }
+
+    /// Color (0-3) of a pixel in the tile starting at a VRAM offset
+    fn tile_pixel(&self, tile: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[tile + y as usize * 2];
+        let high = self.vram[tile + y as usize * 2 + 1];
+        let bit = 7 - x;
+        ((high >> bit) & 0x01) << 1 | ((low >> bit) & 0x01)
+    }
+
+    /// VRAM offset of a BG/window tile, LCDC bit 4 selects between the
+    /// 0x8000 unsigned and the 0x8800 signed addressing
+    fn bg_tile(&self, index: u8) -> usize {
+        if self.control & 0x10 != 0 {
+            index as usize * 16
+        } else {
+            (0x1000 + index as i8 as isize * 16) as usize
+        }
+    }
+
+    /// Color of a pixel of the 256x256 BG/window plane
+    fn bg_pixel(&self, high_map: bool, x: u8, y: u8) -> u8 {
+        let map = if high_map { 0x1C00 } else { 0x1800 };
+        let index = self.vram[map + (y / 8) as usize * 32 + (x / 8) as usize];
+        self.tile_pixel(self.bg_tile(index), x % 8, y % 8)
+    }
+
+    /// OAM entries (by index) covering the current line, in drawing
+    /// priority order: lowest X first, then lowest index
+    fn scan_oam(&self) -> Vec<usize> {
+        let height = if self.control & 0x04 != 0 { 16 } else { 8 };
+        let ly = self.scanline as i16;
+        let mut sprites: Vec<usize> = (0..40)
+            .filter(|&i| {
+                let y = self.oam[i * 4] as i16 - 16;
+                ly >= y && ly < y + height
+            })
+            .take(SPRITES_PER_LINE)
+            .collect();
+        sprites.sort_by_key(|&i| (self.oam[i * 4 + 1], i));
+        sprites
+    }
+
+    /// Color of a sprite at a screen column, 0 being transparent
+    fn sprite_pixel(&self, sprite: usize, x: u8) -> u8 {
+        let attributes = self.oam[sprite * 4 + 3];
+        let tall = self.control & 0x04 != 0;
+        let height = if tall { 16 } else { 8 };
+
+        let mut row = (self.scanline as i16 - (self.oam[sprite * 4] as i16 - 16)) as u8;
+        let mut col = (x as i16 - (self.oam[sprite * 4 + 1] as i16 - 8)) as u8;
+        if attributes & 0x40 != 0 {
+            row = height - 1 - row;
+        }
+        if attributes & 0x20 != 0 {
+            col = 7 - col;
         }
+
+        let mut tile = self.oam[sprite * 4 + 2];
+        if tall {
+            tile &= 0xFE;
+        }
+        self.tile_pixel(tile as usize * 16, col, row)
+    }
+
+    /// Draw the sprites of the current line over the background colors
+    fn render_sprites(&mut self, bg: &[u8]) {
+        let sprites = self.scan_oam();
+        let line = self.scanline as usize * SCREEN_WIDTH;
+
+        for x in 0..SCREEN_WIDTH as u8 {
+            let visible = sprites.iter().cloned().find(|&i| {
+                let left = self.oam[i * 4 + 1] as i16 - 8;
+                x as i16 >= left && (x as i16) < left + 8 && self.sprite_pixel(i, x) != 0
+            });
+            if let Some(sprite) = visible {
+                let attributes = self.oam[sprite * 4 + 3];
+                // The BG-over-OBJ bit only lets BG colors 1-3 through
+                if attributes & 0x80 != 0 && bg[x as usize] != 0 {
+                    continue;
+                }
+                let palette = if attributes & 0x10 != 0 { self.obj_palette1 } else { self.obj_palette0 };
+                self.frame[line + x as usize] = shade(palette, self.sprite_pixel(sprite, x));
+            }
+        }
+    }
+
+    /// Draw the background, the window and the sprites of the current
+    /// line into the frame, run at the end of mode 3
+    pub fn render_scanline(&mut self) {
+        let ly = self.scanline;
+        if ly as usize >= SCREEN_HEIGHT || self.blank_frame {
+            return;
+        }
+
+        let bg_enabled = self.control & 0x01 != 0;
+        let window = bg_enabled
+            && self.control & 0x20 != 0
+            && self.window_y <= ly
+            && self.window_x <= 166;
+        let mut window_drawn = false;
+        let mut bg = [0u8; SCREEN_WIDTH];
+
+        for x in 0..SCREEN_WIDTH as u8 {
+            let color = if !bg_enabled {
+                0
+            } else if window && x + 7 >= self.window_x {
+                window_drawn = true;
+                self.bg_pixel(self.control & 0x40 != 0, x + 7 - self.window_x, self.window_line)
+            } else {
+                self.bg_pixel(
+                    self.control & 0x08 != 0,
+                    x.wrapping_add(self.scroll_x),
+                    ly.wrapping_add(self.scroll_y)
+                )
+            };
+            bg[x as usize] = color;
+            self.frame[ly as usize * SCREEN_WIDTH + x as usize] = shade(self.bg_palette, color);
+        }
+
+        if window_drawn {
+            self.window_line += 1;
+        }
+
+        if self.control & 0x02 != 0 {
+            self.render_sprites(&bg);
+        }
+    }
+}
+
+/// Shade a palette register gives to a color
+pub fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+#[cfg(test)]
+fn solid_tile(ppu: &mut Ppu, offset: usize, color: u8) {
+    for i in 0..8 {
+        ppu.vram[offset + i * 2] = if color & 0x01 != 0 { 0xFF } else { 0 };
+        ppu.vram[offset + i * 2 + 1] = if color & 0x02 != 0 { 0xFF } else { 0 };
+    }
+}
+
+#[test]
+fn tile_addressing() {
+    let mut ppu = Ppu::new();
+    ppu.bg_palette = 0b1110_0100;
+    ppu.control = 0x91;
+    // Tile 1 at 0x8010, tile 0x81 at 0x8810
+    solid_tile(&mut ppu, 0x0010, 3);
+    solid_tile(&mut ppu, 0x0810, 2);
+    // Tile -1 at 0x8FF0
+    solid_tile(&mut ppu, 0x0FF0, 1);
+    ppu.vram[0x1800] = 0x01;
+    ppu.vram[0x1801] = 0x81;
+    ppu.vram[0x1802] = 0xFF;
+
+    ppu.render_scanline();
+    assert_eq!(&ppu.frame[0..3], &[3, 3, 3]);
+    assert_eq!(&ppu.frame[8..11], &[2, 2, 2]);
+
+    // 0x8800 addressing, tile indexes are signed around 0x9000
+    ppu.control = 0x81;
+    ppu.render_scanline();
+    assert_eq!(ppu.frame[0], 0);
+    assert_eq!(ppu.frame[16], 1);
+}
+
+#[test]
+fn scroll_and_window() {
+    let mut ppu = Ppu::new();
+    ppu.bg_palette = 0b1110_0100;
+    ppu.control = 0xF1;
+    solid_tile(&mut ppu, 0x0010, 3);
+    solid_tile(&mut ppu, 0x0020, 1);
+    // BG map at 0x9800, window map at 0x9C00
+    ppu.vram[0x1800 + 32 * 2 + 1] = 0x01;
+    ppu.vram[0x1C00] = 0x02;
+
+    ppu.scroll_x = 8;
+    ppu.scroll_y = 16;
+    ppu.window_y = 0xFF;
+    ppu.render_scanline();
+    assert_eq!(&ppu.frame[0..9], &[3, 3, 3, 3, 3, 3, 3, 3, 0]);
+
+    // Window from x = 80 on, lines below WY only
+    ppu.window_x = 87;
+    ppu.window_y = 1;
+    ppu.render_scanline();
+    assert_eq!(ppu.window_line, 0);
+
+    ppu.scanline = 1;
+    ppu.render_scanline();
+    assert_eq!(ppu.frame[SCREEN_WIDTH + 79], 0);
+    assert_eq!(ppu.frame[SCREEN_WIDTH + 80], 1);
+    assert_eq!(ppu.window_line, 1);
+}
+
+#[cfg(test)]
+fn sprite(ppu: &mut Ppu, index: usize, y: u8, x: u8, tile: u8, attributes: u8) {
+    ppu.oam[index * 4..index * 4 + 4].copy_from_slice(&[y, x, tile, attributes]);
+}
+
+#[test]
+fn sprites() {
+    let mut ppu = Ppu::new();
+    ppu.bg_palette = 0b1110_0100;
+    ppu.obj_palette0 = 0b1110_0100;
+    ppu.obj_palette1 = 0b0000_0000;
+    ppu.control = 0x93;
+    solid_tile(&mut ppu, 0x0010, 1);
+    // Tile 2 has its left column only in color 3
+    for i in 0..8 {
+        ppu.vram[0x20 + i * 2] = 0x80;
+        ppu.vram[0x20 + i * 2 + 1] = 0x80;
     }
-}
\ No newline at end of file
+    ppu.vram[0x1801] = 0x01;
+
+    sprite(&mut ppu, 0, 16, 8, 2, 0x00);
+    // X flipped, the column moves to the right
+    sprite(&mut ppu, 1, 16, 20, 2, 0x20);
+    // Behind a BG color 1
+    sprite(&mut ppu, 2, 16, 16, 2, 0x80);
+    // Lower priority than sprite 0 on the same column, OBP1
+    sprite(&mut ppu, 3, 16, 8, 2, 0x10);
+
+    ppu.render_scanline();
+    assert_eq!(ppu.frame[0], 3);
+    assert_eq!(ppu.frame[1], 0);
+    assert_eq!(ppu.frame[8], 1);
+    // Sprite 1 starts at 12 but its colored column is flipped to 19
+    assert_eq!(ppu.frame[12], 1);
+    assert_eq!(ppu.frame[19], 3);
+
+    // Only the 10 first sprites of OAM are kept on a line
+    for i in 0..12 {
+        sprite(&mut ppu, i, 16, 24 + i as u8 * 8, 2, 0x00);
+    }
+    ppu.render_scanline();
+    assert_eq!(ppu.frame[16 + 9 * 8], 3);
+    assert_eq!(ppu.frame[16 + 10 * 8], 0);
+}
+
+#[test]
+fn tall_sprites() {
+    let mut ppu = Ppu::new();
+    ppu.obj_palette0 = 0b1110_0100;
+    ppu.control = 0x86;
+    solid_tile(&mut ppu, 0x0020, 1);
+    solid_tile(&mut ppu, 0x0030, 2);
+    // Tile 3 is used as 2, the low bit is ignored
+    sprite(&mut ppu, 0, 16, 8, 3, 0x00);
+
+    ppu.scanline = 8;
+    ppu.render_scanline();
+    assert_eq!(ppu.frame[8 * SCREEN_WIDTH], 2);
+
+    // Y flip swaps the two tiles
+    sprite(&mut ppu, 0, 16, 8, 3, 0x40);
+    ppu.render_scanline();
+    assert_eq!(ppu.frame[8 * SCREEN_WIDTH], 1);
+}
+
+#[test]
+fn stat_register() {
+    let mut ppu = Ppu::new();
+    ppu.write_byte(0xFF41, 0xFF);
+    ppu.write_byte(0xFF45, 0x42);
+    ppu.scanline = 0x42;
+
+    // Everything reads as 0 but the sources while the LCD is off
+    assert_eq!(ppu.read_byte(0xFF44), 0);
+    assert_eq!(ppu.read_byte(0xFF41), 0xF8);
+
+    ppu.write_byte(0xFF40, 0x80);
+    ppu.mode = GpuMode::VRAM;
+    assert_eq!(ppu.read_byte(0xFF44), 0x42);
+    assert_eq!(ppu.read_byte(0xFF41), 0xFF);
+    ppu.write_byte(0xFF44, 0);
+    assert_eq!(ppu.read_byte(0xFF44), 0x42);
+}
+
+#[test]
+fn stat_line_blocking() {
+    let mut ppu = Ppu::new();
+    ppu.control = 0x80;
+    ppu.stat = 0x48;
+    ppu.ly_compare = 1;
+
+    ppu.mode = GpuMode::VRAM;
+    assert!(!ppu.update_stat_line());
+    ppu.mode = GpuMode::HBLANK;
+    assert!(ppu.update_stat_line());
+
+    // LY=LYC while HBLANK still holds the line, no new interrupt
+    ppu.scanline = 1;
+    assert!(!ppu.update_stat_line());
+    ppu.mode = GpuMode::OAM;
+    assert!(!ppu.update_stat_line());
+
+    ppu.scanline = 2;
+    ppu.update_stat_line();
+    ppu.scanline = 1;
+    assert!(ppu.update_stat_line());
+}
+
+#[test]
+fn lcd_power() {
+    let mut ppu = Ppu::new();
+    ppu.write_byte(0xFF40, 0x91);
+    assert!(ppu.first_line && ppu.blank_frame);
+
+    ppu.scanline = 100;
+    ppu.mode = GpuMode::VRAM;
+    ppu.frame[0] = 3;
+    ppu.write_byte(0xFF40, 0x11);
+    assert_eq!(ppu.scanline, 0);
+    assert_eq!(ppu.mode, GpuMode::HBLANK);
+    assert_eq!(ppu.frame[0], 0);
+    assert_eq!(ppu.read_byte(0xFF41) & 0x03, 0);
+}
@@ -0,0 +1,350 @@
+/*
+ * Game Boy Printer, plugged into the serial port
+ *
+ * The console sends packets, the printer answers 0x00 to every byte but
+ * the two last ones:
+ *
+ * 0x88 0x33          magic
+ * command            0x01 init, 0x02 print, 0x04 data, 0x08 break, 0x0F status
+ * compression        1 for RLE compressed data
+ * length             16 bits, little endian
+ * data
+ * checksum           16 bits sum of the command to the data, little endian
+ * 0x00               answered with 0x81, the printer is there
+ * 0x00               answered with the status
+ *
+ * Data are 2bpp tiles, 40 per 160x16 band. Print takes the sheets count,
+ * the margins (before in the upper nibble, after in the lower one), the
+ * palette and the exposure.
+ */
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use serial::SerialDevice;
+use self::png::save_png;
+
+pub mod png;
+
+pub const PAPER_WIDTH: usize = 160;
+/// Data for 9 bands at most, a whole screen
+const BUFFER_SIZE: usize = 0x280 * 9;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const BREAK: u8 = 0x08;
+const INQUIRY: u8 = 0x0F;
+
+/* Status bits */
+pub const CHECKSUM_ERROR: u8 = 0x01;
+pub const PRINTING: u8 = 0x02;
+pub const IMAGE_DATA_FULL: u8 = 0x04;
+pub const UNPROCESSED_DATA: u8 = 0x08;
+pub const PACKET_ERROR: u8 = 0x10;
+
+/// Position in the packet of the next byte
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Expand RLE data: a control byte with bit 7 set repeats the next byte
+/// (control & 0x7F) + 2 times, otherwise (control + 1) bytes follow as is
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                let len = out.len() + (control & 0x7F) as usize + 2;
+                out.resize(len, byte);
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+pub struct Printer {
+    /// Where the printed pages are written, as `print_0001.png` and so on
+    pub directory: PathBuf,
+    pub status: u8,
+    stage: Stage,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    /// Tile data received since the last print
+    data: Vec<u8>,
+    /// Grayscale rows printed on the current sheet
+    page: Vec<u8>,
+    /// Number of the last page written
+    pub pages: u32,
+}
+
+impl Printer {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Printer {
+        Printer {
+            directory: directory.into(),
+            status: 0,
+            stage: Stage::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            data: Vec::new(),
+            page: Vec::new(),
+            pages: 0,
+        }
+    }
+
+    fn execute(&mut self) {
+        match self.command {
+            INIT => {
+                self.data.clear();
+                self.status = 0;
+            }
+            PRINT if self.packet.len() >= 4 => {
+                let margins = self.packet[1];
+                let palette = if self.packet[2] == 0 { 0xE4 } else { self.packet[2] };
+                // A margin before starts a new sheet
+                if margins >> 4 != 0 {
+                    self.feed();
+                }
+                self.print(palette);
+                if margins & 0x0F != 0 {
+                    self.feed();
+                }
+                self.status = self.status & !(UNPROCESSED_DATA | IMAGE_DATA_FULL) | PRINTING;
+            }
+            DATA => {
+                if self.compressed {
+                    let data = decompress(&self.packet);
+                    self.data.extend_from_slice(&data);
+                } else {
+                    self.data.extend_from_slice(&self.packet);
+                }
+                self.data.truncate(BUFFER_SIZE);
+                if !self.data.is_empty() {
+                    self.status |= UNPROCESSED_DATA;
+                }
+                if self.data.len() == BUFFER_SIZE {
+                    self.status |= IMAGE_DATA_FULL;
+                }
+            }
+            BREAK => {
+                self.data.clear();
+                self.status &= !(UNPROCESSED_DATA | IMAGE_DATA_FULL | PRINTING);
+            }
+            INQUIRY => {}
+            _ => self.status |= PACKET_ERROR,
+        }
+    }
+
+    /// Draw the buffered tiles at the bottom of the sheet
+    fn print(&mut self, palette: u8) {
+        let tiles_per_row = PAPER_WIDTH / 8;
+        let rows = self.data.len() / 16 / tiles_per_row;
+        for row in 0..rows * 8 {
+            for x in 0..PAPER_WIDTH {
+                let tile = (row / 8 * tiles_per_row + x / 8) * 16;
+                let line = tile + (row & 0x07) * 2;
+                let bit = 7 - (x & 0x07);
+                let color = (self.data[line] >> bit & 0x01) | (self.data[line + 1] >> bit & 0x01) << 1;
+                let shade = palette >> (color * 2) & 0x03;
+                self.page.push(0xFF - shade * 0x55);
+            }
+        }
+        self.data.clear();
+    }
+
+    /// Cut the sheet and write it out
+    pub fn feed(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+        let page = ::std::mem::take(&mut self.page);
+        if let Err(e) = self.save(&page) {
+            eprintln!("could not write the printed page: {}", e);
+        }
+    }
+
+    fn save(&mut self, page: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        let mut path;
+        loop {
+            self.pages += 1;
+            path = self.directory.join(format!("print_{:04}.png", self.pages));
+            if !path.exists() {
+                break;
+            }
+        }
+        save_png(path, PAPER_WIDTH as u32, (page.len() / PAPER_WIDTH) as u32, page)
+    }
+}
+
+/// A sheet printed without a margin after is cut when unplugged
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.feed();
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.stage = match self.stage {
+            Stage::Magic1 if byte == 0x88 => Stage::Magic2,
+            Stage::Magic1 => Stage::Magic1,
+            Stage::Magic2 if byte == 0x33 => {
+                self.checksum = 0;
+                self.packet.clear();
+                Stage::Command
+            }
+            Stage::Magic2 => Stage::Magic1,
+            Stage::Command => {
+                self.command = byte;
+                Stage::Compression
+            }
+            Stage::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                Stage::LengthLow
+            }
+            Stage::LengthLow => {
+                self.length = byte as u16;
+                Stage::LengthHigh
+            }
+            Stage::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                if self.length == 0 { Stage::ChecksumLow } else { Stage::Data }
+            }
+            Stage::Data => {
+                self.packet.push(byte);
+                if self.packet.len() == self.length as usize { Stage::ChecksumLow } else { Stage::Data }
+            }
+            Stage::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                Stage::ChecksumHigh
+            }
+            Stage::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                Stage::Alive
+            }
+            Stage::Alive => {
+                reply = 0x81;
+                Stage::Status
+            }
+            Stage::Status => {
+                reply = self.status;
+                // Printing is over once the console asked for it
+                if self.command == INQUIRY {
+                    self.status &= !PRINTING;
+                }
+                Stage::Magic1
+            }
+        };
+
+        match self.stage {
+            Stage::Compression | Stage::LengthLow | Stage::LengthHigh | Stage::Data | Stage::ChecksumLow => {
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+            }
+            // The whole packet is in, run it before the status goes out
+            Stage::Alive => {
+                if self.checksum == self.received_checksum {
+                    self.status &= !CHECKSUM_ERROR;
+                    self.execute();
+                } else {
+                    self.status |= CHECKSUM_ERROR;
+                }
+            }
+            _ => {}
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+    let mut packet = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+    packet.extend_from_slice(data);
+    let checksum = packet.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+
+    for &byte in [0x88, 0x33].iter().chain(packet.iter()).chain(checksum.to_le_bytes().iter()) {
+        assert_eq!(printer.transfer(byte), 0x00);
+    }
+    (printer.transfer(0x00), printer.transfer(0x00))
+}
+
+#[test]
+fn rle() {
+    assert_eq!(decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34, 0x80, 0xFF]), vec![0xAA, 0xAA, 0xAA, 0x12, 0x34, 0xFF, 0xFF]);
+}
+
+#[test]
+fn packets() {
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("gameboy-printer-{}", ::std::process::id()));
+    let mut printer = Printer::new(&dir);
+    assert_eq!(send_packet(&mut printer, INIT, false, &[]), (0x81, 0x00));
+
+    // A band of color 3, 640 bytes of 0xFF compressed in runs of 128,
+    // then the empty end of data packet
+    let band = [0xFE, 0xFF, 0xFE, 0xFF, 0xFE, 0xFF, 0xFE, 0xFF, 0xFE, 0xFF];
+    assert_eq!(decompress(&band).len(), 640);
+    assert_eq!(send_packet(&mut printer, DATA, true, &band), (0x81, UNPROCESSED_DATA));
+    assert_eq!(send_packet(&mut printer, DATA, false, &[]), (0x81, UNPROCESSED_DATA));
+
+    // A bad checksum is reported and the packet ignored
+    for &byte in [0x88, 0x33, PRINT, 0x00, 0x00, 0x00, 0x00, 0x00].iter() {
+        printer.transfer(byte);
+    }
+    assert_eq!((printer.transfer(0x00), printer.transfer(0x00)), (0x81, UNPROCESSED_DATA | CHECKSUM_ERROR));
+
+    // One sheet, margin after, palette with color 3 as light gray
+    assert_eq!(send_packet(&mut printer, PRINT, false, &[0x01, 0x03, 0x40, 0x40]), (0x81, PRINTING));
+    assert_eq!(send_packet(&mut printer, INQUIRY, false, &[]), (0x81, PRINTING));
+    assert_eq!(send_packet(&mut printer, INQUIRY, false, &[]), (0x81, 0x00));
+
+    let png = fs::read(dir.join(format!("print_{:04}.png", printer.pages))).unwrap();
+    assert_eq!(&png[16..24], &[0, 0, 0, 160, 0, 0, 0, 16]);
+    // First row, after the zlib and stored block headers
+    assert_eq!(&png[33 + 8 + 2 + 5..33 + 8 + 2 + 5 + 3], &[0x00, 0xAA, 0xAA]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn feed_on_drop() {
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("gameboy-printer-drop-{}", ::std::process::id()));
+    let mut printer = Printer::new(&dir);
+    send_packet(&mut printer, DATA, false, &[0xFF; 640]);
+    // No margin after, the sheet stays in the printer
+    send_packet(&mut printer, PRINT, false, &[0x01, 0x00, 0xE4, 0x40]);
+    assert!(!dir.join("print_0001.png").exists());
+    drop(printer);
+    assert!(dir.join("print_0001.png").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
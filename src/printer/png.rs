@@ -0,0 +1,111 @@
+/*
+ * Minimal PNG encoder, 8 bits grayscale with stored (uncompressed)
+ * deflate blocks
+ */
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+/// Largest payload of a stored deflate block
+const BLOCK_SIZE: usize = 0xFFFF;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 0x01 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+
+    let mut crc = kind.to_vec();
+    crc.extend_from_slice(data);
+    out.write_all(&crc32(&crc).to_be_bytes())
+}
+
+/// Zlib stream of stored blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Write a grayscale image, one byte per pixel row by row
+pub fn write_png<W: Write>(out: &mut W, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    if pixels.len() != width as usize * height as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "image size mismatch"));
+    }
+    out.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits grayscale, deflate, no filter, not interlaced
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    // Every row starts with its filter type, none
+    let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+    for row in pixels.chunks(width.max(1) as usize) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+pub fn save_png<P: AsRef<Path>>(path: P, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_png(&mut out, width, height, pixels)?;
+    out.flush()
+}
+
+#[test]
+fn checksums() {
+    assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+}
+
+#[test]
+fn stored_image() {
+    let mut out = Vec::new();
+    write_png(&mut out, 2, 2, &[0x00, 0x55, 0xAA, 0xFF]).unwrap();
+    assert_eq!(&out[..8], &SIGNATURE);
+    assert_eq!(&out[12..16], b"IHDR");
+    assert_eq!(&out[16..24], &[0, 0, 0, 2, 0, 0, 0, 2]);
+    assert_eq!(&out[out.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+
+    // Filter bytes in front of the rows, a single final block
+    let data = zlib_stored(&[0, 0x00, 0x55, 0, 0xAA, 0xFF]);
+    assert_eq!(&data[..7], &[0x78, 0x01, 0x01, 0x06, 0x00, 0xF9, 0xFF]);
+    assert_eq!(zlib_stored(&vec![0; BLOCK_SIZE + 1])[2], 0x00);
+}
@@ -0,0 +1,221 @@
+/*
+ * Rewind history, save states taken every few frames
+ *
+ * Snapshots are kept in a bounded ring buffer. Most of them are stored
+ * as the XOR of the state with the last keyframe, run-length encoded:
+ * only what changed since the keyframe takes memory. A keyframe and its
+ * deltas are dropped together, a group spans at most half the buffer so
+ * that the newest one is never the one dropped.
+ *
+ * Delta encoding, repeated until the end of the state:
+ * varint  bytes left unchanged
+ * varint  bytes changed, followed by their XOR with the keyframe
+ */
+
+use std::collections::VecDeque;
+use std::io;
+
+/// Snapshots between two keyframes
+const KEYFRAME_INTERVAL: usize = 30;
+
+fn write_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> io::Result<usize> {
+    let mut v = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated delta"))?;
+        *pos += 1;
+        v |= (byte as usize & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+        shift += 7;
+    }
+}
+
+/// XOR of `state` with `key`, both of the same size, run-length encoded
+pub fn encode_delta(key: &[u8], state: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < state.len() {
+        let start = i;
+        while i < state.len() && state[i] == key[i] {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+
+        let start = i;
+        while i < state.len() && state[i] != key[i] {
+            i += 1;
+        }
+        write_varint(&mut out, i - start);
+        out.extend(state[start..i].iter().zip(&key[start..i]).map(|(s, k)| s ^ k));
+    }
+    out
+}
+
+/// State an `encode_delta` output was made from
+pub fn decode_delta(key: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let mut state = key.to_vec();
+    let mut pos = 0;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos)?;
+        let changed = read_varint(delta, &mut pos)?;
+        if i + changed > state.len() || pos + changed > delta.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "delta out of the keyframe"));
+        }
+        for (byte, x) in state[i..i + changed].iter_mut().zip(&delta[pos..pos + changed]) {
+            *byte ^= x;
+        }
+        i += changed;
+        pos += changed;
+    }
+    Ok(state)
+}
+
+pub struct Snapshot {
+    /// Frames completed by the PPU when it was taken
+    pub frame: u64,
+    /// Clock ticks of the cpu when it was taken
+    pub ticks: u64,
+    /// The whole state, or a delta against the previous keyframe
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+pub struct Rewind {
+    /// Frames between two snapshots
+    pub interval: u64,
+    /// Snapshots kept at most, the oldest group is dropped first
+    pub capacity: usize,
+    pub snapshots: VecDeque<Snapshot>,
+    /// Frame the last snapshot was taken at
+    last_frame: Option<u64>,
+}
+
+impl Rewind {
+    pub fn new(interval: u64, capacity: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            snapshots: VecDeque::new(),
+            last_frame: None,
+        }
+    }
+
+    /// A snapshot must be taken at this frame
+    pub fn due(&self, frame: u64) -> bool {
+        self.last_frame != Some(frame) && frame % self.interval == 0
+    }
+
+    /// Index of the keyframe a snapshot is relative to
+    fn keyframe(&self, index: usize) -> usize {
+        (0..=index).rev().find(|&i| self.snapshots[i].keyframe).unwrap_or(0)
+    }
+
+    pub fn push(&mut self, frame: u64, ticks: u64, state: Vec<u8>) {
+        self.last_frame = Some(frame);
+        let (keyframe, data) = match self.snapshots.len() {
+            0 => (true, state),
+            len => {
+                let key = self.keyframe(len - 1);
+                let key_data = &self.snapshots[key].data;
+                let group = KEYFRAME_INTERVAL.min(self.capacity / 2).max(1);
+                if len - key >= group || key_data.len() != state.len() {
+                    (true, state)
+                } else {
+                    (false, encode_delta(key_data, &state))
+                }
+            }
+        };
+        self.snapshots.push_back(Snapshot { frame, ticks, keyframe, data });
+
+        if self.snapshots.len() > self.capacity {
+            // The deltas of the oldest keyframe go with it
+            self.snapshots.pop_front();
+            while self.snapshots.front().map_or(false, |s| !s.keyframe) {
+                self.snapshots.pop_front();
+            }
+        }
+    }
+
+    /// Full state of a snapshot
+    pub fn state(&self, index: usize) -> io::Result<Vec<u8>> {
+        let snapshot = &self.snapshots[index];
+        if snapshot.keyframe {
+            return Ok(snapshot.data.clone());
+        }
+        decode_delta(&self.snapshots[self.keyframe(index)].data, &snapshot.data)
+    }
+
+    /// Latest snapshot taken before a clock tick
+    pub fn before(&self, ticks: u64) -> Option<usize> {
+        self.snapshots.iter().rposition(|s| s.ticks < ticks)
+    }
+
+    /// Latest snapshot taken at or before a frame, the oldest one when
+    /// the history does not go that far
+    pub fn at_frame(&self, frame: u64) -> Option<usize> {
+        let oldest = if self.snapshots.is_empty() { None } else { Some(0) };
+        self.snapshots.iter().rposition(|s| s.frame <= frame).or(oldest)
+    }
+
+    /// Forget the snapshots newer than a clock tick, the emulation went
+    /// back before them
+    pub fn truncate_after(&mut self, ticks: u64, frame: u64) {
+        while self.snapshots.back().map_or(false, |s| s.ticks > ticks) {
+            self.snapshots.pop_back();
+        }
+        self.last_frame = Some(frame);
+    }
+
+    /// Bytes taken by the snapshots
+    pub fn memory_used(&self) -> usize {
+        self.snapshots.iter().map(|s| s.data.len()).sum()
+    }
+}
+
+#[test]
+fn delta_round_trip() {
+    let key = vec![0u8; 1000];
+    let mut state = key.clone();
+    state[3] = 0x42;
+    state[4] = 0x43;
+    state[900] = 0xFF;
+    let delta = encode_delta(&key, &state);
+    assert_eq!(delta, vec![3, 2, 0x42, 0x43, 0xFF, 6, 1, 0xFF, 99, 0]);
+    assert_eq!(decode_delta(&key, &delta).unwrap(), state);
+    assert!(decode_delta(&key[..10], &delta).is_err());
+}
+
+#[test]
+fn ring_buffer() {
+    let mut rewind = Rewind::new(2, 4);
+    assert!(rewind.due(0));
+    assert!(!rewind.due(1));
+    for frame in 0..5u8 {
+        rewind.push(frame as u64 * 2, frame as u64 * 100, vec![frame; 64]);
+    }
+    assert!(!rewind.due(8));
+
+    // Groups of two, frames 0 and 2 were dropped together
+    assert_eq!(rewind.snapshots.len(), 3);
+    assert!(rewind.snapshots[0].keyframe && !rewind.snapshots[1].keyframe && rewind.snapshots[2].keyframe);
+    assert_eq!(rewind.state(1).unwrap(), vec![3; 64]);
+    assert_eq!(rewind.memory_used(), 64 + (64 + 2) + 64);
+
+    assert_eq!(rewind.at_frame(7), Some(1));
+    assert_eq!(rewind.at_frame(0), Some(0));
+    assert_eq!(rewind.before(300), Some(0));
+    rewind.truncate_after(300, 6);
+    assert_eq!(rewind.snapshots.len(), 2);
+}
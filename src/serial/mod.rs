@@ -0,0 +1,216 @@
+/*
+ * Serial port, 0xFF01-0xFF02
+ *
+ * FF01  SB  byte shifted out, MSB first, while the received one comes in
+ * FF02  SC  bit 7 transfer running, bit 0 internal clock (8192 Hz)
+ */
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use state::{StateReader, StateWriter};
+
+/// Clock ticks per bit with the internal clock
+const BIT_TICKS: u32 = 512;
+
+/// Whatever is plugged into the link port
+pub trait SerialDevice {
+    /// Exchange a byte with the device, called when this side starts a
+    /// transfer with its internal clock
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    /// Called every cycle with the clock ticks since power on and SB.
+    /// Returns the byte of a transfer the device clocked into this side.
+    fn poll(&mut self, _ticks: u64, _byte: u8) -> Option<u8> {
+        None
+    }
+
+    /// Something is at the other end, it sees what the console sends
+    fn plugged(&self) -> bool {
+        true
+    }
+}
+
+/// Nothing plugged in, the line stays high
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+
+    fn plugged(&self) -> bool {
+        false
+    }
+}
+
+/// Collects the bytes sent as text, test ROMs print their results this way
+#[derive(Clone, Default)]
+pub struct TextSink {
+    text: Rc<RefCell<String>>,
+}
+
+impl TextSink {
+    pub fn new() -> TextSink {
+        TextSink::default()
+    }
+
+    /// Everything received so far, shared between the clones of a sink
+    pub fn text(&self) -> String {
+        self.text.borrow().clone()
+    }
+}
+
+impl SerialDevice for TextSink {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.text.borrow_mut().push(byte as char);
+        0xFF
+    }
+}
+
+pub struct Serial {
+    /// SB
+    pub data: u8,
+    /// SC, bits 7 and 0
+    pub control: u8,
+    pub device: Box<dyn SerialDevice>,
+    /// Byte coming from the device, shifted in during the transfer
+    incoming: u8,
+    /// Bits left to shift
+    bits: u8,
+    timer: u32,
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            device: Box::new(Disconnected),
+            incoming: 0xFF,
+            bits: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.data,
+            0xFF02 => 0x7E | self.control,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: u16, v: u8) {
+        match addr {
+            0xFF01 => self.data = v,
+            0xFF02 => {
+                self.control = v & 0x81;
+                // With the external clock the other side drives the transfer
+                if self.control == 0x81 {
+                    self.incoming = self.device.transfer(self.data);
+                    self.bits = 8;
+                    self.timer = BIT_TICKS;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The plugged device is not part of the state
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.data);
+        w.write_u8(self.control);
+        w.write_u8(self.incoming);
+        w.write_u8(self.bits);
+        w.write_u32(self.timer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.data = r.read_u8()?;
+        self.control = r.read_u8()?;
+        self.incoming = r.read_u8()?;
+        self.bits = r.read_u8()?;
+        self.timer = r.read_u32()?;
+        Ok(())
+    }
+
+    /// Advance by some clock ticks, `now` being the clock ticks since
+    /// power on. Returns true when the SERIAL interrupt is requested.
+    pub fn step(&mut self, ticks: u32, now: u64) -> bool {
+        // The device drives the shifts of a transfer on the external clock,
+        // the bits only move while one is requested
+        if let Some(incoming) = self.device.poll(now, self.data) {
+            if self.bits == 0 && self.control == 0x80 {
+                self.incoming = incoming;
+                self.bits = 8;
+                self.timer = BIT_TICKS;
+            }
+        }
+
+        if self.bits == 0 {
+            return false;
+        }
+        if self.timer > ticks {
+            self.timer -= ticks;
+            return false;
+        }
+        self.timer += BIT_TICKS - ticks;
+
+        self.data = self.data << 1 | self.incoming >> 7;
+        self.incoming <<= 1;
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.control &= 0x7F;
+            return true;
+        }
+        false
+    }
+}
+
+#[test]
+fn transfer() {
+    let sink = TextSink::new();
+    let mut serial = Serial::new();
+    serial.device = Box::new(sink.clone());
+
+    serial.write_byte(0xFF01, b'P');
+    serial.write_byte(0xFF02, 0x81);
+    assert_eq!(sink.text(), "P");
+    assert_eq!(serial.read_byte(0xFF02), 0xFF);
+
+    // 4 bits in, half of the 0xFF received
+    for _ in 0..4 * BIT_TICKS / 4 {
+        assert!(!serial.step(4, 0));
+    }
+    assert_eq!(serial.data, b'P' << 4 | 0x0F);
+
+    for _ in 0..4 * BIT_TICKS / 4 - 1 {
+        assert!(!serial.step(4, 0));
+    }
+    assert!(serial.step(4, 0));
+    assert_eq!(serial.data, 0xFF);
+    assert_eq!(serial.read_byte(0xFF02), 0x7F);
+}
+
+#[test]
+fn external_clock() {
+    let sink = TextSink::new();
+    let mut serial = Serial::new();
+    serial.device = Box::new(sink.clone());
+
+    // Waits for a clock that never comes
+    serial.write_byte(0xFF01, b'P');
+    serial.write_byte(0xFF02, 0x80);
+    for _ in 0..10000 {
+        assert!(!serial.step(4, 0));
+    }
+    assert_eq!(sink.text(), "");
+    assert_eq!(serial.read_byte(0xFF02), 0xFE);
+}
@@ -0,0 +1,204 @@
+/*
+ * Little-endian binary encoding used to snapshot the emulated components
+ */
+
+use std::io;
+
+/// Tag at the start of every save state
+const MAGIC: &[u8; 4] = b"GBST";
+
+/// Layout written by `save_state`. Bump it when a component changes what
+/// it writes, its `load_state` then reads the older layouts according to
+/// `StateReader::version`.
+pub const STATE_VERSION: u16 = 1;
+
+/// Identifies the format and the game a save state belongs to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateHeader {
+    pub version: u16,
+    /// Sum of the rom bytes, as the global checksum of the header
+    pub rom_checksum: u16,
+    pub rom_size: u32,
+}
+
+impl StateHeader {
+    pub fn write(&self, w: &mut StateWriter) {
+        w.buf.extend_from_slice(MAGIC);
+        w.write_u16(self.version);
+        w.write_u16(self.rom_checksum);
+        w.write_u32(self.rom_size);
+    }
+
+    /// Read a header, refusing states of an unknown version
+    pub fn read(r: &mut StateReader) -> io::Result<StateHeader> {
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a save state"));
+        }
+        let header = StateHeader {
+            version: r.read_u16()?,
+            rom_checksum: r.read_u16()?,
+            rom_size: r.read_u32()?,
+        };
+        if header.version == 0 || header.version > STATE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported save state version {}", header.version),
+            ));
+        }
+        Ok(header)
+    }
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn write_bool(&mut self, v: bool) {
+        self.buf.push(v as u8);
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, v: f32) {
+        self.write_u32(v.to_bits());
+    }
+
+    /// Write a length-prefixed block of bytes
+    pub fn write_bytes(&mut self, v: &[u8]) {
+        self.write_u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Layout of the data, older versions are migrated while reading
+    pub version: u16,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0, version: STATE_VERSION }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated state"));
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        let mut b = [0u8; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn read_f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    /// Read a length-prefixed block of bytes
+    pub fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Read a length-prefixed block into a buffer that must have the same size
+    pub fn read_into(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let data = self.read_bytes()?;
+        if data.len() != buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected a block of {} bytes, found {}", buf.len(), data.len()),
+            ));
+        }
+        buf.copy_from_slice(&data);
+        Ok(())
+    }
+}
+
+#[test]
+fn round_trip() {
+    let mut w = StateWriter::new();
+    w.write_u8(0x42);
+    w.write_bool(true);
+    w.write_u16(0x4243);
+    w.write_u32(0xDEAD_BEEF);
+    w.write_u64(0x0123_4567_89AB_CDEF);
+    w.write_bytes(&[1, 2, 3]);
+    let data = w.into_inner();
+
+    let mut r = StateReader::new(&data);
+    assert_eq!(r.read_u8().unwrap(), 0x42);
+    assert!(r.read_bool().unwrap());
+    assert_eq!(r.read_u16().unwrap(), 0x4243);
+    assert_eq!(r.read_u32().unwrap(), 0xDEAD_BEEF);
+    assert_eq!(r.read_u64().unwrap(), 0x0123_4567_89AB_CDEF);
+    assert_eq!(r.read_bytes().unwrap(), vec![1, 2, 3]);
+    assert!(r.read_u8().is_err());
+}
+
+#[test]
+fn header() {
+    let header = StateHeader { version: STATE_VERSION, rom_checksum: 0x1234, rom_size: 0x8000 };
+    let mut w = StateWriter::new();
+    header.write(&mut w);
+    let mut data = w.into_inner();
+    assert_eq!(StateHeader::read(&mut StateReader::new(&data)).unwrap(), header);
+    data[0] = b'X';
+    assert!(StateHeader::read(&mut StateReader::new(&data)).is_err());
+
+    // A state from a newer emulator
+    let mut w = StateWriter::new();
+    StateHeader { version: STATE_VERSION + 1, ..header }.write(&mut w);
+    let data = w.into_inner();
+    assert!(StateHeader::read(&mut StateReader::new(&data)).is_err());
+}
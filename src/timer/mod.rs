@@ -0,0 +1,193 @@
+/*
+ * Timer and divider, 0xFF04-0xFF07
+ *
+ * FF04  DIV   upper byte of the 16 bits internal divider, reset on write
+ * FF05  TIMA  counter, incremented on a falling edge of a divider bit
+ * FF06  TMA   value TIMA is reloaded with after an overflow
+ * FF07  TAC   bit 2 enable, bits 0-1 select the divider bit
+ */
+
+use std::io;
+use state::{StateReader, StateWriter};
+
+#[derive(Default)]
+pub struct Timer {
+    /// Internal divider, incremented every clock tick
+    pub divider: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    /// TIMA overflowed during the last cycle, it reads 0 until reloaded
+    overflow: bool,
+    /// TIMA was reloaded from TMA during the last cycle
+    reloading: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+
+    /// Divider bit watched by TIMA for the current frequency
+    fn bit(&self) -> u16 {
+        match self.tac & 0x03 {
+            0 => 1 << 9,
+            1 => 1 << 3,
+            2 => 1 << 5,
+            _ => 1 << 7,
+        }
+    }
+
+    /// The selected divider bit ANDed with the enable bit
+    fn signal(&self) -> bool {
+        self.tac & 0x04 != 0 && self.divider & self.bit() != 0
+    }
+
+    fn increment(&mut self) {
+        self.tima = self.tima.wrapping_add(1);
+        if self.tima == 0 {
+            self.overflow = true;
+        }
+    }
+
+    /// Advance by one machine cycle, returns true when the TIMER
+    /// interrupt is requested
+    pub fn step(&mut self) -> bool {
+        let mut interrupt = false;
+        self.reloading = false;
+
+        // The reload and the interrupt happen one cycle after the overflow
+        if self.overflow {
+            self.overflow = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupt = true;
+        }
+
+        let before = self.signal();
+        self.divider = self.divider.wrapping_add(4);
+        if before && !self.signal() {
+            self.increment();
+        }
+        interrupt
+    }
+
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.divider >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.divider);
+        w.write_u8(self.tima);
+        w.write_u8(self.tma);
+        w.write_u8(self.tac);
+        w.write_bool(self.overflow);
+        w.write_bool(self.reloading);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.divider = r.read_u16()?;
+        self.tima = r.read_u8()?;
+        self.tma = r.read_u8()?;
+        self.tac = r.read_u8()?;
+        self.overflow = r.read_bool()?;
+        self.reloading = r.read_bool()?;
+        Ok(())
+    }
+
+    /// Resetting the divider or changing TAC may produce a falling edge
+    /// and increment TIMA
+    pub fn write_byte(&mut self, addr: u16, v: u8) {
+        match addr {
+            0xFF04 => {
+                let before = self.signal();
+                self.divider = 0;
+                if before {
+                    self.increment();
+                }
+            }
+            // Writing during the overflow cycle cancels the reload, the
+            // write is lost when TMA is being copied
+            0xFF05 if !self.reloading => {
+                self.tima = v;
+                self.overflow = false;
+            }
+            0xFF06 => {
+                self.tma = v;
+                if self.reloading {
+                    self.tima = v;
+                }
+            }
+            0xFF07 => {
+                let before = self.signal();
+                self.tac = v & 0x07;
+                if before && !self.signal() {
+                    self.increment();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn overflow_reload() {
+    let mut timer = Timer::new();
+    timer.write_byte(0xFF07, 0x05);
+    timer.write_byte(0xFF06, 0x42);
+    timer.write_byte(0xFF05, 0xFF);
+
+    // 16 clock ticks per increment
+    for _ in 0..3 {
+        assert!(!timer.step());
+    }
+    assert!(!timer.step());
+    assert_eq!(timer.read_byte(0xFF05), 0x00);
+    assert!(timer.step());
+    assert_eq!(timer.read_byte(0xFF05), 0x42);
+}
+
+#[test]
+fn overflow_cancelled() {
+    let mut timer = Timer::new();
+    timer.write_byte(0xFF07, 0x05);
+    timer.write_byte(0xFF05, 0xFF);
+    for _ in 0..4 {
+        timer.step();
+    }
+
+    timer.write_byte(0xFF05, 0x10);
+    assert!(!timer.step());
+    assert_eq!(timer.read_byte(0xFF05), 0x10);
+}
+
+#[test]
+fn divider_glitches() {
+    let mut timer = Timer::new();
+    timer.write_byte(0xFF07, 0x05);
+    timer.step();
+    timer.step();
+    assert_eq!(timer.divider, 8);
+
+    // Bit 3 goes low when DIV is reset
+    timer.write_byte(0xFF04, 0x42);
+    assert_eq!((timer.divider, timer.tima), (0, 1));
+
+    // Same when the timer is disabled while the bit is high
+    timer.step();
+    timer.step();
+    timer.write_byte(0xFF07, 0x01);
+    assert_eq!(timer.tima, 2);
+
+    for _ in 0..64 {
+        timer.step();
+    }
+    assert_eq!(timer.read_byte(0xFF04), 0x01);
+    assert_eq!(timer.read_byte(0xFF07), 0xF9);
+}
@@ -0,0 +1,65 @@
+/*
+ * Machine shared by the integration tests, on a rom the programs can be
+ * written into
+ */
+
+// Each test file uses its own part of it
+#![allow(dead_code)]
+
+use std::io;
+use gameboy_emu::cartridge::{Cartridge, Mapper};
+use gameboy_emu::cpu::Cpu;
+use gameboy_emu::gameboy::GameBoy;
+use gameboy_emu::memory::Memory;
+use gameboy_emu::state::{StateReader, StateWriter};
+
+/// Writable 32KiB rom, lets the tests put programs anywhere below 0x8000
+struct FlatRom(Vec<u8>);
+
+impl Mapper for FlatRom {
+    fn read_rom(&self, addr: u16) -> u8 {
+        self.0[addr as usize]
+    }
+
+    fn write_register(&mut self, addr: u16, v: u8) {
+        self.0[addr as usize] = v;
+    }
+
+    fn read_ram(&self, _addr: u16) -> u8 {
+        0xFF
+    }
+
+    fn write_ram(&mut self, _addr: u16, _v: u8) -> bool {
+        false
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.0);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.0)
+    }
+
+    fn battery_dump(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn battery_load(&mut self, _data: &[u8]) {}
+}
+
+/// Machine on an empty rom
+pub fn init_env() -> GameBoy {
+    machine_with(vec![0; 0x8000])
+}
+
+/// Machine on a 32KiB rom image
+pub fn machine_with(rom: Vec<u8>) -> GameBoy {
+    let cartridge = Cartridge::with_mapper(&rom, Box::new(FlatRom(rom.clone())));
+    GameBoy::new(
+        Box::new(Cpu::new()),
+        Vec::new(),
+        Box::new(cartridge),
+        Box::new(Memory::new()),
+    )
+}
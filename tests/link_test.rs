@@ -0,0 +1,51 @@
+extern crate gameboy_emu;
+
+mod common;
+
+use common::init_env;
+use gameboy_emu::cpu::{IterFlag, Register16};
+use gameboy_emu::gameboy::GameBoy;
+use gameboy_emu::link::{CableEnd, step_linked};
+
+/// Two machines on a cable after one byte went each way, the master
+/// sending 0x12 and the slave 0x34
+fn linked_transfer() -> (GameBoy, GameBoy) {
+    let mut master = init_env();
+    let mut slave = init_env();
+    let (a, b) = CableEnd::pair();
+    master.connect_serial(Box::new(a));
+    slave.connect_serial(Box::new(b));
+
+    // The slave waits on the external clock
+    slave.write_byte(0xFF01, 0x34);
+    slave.write_byte(0xFF02, 0x80);
+    slave.write_byte(0x1000, 0x18);
+    slave.write_byte(0x1001, 0xFE);
+    slave.cpu.set_16(Register16::PC, 0x1000);
+
+    // LD A,0x12; LDH (01),A; LD A,0x81; LDH (02),A; JR -2
+    let program = [0x3E, 0x12, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE];
+    for (i, &b) in program.iter().enumerate() {
+        master.write_byte(0x1000 + i as u16, b);
+    }
+    master.cpu.set_16(Register16::PC, 0x1000);
+
+    while master.cpu.ticks() < 8 * 512 + 200 {
+        step_linked(&mut master, &mut slave);
+    }
+    (master, slave)
+}
+
+#[test]
+fn test_link_exchange() {
+    let (master, slave) = linked_transfer();
+    assert_eq!(master.read_byte(0xFF01), 0x34);
+    assert_eq!(slave.read_byte(0xFF01), 0x12);
+}
+
+#[test]
+fn test_link_interrupts() {
+    let (master, slave) = linked_transfer();
+    assert!(master.cpu.get_iter_flag(IterFlag::SERIAL));
+    assert!(slave.cpu.get_iter_flag(IterFlag::SERIAL));
+}
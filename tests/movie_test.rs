@@ -0,0 +1,219 @@
+extern crate gameboy_emu;
+
+use std::env;
+use std::fs;
+use gameboy_emu::apu::CLOCK_RATE;
+use gameboy_emu::cartridge::Cartridge;
+use gameboy_emu::cpu::Cpu;
+use gameboy_emu::gameboy::GameBoy;
+use gameboy_emu::joypad::{Button, ButtonState};
+use gameboy_emu::memory::Memory;
+use gameboy_emu::movie::Movie;
+
+/// MBC3 with RTC, reads the seconds and the joypad on and on and mixes
+/// them into the work RAM garbage
+fn movie_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0x10;
+    rom[0x149] = 0x02;
+    let program = [
+        0x21, 0x00, 0xC8, // LD HL,0xC800
+        0x3E, 0x0A, 0xEA, 0x00, 0x00, // enable the RAM and the RTC
+        0x3E, 0x08, 0xEA, 0x00, 0x40, // select the seconds
+        0x3E, 0x00, 0xE0, 0x00, // select both button groups
+        0xAF, 0xEA, 0x00, 0x60, 0x3C, 0xEA, 0x00, 0x60, // latch the clock
+        0xFA, 0x00, 0xA0, 0x47, // LD A,(0xA000); LD B,A
+        0xF0, 0x00, 0xA8, 0x47, // LDH A,(0x00); XOR B; LD B,A
+        0x7E, 0xA8, 0x22, // LD A,(HL); XOR B; LD (HL+),A
+        0xCB, 0xA4, 0x18, 0xE7, // RES 4,H; JR to the latch
+    ];
+    rom[..program.len()].copy_from_slice(&program);
+    rom
+}
+
+fn new_machine(rom: &[u8]) -> GameBoy {
+    GameBoy::new(
+        Box::new(Cpu::new()),
+        Vec::new(),
+        Box::new(Cartridge::from_bytes(rom.to_vec()).unwrap()),
+        Box::new(Memory::new()),
+    )
+}
+
+/// Record 240 frames from power on, the buttons changing every 4
+fn record_power_on(gb: &mut GameBoy) -> Movie {
+    gb.start_movie(true, 1_700_000_000, 7).unwrap();
+    for frame in 0..240 {
+        gb.set_buttons(ButtonState::from_nibbles(frame / 4 % 16, frame / 64 % 16));
+        gb.run_frame();
+    }
+    gb.stop_movie().unwrap()
+}
+
+#[test]
+fn test_movie_record() {
+    let mut recorder = new_machine(&movie_rom());
+    let movie = record_power_on(&mut recorder);
+    // A frame per FRAME_TICKS, the last one may have just started
+    assert!(movie.inputs.len() == 240 || movie.inputs.len() == 241);
+    assert_eq!(movie.sync.len(), (movie.inputs.len() - 1) / 60);
+    assert_ne!(movie.inputs[100], ButtonState::default());
+    assert_eq!(recorder.read_byte(0xA000), 4);
+    assert_eq!(Movie::decode(&movie.encode()).unwrap(), movie);
+}
+
+#[test]
+fn test_movie_playback() {
+    let rom = movie_rom();
+    let mut recorder = new_machine(&rom);
+    let movie = record_power_on(&mut recorder);
+
+    // The buttons of the player are ignored
+    let mut player = new_machine(&rom);
+    player.play_movie(movie.clone()).unwrap();
+    for _ in 0..240 {
+        player.press(Button::A);
+        player.run_frame();
+    }
+    assert!(player.movie.as_ref().unwrap().finished());
+    assert_eq!(player.movie.as_ref().unwrap().desync, None);
+    assert!(player.save_state() == recorder.save_state());
+    assert!(player.play_movie(movie).is_err());
+}
+
+#[test]
+fn test_movie_save_file() {
+    // The playback does not end up in the player's save
+    let rom = movie_rom();
+    let movie = record_power_on(&mut new_machine(&rom));
+    let mut player = new_machine(&rom);
+    let path = env::temp_dir().join("gameboy-movie-save.sav");
+    player.cartridge.set_save_path(Some(path.clone()));
+    player.play_movie(movie).unwrap();
+    assert_eq!(player.cartridge.save_path(), None);
+    player.run_frame();
+    player.stop_movie();
+    assert_eq!(player.cartridge.save_path(), Some(path.as_path()));
+    player.cartridge.set_save_path(None);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_movie_no_time_travel() {
+    // Going back would leave the recorded frames out of step
+    let mut gb = new_machine(&movie_rom());
+    gb.enable_rewind(1, 10);
+    gb.start_movie(true, 1_700_000_000, 7).unwrap();
+    for _ in 0..3 {
+        gb.run_frame();
+    }
+    let state = gb.save_state();
+    assert!(gb.load_state(&state).is_err());
+    assert!(gb.rewind(1).is_err());
+    assert!(gb.step_back().is_err());
+    gb.stop_movie();
+    assert!(gb.load_state(&state).is_ok());
+}
+
+#[test]
+fn test_movie_desync() {
+    // Other buttons at frame 100 show up at the next sync point
+    let rom = movie_rom();
+    let mut movie = record_power_on(&mut new_machine(&rom));
+    movie.inputs[100] = ButtonState::default();
+    let mut player = new_machine(&rom);
+    player.play_movie(movie).unwrap();
+    for _ in 0..240 {
+        player.run_frame();
+    }
+    assert_eq!(player.movie.as_ref().unwrap().desync, Some(120));
+}
+
+#[test]
+fn test_movie_from_state() {
+    // Played on a machine that ran something else, one instruction at a
+    // time as in the debugger
+    let rom = movie_rom();
+    let mut recorder = new_machine(&rom);
+    for _ in 0..10 {
+        recorder.run_frame();
+    }
+    recorder.start_movie(false, 1_800_000_000, 0).unwrap();
+    for i in 0..400u32 {
+        recorder.set_buttons(ButtonState::from_nibbles(0, (i / 8 % 16) as u8));
+        for _ in 0..3000 {
+            recorder.step();
+        }
+    }
+    let movie = recorder.stop_movie().unwrap();
+    assert!(movie.inputs.len() > 100);
+
+    let mut player = new_machine(&rom);
+    for _ in 0..30 {
+        player.run_frame();
+    }
+    player.play_movie(movie).unwrap();
+    for _ in 0..400 * 3000 {
+        player.step();
+    }
+    assert_eq!(player.movie.as_ref().unwrap().desync, None);
+    assert!(player.save_state() == recorder.save_state());
+}
+
+#[test]
+fn test_movie_stop() {
+    // LD A,0; LDH (00),A; STOP; INC A; JR -3, a button wakes the cpu up
+    let mut rom = vec![0; 0x8000];
+    rom[..9].copy_from_slice(&[0x3E, 0x00, 0xE0, 0x00, 0x10, 0x00, 0x3C, 0x18, 0xFD]);
+    let mut recorder = new_machine(&rom);
+    recorder.start_movie(true, 0, 0).unwrap();
+    while !recorder.stopped {
+        recorder.step();
+    }
+    for _ in 0..10 {
+        recorder.step();
+    }
+    recorder.press(Button::Start);
+    recorder.step();
+    assert!(!recorder.stopped);
+    for _ in 0..100 {
+        recorder.step();
+    }
+    let state = recorder.save_state();
+    let movie = recorder.stop_movie().unwrap();
+
+    let mut player = new_machine(&rom);
+    player.play_movie(movie).unwrap();
+    for _ in 0..4 + 100 {
+        player.step();
+    }
+    assert!(!player.stopped);
+    assert!(player.save_state() == state);
+}
+
+#[test]
+fn test_rtc_epoch() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0x10;
+    rom[0x149] = 0x02;
+    // JR -2
+    rom[0] = 0x18;
+    rom[1] = 0xFE;
+    let mut gb = new_machine(&rom);
+    let seconds = |gb: &mut GameBoy| {
+        gb.write_byte(0x0000, 0x0A);
+        gb.write_byte(0x4000, 0x08);
+        gb.write_byte(0x6000, 0x00);
+        gb.write_byte(0x6000, 0x01);
+        gb.read_byte(0xA000)
+    };
+    gb.set_rtc_epoch(Some(1_000_000));
+    let start = seconds(&mut gb);
+
+    // Two seconds of cpu ticks are only one in double speed
+    gb.cpu.toggle_speed();
+    while gb.cpu.ticks() < 2 * CLOCK_RATE as u64 {
+        gb.step();
+    }
+    assert_eq!(seconds(&mut gb), (start + 1) % 60);
+}
@@ -1,45 +1,39 @@
 extern crate gameboy_emu;
 
-use gameboy_emu::cartridge::Cartridge;
-use gameboy_emu::cpu::{Cpu, Flag, Register16, Register8};
+use gameboy_emu::cpu::{Flag, IterFlag, Register16, Register8};
 use gameboy_emu::cpu::opcodes::decode;
-use gameboy_emu::gameboy::GameBoy;
-use gameboy_emu::memory::Memory;
+use gameboy_emu::joypad::Button;
 use gameboy_emu::utils::get_opcode_from_small;
 
-fn init_env() -> GameBoy {
-    let rom = Cartridge::empty(0xFFFF).unwrap();
-    let cpu = Cpu::new();
-    let mem = Memory::new(rom);
-    let mut gb = GameBoy::new(cpu, mem);
-    gb
-}
+mod common;
+
+use common::init_env;
 
 #[test]
 fn test_jr_d() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x18);
-    gb.mem.write_byte(0x1001, 0x10);
+    gb.write_byte(0x1000, 0x18);
+    gb.write_byte(0x1001, 0x10);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     decode(&mut gb);
 
-    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1010);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1012);
 
-    gb.mem.write_byte(0x1001, 0xFC);
+    gb.write_byte(0x1001, 0xFC);
     gb.cpu.set_16(Register16::PC, 0x1000);
     decode(&mut gb);
 
-    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1000 - 4);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1000 - 2);
 }
 
 #[test]
 fn test_jr_cc_d() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, get_opcode_from_small(0, 4, 0, None, None));
-    gb.mem.write_byte(0x1001, 0xFC);
+    gb.write_byte(0x1000, get_opcode_from_small(0, 4, 0, None, None));
+    gb.write_byte(0x1001, 0xFC);
 
     gb.cpu.set_flag(Flag::Z, true);
     gb.cpu.set_16(Register16::PC, 0x1000);
@@ -58,9 +52,9 @@ fn test_jr_cc_d() {
 fn test_ld() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x01);
-    gb.mem.write_byte(0x1001, 0x10);
-    gb.mem.write_byte(0x1002, 0x00);
+    gb.write_byte(0x1000, 0x01);
+    gb.write_byte(0x1001, 0x10);
+    gb.write_byte(0x1002, 0x00);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     decode(&mut gb);
@@ -68,27 +62,27 @@ fn test_ld() {
     assert_eq!(gb.cpu.get_16(Register16::BC), 0x0010);
 
     //LD (BC), A
-    gb.mem.write_byte(0x1000, 0x02);
+    gb.write_byte(0x1000, 0x02);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::BC, 0x1010);
     gb.cpu.set_8(Register8::A, 0x42);
     decode(&mut gb);
 
-    assert_eq!(gb.mem.read_byte(0x1010), 0x42);
+    assert_eq!(gb.read_byte(0x1010), 0x42);
 
     //LD (DE), A
-    gb.mem.write_byte(0x1000, 0x12);
+    gb.write_byte(0x1000, 0x12);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::DE, 0x1011);
     gb.cpu.set_8(Register8::A, 0x42);
     decode(&mut gb);
 
-    assert_eq!(gb.mem.read_byte(0x1011), 0x42);
+    assert_eq!(gb.read_byte(0x1011), 0x42);
 
     //LD HL, nn
-    gb.mem.write_byte(0x1000, 0x21);
-    gb.mem.write_byte(0x1001, 0x43);
-    gb.mem.write_byte(0x1002, 0x42);
+    gb.write_byte(0x1000, 0x21);
+    gb.write_byte(0x1001, 0x43);
+    gb.write_byte(0x1002, 0x42);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::HL, 0);
 
@@ -97,40 +91,41 @@ fn test_ld() {
     assert_eq!(gb.cpu.get_16(Register16::HL), 0x4243);
 
     // LD (HL+), A
-    gb.mem.write_byte(0x1000, 0x22);
+    gb.write_byte(0x1000, 0x22);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::HL, 0x4242);
     gb.cpu.set_8(Register8::A, 0x42);
 
     decode(&mut gb);
 
-    assert_eq!(gb.mem.read_word(0x4243), 0x42);
+    assert_eq!(gb.read_byte(0x4242), 0x42);
     assert_eq!(gb.cpu.get_16(Register16::HL), 0x4243);
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
 
     // LD (nn), SP
-    gb.mem.write_byte(0x1000, 0x08);
-    gb.mem.write_byte(0x1001, 0x00);
-    gb.mem.write_byte(0x1002, 0x20);
+    gb.write_byte(0x1000, 0x08);
+    gb.write_byte(0x1001, 0x00);
+    gb.write_byte(0x1002, 0x20);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::SP, 0x4242);
     decode(&mut gb);
 
-    assert_eq!(gb.mem.read_word(0x2000), 0x4242);
+    assert_eq!(gb.read_word(0x2000), 0x4242);
 
     // LD (nn), A
-    gb.mem.write_byte(0x1000, 0x32);
-    gb.mem.write_byte(0x1001, 0x00);
-    gb.mem.write_byte(0x1002, 0x20);
+    gb.write_byte(0x1000, 0xEA);
+    gb.write_byte(0x1001, 0x00);
+    gb.write_byte(0x1002, 0x20);
     gb.cpu.set_16(Register16::PC, 0x1000);
-    gb.cpu.set_8(Register8::A, 0x42);
+    gb.cpu.set_8(Register8::A, 0x24);
     decode(&mut gb);
 
-    assert_eq!(gb.mem.read_byte(0x2000), 0x42);
+    assert_eq!(gb.read_byte(0x2000), 0x24);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1003);
 
     // LD A, (BC)
-    gb.mem.write_byte(0x1000, 0x0A);
-    gb.mem.write_byte(0x2020, 0x42);
+    gb.write_byte(0x1000, 0x0A);
+    gb.write_byte(0x2020, 0x42);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::BC, 0x2020);
     gb.cpu.set_8(Register8::A, 0x0);
@@ -139,8 +134,8 @@ fn test_ld() {
     assert_eq!(gb.cpu.get_8(Register8::A), 0x42);
 
     // LD A, (DE)
-    gb.mem.write_byte(0x1000, 0x1A);
-    gb.mem.write_byte(0x2020, 0x42);
+    gb.write_byte(0x1000, 0x1A);
+    gb.write_byte(0x2020, 0x42);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::DE, 0x2020);
     gb.cpu.set_8(Register8::A, 0x0);
@@ -148,31 +143,41 @@ fn test_ld() {
 
     assert_eq!(gb.cpu.get_8(Register8::A), 0x42);
 
-    // LD HL, (nn)
-    gb.mem.write_byte(0x1000, 0x2A);
-    gb.mem.write_byte(0x1001, 0x20);
-    gb.mem.write_byte(0x1002, 0x20);
+    // LD A, (HL+)
+    gb.write_byte(0x1000, 0x2A);
+    gb.write_byte(0x2020, 0x42);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::HL, 0x2020);
+    gb.cpu.set_8(Register8::A, 0);
+    decode(&mut gb);
 
-    gb.mem.write_byte(0x2020, 0x42);
-    gb.mem.write_byte(0x2021, 0x41);
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x42);
+    assert_eq!(gb.cpu.get_16(Register16::HL), 0x2021);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
+
+    // LD A, (HL-)
+    gb.write_byte(0x1000, 0x3A);
+    gb.write_byte(0x2021, 0x41);
     gb.cpu.set_16(Register16::PC, 0x1000);
-    gb.cpu.set_16(Register16::HL, 0);
+    gb.cpu.set_8(Register8::A, 0);
     decode(&mut gb);
 
-    assert_eq!(gb.cpu.get_16(Register16::HL), 0x4142);
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x41);
+    assert_eq!(gb.cpu.get_16(Register16::HL), 0x2020);
 
     // LD A, (nn)
-    gb.mem.write_byte(0x1000, 0x3A);
-    gb.mem.write_byte(0x1001, 0x20);
-    gb.mem.write_byte(0x1002, 0x20);
-
-    gb.mem.write_byte(0x2020, 0x42);
+    gb.write_byte(0x1000, 0xFA);
+    gb.write_byte(0x1001, 0x21);
+    gb.write_byte(0x1002, 0x20);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0);
     decode(&mut gb);
 
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x41);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1003);
+
     // LD SP, HL
-    gb.mem.write_byte(0x1000, 0xF9);
+    gb.write_byte(0x1000, 0xF9);
     gb.cpu.set_16(Register16::SP, 0x0000);
     gb.cpu.set_16(Register16::HL, 0x4243);
     gb.cpu.set_16(Register16::PC, 0x1000);
@@ -186,7 +191,7 @@ fn test_ld() {
 fn test_inc_16() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x03);
+    gb.write_byte(0x1000, 0x03);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::BC, 0x4242);
@@ -200,7 +205,7 @@ fn test_inc_16() {
 fn test_dec_16() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x0B);
+    gb.write_byte(0x1000, 0x0B);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::BC, 0x4242);
@@ -214,7 +219,7 @@ fn test_dec_16() {
 fn test_inc_8() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x04);
+    gb.write_byte(0x1000, 0x04);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::B, 0x42);
@@ -228,7 +233,7 @@ fn test_inc_8() {
 fn test_dec_8() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x05);
+    gb.write_byte(0x1000, 0x05);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::B, 0x42);
@@ -242,8 +247,8 @@ fn test_dec_8() {
 fn test_ld_8() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x06);
-    gb.mem.write_byte(0x1001, 0x42);
+    gb.write_byte(0x1000, 0x06);
+    gb.write_byte(0x1001, 0x42);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::B, 0x0);
@@ -252,7 +257,7 @@ fn test_ld_8() {
 
     assert_eq!(gb.cpu.get_8(Register8::B), 0x42);
 
-    gb.mem.write_byte(0x1000, 0x41);
+    gb.write_byte(0x1000, 0x41);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::B, 0x0);
@@ -263,7 +268,7 @@ fn test_ld_8() {
     assert_eq!(gb.cpu.get_8(Register8::B), 0x42);
 
     //LD (HL-), A
-    gb.mem.write_byte(0x1000, 0x32);
+    gb.write_byte(0x1000, 0x32);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0x42);
@@ -271,7 +276,7 @@ fn test_ld_8() {
 
     decode(&mut gb);
 
-    assert_eq!(gb.mem.read_byte(0x4241), 0x42);
+    assert_eq!(gb.read_byte(0x4242), 0x42);
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
     assert_eq!(gb.cpu.get_16(Register16::HL), 0x4241);
 }
@@ -280,7 +285,7 @@ fn test_ld_8() {
 fn test_rlca() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x07);
+    gb.write_byte(0x1000, 0x07);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0x42);
@@ -300,7 +305,8 @@ fn test_rlca() {
 fn test_ret() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0xC9);
+    gb.write_byte(0x1000, 0xC9);
+    gb.write_word(0x4242, 0x4242);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::SP, 0x4242);
@@ -309,7 +315,7 @@ fn test_ret() {
 
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x4242);
 
-    gb.mem.write_byte(0x1000, 0xC8);
+    gb.write_byte(0x1000, 0xC8);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::SP, 0x4242);
@@ -318,7 +324,7 @@ fn test_ret() {
 
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
 
-    gb.mem.write_byte(0x1000, 0xC8);
+    gb.write_byte(0x1000, 0xC8);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::SP, 0x4242);
@@ -334,9 +340,9 @@ fn test_jp() {
     let mut gb = init_env();
 
     // jp_nn
-    gb.mem.write_byte(0x1000, 0xC3);
-    gb.mem.write_byte(0x1001, 0x43);
-    gb.mem.write_byte(0x1002, 0x42);
+    gb.write_byte(0x1000, 0xC3);
+    gb.write_byte(0x1001, 0x43);
+    gb.write_byte(0x1002, 0x42);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
 
@@ -345,7 +351,7 @@ fn test_jp() {
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x4243);
 
     // jp_HL
-    gb.mem.write_byte(0x1000, 0xE9);
+    gb.write_byte(0x1000, 0xE9);
     gb.cpu.set_16(Register16::HL, 0x4342);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
@@ -355,9 +361,9 @@ fn test_jp() {
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x4342);
 
     // JP cc[y], nn
-    gb.mem.write_byte(0x1000, 0xC2);
-    gb.mem.write_byte(0x1001, 0x42);
-    gb.mem.write_byte(0x1002, 0x42);
+    gb.write_byte(0x1000, 0xC2);
+    gb.write_byte(0x1001, 0x42);
+    gb.write_byte(0x1002, 0x42);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_flag(Flag::Z, true);
@@ -379,7 +385,7 @@ fn test_jp() {
 fn test_alu() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0x80);
+    gb.write_byte(0x1000, 0x80);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0x10);
     gb.cpu.set_8(Register8::B, 0x10);
@@ -390,7 +396,7 @@ fn test_alu() {
     assert_eq!(gb.cpu.get_8(Register8::B), 0x10);
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
 
-    gb.mem.write_byte(0x1000, 0x80);
+    gb.write_byte(0x1000, 0x80);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0xFF);
     gb.cpu.set_8(Register8::B, 0x10);
@@ -403,7 +409,7 @@ fn test_alu() {
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
     assert_eq!(gb.cpu.get_flag(Flag::C), true);
 
-    gb.mem.write_byte(0x1000, 0x88);
+    gb.write_byte(0x1000, 0x88);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0x10);
     gb.cpu.set_8(Register8::B, 0x10);
@@ -415,7 +421,7 @@ fn test_alu() {
     assert_eq!(gb.cpu.get_8(Register8::B), 0x10);
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
 
-    gb.mem.write_byte(0x1000, 0x88);
+    gb.write_byte(0x1000, 0x88);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0x10);
     gb.cpu.set_8(Register8::B, 0x10);
@@ -427,7 +433,7 @@ fn test_alu() {
     assert_eq!(gb.cpu.get_8(Register8::B), 0x10);
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
 
-    gb.mem.write_byte(0x1000, 0x90);
+    gb.write_byte(0x1000, 0x90);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0x10);
     gb.cpu.set_8(Register8::B, 0x10);
@@ -439,7 +445,7 @@ fn test_alu() {
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
 
     //SUB B+
-    gb.mem.write_byte(0x1000, 0x90);
+    gb.write_byte(0x1000, 0x90);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0x10);
     gb.cpu.set_8(Register8::B, 0x10);
@@ -453,7 +459,7 @@ fn test_alu() {
     assert_eq!(gb.cpu.get_flag(Flag::C), false);
 
     //SUB B
-    gb.mem.write_byte(0x1000, 0x90);
+    gb.write_byte(0x1000, 0x90);
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_8(Register8::A, 0x10);
     gb.cpu.set_8(Register8::B, 0x11);
@@ -471,7 +477,8 @@ fn test_alu() {
 fn test_pop() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0xC1);
+    gb.write_byte(0x1000, 0xC1);
+    gb.write_word(0x4242, 0x4242);
     gb.cpu.set_16(Register16::SP, 0x4242);
     gb.cpu.set_16(Register16::PC, 0x1000);
 
@@ -479,6 +486,17 @@ fn test_pop() {
 
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
     assert_eq!(gb.cpu.get_16(Register16::BC), 0x4242);
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0x4244);
+
+    // POP AF, the low nibble of F always reads 0
+    gb.write_byte(0x1000, 0xF1);
+    gb.write_word(0x4242, 0x42FF);
+    gb.cpu.set_16(Register16::SP, 0x4242);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+
+    decode(& mut gb);
+
+    assert_eq!(gb.cpu.get_16(Register16::AF), 0x42F0);
 }
 
 #[test]
@@ -486,9 +504,9 @@ fn test_call() {
     let mut gb = init_env();
 
     //CALL NZ, nn
-    gb.mem.write_byte(0x1000, 0xC4);
-    gb.mem.write_byte(0x1001, 0x42);
-    gb.mem.write_byte(0x1002, 0x42);
+    gb.write_byte(0x1000, 0xC4);
+    gb.write_byte(0x1001, 0x42);
+    gb.write_byte(0x1002, 0x42);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
 
@@ -505,12 +523,15 @@ fn test_call() {
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1003);
 
     //CALL nn
-    gb.mem.write_byte(0x1000, 0xCD);
+    gb.write_byte(0x1000, 0xCD);
     gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::SP, 0x4242);
 
     decode(& mut gb);
 
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x4242);
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0x4240);
+    assert_eq!(gb.read_word(0x4240), 0x1003);
 }
 
 #[test]
@@ -518,7 +539,7 @@ fn test_add() {
     let mut gb = init_env();
 
     // ADD HL, rp[p]
-    gb.mem.write_byte(0x1000, 0x09);
+    gb.write_byte(0x1000, 0x09);
 
     gb.cpu.set_16(Register16::PC, 0x1000);
     gb.cpu.set_16(Register16::BC, 0x4242);
@@ -537,21 +558,24 @@ fn test_rst() {
     let mut gb = init_env();
 
     //RST x18
-    gb.mem.write_byte(0x1000, 0xDF);
+    gb.write_byte(0x1000, 0xDF);
     gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::SP, 0x4242);
 
     decode(& mut gb);
 
-    assert_eq!(gb.cpu.get_16(Register16::SP), 0x1001);
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0x4240);
+    assert_eq!(gb.read_word(0x4240), 0x1001);
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x0018);
 
     //RST x08
-    gb.mem.write_byte(0x1000, 0xCF);
+    gb.write_byte(0x1000, 0xCF);
     gb.cpu.set_16(Register16::PC, 0x1000);
 
     decode(& mut gb);
 
-    assert_eq!(gb.cpu.get_16(Register16::SP), 0x1001);
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0x423E);
+    assert_eq!(gb.read_word(0x423E), 0x1001);
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x0008);
 }
 
@@ -562,8 +586,8 @@ fn test_bit() {
     gb.cpu.reset_flags();
 
     //CB Bit 0, B
-    gb.mem.write_byte(0x1000, 0xCB);
-    gb.mem.write_byte(0x1001, 0x40);
+    gb.write_byte(0x1000, 0xCB);
+    gb.write_byte(0x1001, 0x40);
     gb.cpu.set_8(Register8::B, 0);
     gb.cpu.set_16(Register16::PC, 0x1000);
 
@@ -575,8 +599,8 @@ fn test_bit() {
     assert_eq!(gb.cpu.get_16(Register16::PC), 0x1002);
 
     //CB Bit 0, B
-    gb.mem.write_byte(0x1000, 0xCB);
-    gb.mem.write_byte(0x1001, 0x40);
+    gb.write_byte(0x1000, 0xCB);
+    gb.write_byte(0x1001, 0x40);
     gb.cpu.set_8(Register8::B, 0b1000_0000);
     gb.cpu.set_16(Register16::PC, 0x1000);
 
@@ -588,37 +612,494 @@ fn test_bit() {
     assert_eq!(gb.cpu.get_flag(Flag::H), true);
 
     //CB RES 0, B
-    gb.mem.write_byte(0x1000, 0xCB);
-    gb.mem.write_byte(0x1001, 0x80);
-    gb.cpu.set_8(Register8::B, 0b1100_0000);
+    gb.write_byte(0x1000, 0xCB);
+    gb.write_byte(0x1001, 0x80);
+    gb.cpu.set_8(Register8::B, 0b1100_0001);
     gb.cpu.set_16(Register16::PC, 0x1000);
 
 
     decode(&mut gb);
 
-    assert_eq!(gb.cpu.get_8(Register8::B), 0b0100_0000);
+    assert_eq!(gb.cpu.get_8(Register8::B), 0b1100_0000);
 
     //CB SET 0, B
-    gb.mem.write_byte(0x1000, 0xCB);
-    gb.mem.write_byte(0x1001, 0xC0);
+    gb.write_byte(0x1000, 0xCB);
+    gb.write_byte(0x1001, 0xC0);
     gb.cpu.set_8(Register8::B, 0b0100_0000);
     gb.cpu.set_16(Register16::PC, 0x1000);
 
 
     decode(&mut gb);
 
-    assert_eq!(gb.cpu.get_8(Register8::B), 0b1100_0000);
+    assert_eq!(gb.cpu.get_8(Register8::B), 0b0100_0001);
 }
 
 #[test]
 fn test_push() {
     let mut gb = init_env();
 
-    gb.mem.write_byte(0x1000, 0xD5);
+    gb.write_byte(0x1000, 0xD5);
     gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::SP, 0x4244);
     gb.cpu.set_16(Register16::DE, 0x4242);
 
     decode(&mut gb);
 
     assert_eq!(gb.cpu.get_16(Register16::SP), 0x4242);
-}
\ No newline at end of file
+    assert_eq!(gb.read_word(0x4242), 0x4242);
+}
+
+#[test]
+fn test_alu_flags() {
+    let mut gb = init_env();
+
+    //ADD A, B half carry
+    gb.write_byte(0x1000, 0x80);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::A, 0x0F);
+    gb.cpu.set_8(Register8::B, 0x01);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x10);
+    assert!(gb.cpu.get_flag(Flag::H));
+    assert!(!gb.cpu.get_flag(Flag::C));
+
+    //SBC A, B with carry
+    gb.write_byte(0x1000, 0x98);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::A, 0x10);
+    gb.cpu.set_8(Register8::B, 0x0F);
+    gb.cpu.set_flag(Flag::C, true);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x00);
+    assert!(gb.cpu.get_flag(Flag::Z));
+    assert!(gb.cpu.get_flag(Flag::N));
+    assert!(gb.cpu.get_flag(Flag::H));
+    assert!(!gb.cpu.get_flag(Flag::C));
+
+    //CP n
+    gb.write_byte(0x1000, 0xFE);
+    gb.write_byte(0x1001, 0x20);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::A, 0x10);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x10);
+    assert!(!gb.cpu.get_flag(Flag::Z));
+    assert!(!gb.cpu.get_flag(Flag::H));
+    assert!(gb.cpu.get_flag(Flag::C));
+
+    //INC B keeps the carry
+    gb.write_byte(0x1000, 0x04);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::B, 0xFF);
+    gb.cpu.set_flag(Flag::C, true);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::B), 0x00);
+    assert!(gb.cpu.get_flag(Flag::Z));
+    assert!(gb.cpu.get_flag(Flag::H));
+    assert!(gb.cpu.get_flag(Flag::C));
+
+    //ADD HL, BC half carry from bit 11
+    gb.write_byte(0x1000, 0x09);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::HL, 0x0FFF);
+    gb.cpu.set_16(Register16::BC, 0x0001);
+    gb.cpu.set_flag(Flag::Z, true);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_16(Register16::HL), 0x1000);
+    assert!(gb.cpu.get_flag(Flag::Z));
+    assert!(gb.cpu.get_flag(Flag::H));
+    assert!(!gb.cpu.get_flag(Flag::C));
+}
+
+#[test]
+fn test_daa() {
+    let mut gb = init_env();
+
+    //ADD A, B then DAA: 0x45 + 0x38 = 83
+    gb.write_byte(0x1000, 0x80);
+    gb.write_byte(0x1001, 0x27);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::A, 0x45);
+    gb.cpu.set_8(Register8::B, 0x38);
+
+    decode(&mut gb);
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x83);
+    assert!(!gb.cpu.get_flag(Flag::C));
+
+    //SUB B then DAA: 0x10 - 0x01 = 09
+    gb.write_byte(0x1000, 0x90);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::A, 0x10);
+    gb.cpu.set_8(Register8::B, 0x01);
+
+    decode(&mut gb);
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x09);
+
+    //ADD A, B then DAA: 0x99 + 0x01 = 00, carry
+    gb.write_byte(0x1000, 0x80);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::A, 0x99);
+    gb.cpu.set_8(Register8::B, 0x01);
+
+    decode(&mut gb);
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x00);
+    assert!(gb.cpu.get_flag(Flag::Z));
+    assert!(gb.cpu.get_flag(Flag::C));
+}
+
+#[test]
+fn test_sp_offset() {
+    let mut gb = init_env();
+
+    //ADD SP, d
+    gb.write_byte(0x1000, 0xE8);
+    gb.write_byte(0x1001, 0xFF);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::SP, 0x4200);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0x41FF);
+    assert!(!gb.cpu.get_flag(Flag::Z));
+    assert!(!gb.cpu.get_flag(Flag::H));
+    assert!(!gb.cpu.get_flag(Flag::C));
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1002);
+
+    //LD HL, SP + d
+    gb.write_byte(0x1000, 0xF8);
+    gb.write_byte(0x1001, 0x01);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::SP, 0x42FF);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_16(Register16::HL), 0x4300);
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0x42FF);
+    assert!(gb.cpu.get_flag(Flag::H));
+    assert!(gb.cpu.get_flag(Flag::C));
+}
+
+#[test]
+fn test_ldh() {
+    let mut gb = init_env();
+
+    //LD (0xFF00 + n), A
+    gb.write_byte(0x1000, 0xE0);
+    gb.write_byte(0x1001, 0x80);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::A, 0x42);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.read_byte(0xFF80), 0x42);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1002);
+
+    //LD A, (0xFF00 + C)
+    gb.write_byte(0x1000, 0xF2);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::A, 0);
+    gb.cpu.set_8(Register8::C, 0x80);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::A), 0x42);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
+}
+
+#[test]
+fn test_cb_hl() {
+    let mut gb = init_env();
+
+    //CB RLC (HL)
+    gb.write_byte(0x1000, 0xCB);
+    gb.write_byte(0x1001, 0x06);
+    gb.write_byte(0x2000, 0x81);
+    gb.cpu.set_16(Register16::HL, 0x2000);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.read_byte(0x2000), 0x03);
+    assert!(gb.cpu.get_flag(Flag::C));
+
+    //CB SWAP (HL)
+    gb.write_byte(0x1001, 0x36);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.read_byte(0x2000), 0x30);
+    assert!(!gb.cpu.get_flag(Flag::C));
+
+    //CB SET 7, (HL)
+    gb.write_byte(0x1001, 0xFE);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.read_byte(0x2000), 0xB0);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1002);
+}
+
+#[test]
+fn test_reti() {
+    let mut gb = init_env();
+
+    gb.write_byte(0x1000, 0xD9);
+    gb.write_word(0x4242, 0x2000);
+    gb.cpu.set_16(Register16::SP, 0x4242);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_iter_master(false);
+
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x2000);
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0x4244);
+    assert!(gb.cpu.get_iter_master());
+}
+
+#[test]
+fn test_illegal() {
+    let mut gb = init_env();
+
+    gb.write_byte(0x1000, 0xD3);
+    gb.write_byte(0x1001, 0x04);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::B, 0);
+
+    decode(&mut gb);
+    decode(&mut gb);
+
+    assert!(gb.locked);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1000);
+    assert_eq!(gb.cpu.get_8(Register8::B), 0);
+}
+
+#[test]
+fn test_halt() {
+    let mut gb = init_env();
+
+    //HALT, INC B
+    gb.write_byte(0x1000, 0x76);
+    gb.write_byte(0x1001, 0x04);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::B, 0);
+    gb.cpu.set_iter_master(false);
+    gb.cpu.set_iter_flag_enable(IterFlag::TIMER, true);
+
+    decode(&mut gb);
+    decode(&mut gb);
+
+    assert!(gb.halted);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
+
+    // Wakes up without IME, execution goes on after HALT
+    gb.cpu.set_iter_flag(IterFlag::TIMER, true);
+    decode(&mut gb);
+    decode(&mut gb);
+
+    assert!(!gb.halted);
+    assert_eq!(gb.cpu.get_8(Register8::B), 1);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1002);
+}
+
+#[test]
+fn test_halt_bug() {
+    let mut gb = init_env();
+
+    //HALT, INC B with an interrupt pending and IME off
+    gb.write_byte(0x1000, 0x76);
+    gb.write_byte(0x1001, 0x04);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::B, 0);
+    gb.cpu.set_iter_master(false);
+    gb.cpu.set_iter_flag_enable(IterFlag::TIMER, true);
+    gb.cpu.set_iter_flag(IterFlag::TIMER, true);
+
+    decode(&mut gb);
+
+    assert!(!gb.halted);
+
+    // INC B is executed twice
+    decode(&mut gb);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
+    decode(&mut gb);
+
+    assert_eq!(gb.cpu.get_8(Register8::B), 2);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1002);
+}
+
+#[test]
+fn test_stop() {
+    let mut gb = init_env();
+
+    //STOP, INC B
+    gb.write_byte(0x1000, 0x10);
+    gb.write_byte(0x1001, 0x00);
+    gb.write_byte(0x1002, 0x04);
+    // Action buttons selected
+    gb.write_byte(0xFF00, 0x10);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_8(Register8::B, 0);
+
+    decode(&mut gb);
+    decode(&mut gb);
+
+    assert!(gb.stopped);
+    assert_eq!(gb.cpu.get_8(Register8::B), 0);
+
+    // Directions are not selected
+    gb.press(Button::Up);
+    decode(&mut gb);
+    assert!(gb.stopped);
+
+    // A button goes down
+    gb.press(Button::A);
+    decode(&mut gb);
+
+    assert!(!gb.stopped);
+    assert_eq!(gb.cpu.get_8(Register8::B), 1);
+    assert!(gb.cpu.get_iter_flag(IterFlag::JOYPAD));
+}
+
+#[test]
+fn test_speed_switch() {
+    let mut gb = init_env();
+    gb.cgb_mode = true;
+
+    //STOP with KEY1 armed
+    gb.write_byte(0x1000, 0x10);
+    gb.write_byte(0xFF4D, 0x01);
+    assert_eq!(gb.read_byte(0xFF4D), 0x7F);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+
+    decode(&mut gb);
+
+    assert!(!gb.stopped);
+    assert!(gb.cpu.double_speed());
+    assert_eq!(gb.read_byte(0xFF4D), 0xFE);
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1002);
+}
+
+#[test]
+fn test_interrupt_priority() {
+    let mut gb = init_env();
+
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::SP, 0x4242);
+    gb.cpu.set_iter_master(true);
+    gb.write_byte(0xFFFF, 0x1F);
+    gb.write_byte(0xFF0F, 0x14);
+    assert_eq!(gb.read_byte(0xFF0F), 0xF4);
+
+    gb.interrupt_step();
+
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x0050);
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0x4240);
+    assert_eq!(gb.read_word(0x4240), 0x1000);
+    assert_eq!(gb.read_byte(0xFF0F), 0xF0);
+    assert!(!gb.cpu.get_iter_master());
+
+    // IME is off now, the joypad one has to wait
+    gb.interrupt_step();
+
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x0050);
+}
+
+#[test]
+fn test_ei_delay() {
+    let mut gb = init_env();
+
+    //EI, NOP, NOP
+    gb.write_byte(0x1000, 0xFB);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::SP, 0x4242);
+    gb.cpu.set_iter_master(false);
+    gb.write_byte(0xFFFF, 0x01);
+    gb.write_byte(0xFF0F, 0x01);
+
+    decode(&mut gb);
+    gb.interrupt_step();
+
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1001);
+
+    decode(&mut gb);
+    gb.interrupt_step();
+
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x0040);
+    assert_eq!(gb.read_word(0x4240), 0x1002);
+
+    //EI, DI cancels it
+    gb.write_byte(0x1001, 0xF3);
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.write_byte(0xFF0F, 0x01);
+
+    decode(&mut gb);
+    decode(&mut gb);
+    gb.interrupt_step();
+
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x1002);
+}
+
+#[test]
+fn test_interrupt_ie_push() {
+    let mut gb = init_env();
+
+    // The high byte of PC lands on IE and disables the interrupt
+    gb.cpu.set_16(Register16::PC, 0x0200);
+    gb.cpu.set_16(Register16::SP, 0x0000);
+    gb.cpu.set_iter_master(true);
+    gb.write_byte(0xFFFF, 0x01);
+    gb.write_byte(0xFF0F, 0x01);
+
+    gb.interrupt_step();
+
+    assert_eq!(gb.cpu.get_16(Register16::PC), 0x0000);
+    assert_eq!(gb.cpu.get_16(Register16::SP), 0xFFFE);
+    assert_eq!(gb.read_byte(0xFFFF), 0x02);
+    assert_eq!(gb.read_byte(0xFF0F), 0xE1);
+}
+
+#[test]
+fn test_run_frame() {
+    let mut gb = init_env();
+    // JR -2 forever with the LCD on
+    gb.write_byte(0x0000, 0x18);
+    gb.write_byte(0x0001, 0xFE);
+    gb.write_byte(0xFF40, 0x80);
+    gb.write_byte(0xFF26, 0x80);
+    gb.start_recording(Some((1, 2)), true);
+
+    gb.run_frame();
+    assert_eq!(gb.cpu.ppu.frames, 1);
+    gb.run_frame();
+    gb.run_frame();
+    assert_eq!(gb.cpu.ppu.frames, 3);
+
+    // One frame of audio, about 44100 / 59.7 stereo samples
+    let recorder = gb.stop_recording().unwrap();
+    assert!(recorder.mix.len() > 1400 && recorder.mix.len() < 1500);
+    assert_eq!(recorder.stems.unwrap()[0].len() * 2, recorder.mix.len());
+
+    // Nothing stops a frame with the LCD off
+    gb.write_byte(0xFF40, 0x00);
+    let ticks = gb.cpu.ticks();
+    gb.run_frame();
+    assert!(gb.cpu.ticks() - ticks >= 70224);
+}
@@ -0,0 +1,94 @@
+extern crate gameboy_emu;
+
+mod common;
+
+use common::init_env;
+use gameboy_emu::cpu::Register16;
+use gameboy_emu::gameboy::GameBoy;
+use gameboy_emu::serial::TextSink;
+
+/// Machine running INC A; LD (HL+),A; JR -4 with the LCD on, a snapshot
+/// taken every frame
+fn counting_machine() -> GameBoy {
+    let mut gb = init_env();
+    for (i, &b) in [0x3C, 0x22, 0x18, 0xFC].iter().enumerate() {
+        gb.write_byte(i as u16, b);
+    }
+    gb.cpu.set_16(Register16::HL, 0xC000);
+    gb.write_byte(0xFF40, 0x91);
+    gb.enable_rewind(1, 100);
+    gb
+}
+
+fn registers(gb: &GameBoy) -> (u16, u16, u16, u64) {
+    (gb.cpu.get_16(Register16::AF), gb.cpu.get_16(Register16::HL), gb.cpu.get_16(Register16::PC), gb.cpu.ticks())
+}
+
+#[test]
+fn test_rewind_frames() {
+    let mut gb = counting_machine();
+    gb.run_frame();
+    gb.run_frame();
+    let state = gb.save_state();
+    for _ in 0..3 {
+        gb.run_frame();
+    }
+    assert_eq!(gb.rewind(3).unwrap(), 3);
+    assert!(gb.save_state() == state);
+
+    // Only the keyframe is stored whole
+    let history = gb.history.as_ref().unwrap();
+    assert_eq!(history.snapshots.len(), 3);
+    assert!(history.memory_used() < state.len() + state.len() / 5);
+}
+
+#[test]
+fn test_rewind_past_history() {
+    let mut gb = counting_machine();
+    for _ in 0..5 {
+        gb.run_frame();
+    }
+    assert_eq!(gb.rewind(100).unwrap(), 5);
+    assert_eq!(gb.cpu.ppu.frames, 0);
+}
+
+#[test]
+fn test_step_back() {
+    let mut gb = counting_machine();
+    gb.run_frame();
+    gb.run_frame();
+
+    let mut trace = Vec::new();
+    for _ in 0..5 {
+        trace.push(registers(&gb));
+        gb.step();
+    }
+    for expected in trace.into_iter().rev() {
+        assert!(gb.step_back().unwrap());
+        assert_eq!(registers(&gb), expected);
+    }
+}
+
+#[test]
+fn test_step_back_audio_capture() {
+    // The capture does not hear the replay twice
+    let mut gb = counting_machine();
+    gb.start_recording(None, false);
+    for _ in 0..1000 {
+        gb.step();
+    }
+    let samples = gb.cpu.apu.recorder.as_ref().unwrap().mix.len();
+    assert!(samples > 0);
+    assert!(gb.step_back().unwrap());
+    assert_eq!(gb.cpu.apu.recorder.as_ref().unwrap().mix.len(), samples);
+}
+
+#[test]
+fn test_rewind_link_device() {
+    // The device would get the bytes sent again
+    let mut gb = counting_machine();
+    gb.run_frame();
+    gb.connect_serial(Box::new(TextSink::new()));
+    assert!(gb.step_back().is_err());
+    assert!(gb.rewind(1).is_err());
+}
@@ -0,0 +1,49 @@
+extern crate gameboy_emu;
+
+mod common;
+
+use common::init_env;
+use gameboy_emu::cpu::Register16;
+use gameboy_emu::gameboy::GameBoy;
+use gameboy_emu::serial::TextSink;
+
+/// Program sending 'O' on the internal clock with the serial interrupt
+/// enabled, then looping
+fn send_byte(gb: &mut GameBoy) {
+    gb.cpu.set_iter_enable(0x08);
+    gb.cpu.set_iter_master(true);
+
+    // LD A,'O'; LDH (01),A; LD A,0x81; LDH (02),A; JR -2
+    let program = [0x3E, b'O', 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0x18, 0xFE];
+    for (i, &b) in program.iter().enumerate() {
+        gb.write_byte(0x1000 + i as u16, b);
+    }
+    gb.cpu.set_16(Register16::PC, 0x1000);
+    gb.cpu.set_16(Register16::SP, 0xD000);
+}
+
+#[test]
+fn test_serial_output() {
+    let mut gb = init_env();
+    let sink = TextSink::new();
+    gb.connect_serial(Box::new(sink.clone()));
+    send_byte(&mut gb);
+
+    for _ in 0..4 {
+        gb.step();
+    }
+    assert_eq!(sink.text(), "O");
+}
+
+#[test]
+fn test_serial_disconnected() {
+    let mut gb = init_env();
+    send_byte(&mut gb);
+
+    // 8 bits at 8192 Hz, nothing answers
+    while gb.cpu.get_16(Register16::PC) != 0x58 {
+        gb.step();
+    }
+    assert_eq!(gb.read_byte(0xFF01), 0xFF);
+    assert_eq!(gb.read_byte(0xFF02) & 0x80, 0);
+}
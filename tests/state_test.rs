@@ -0,0 +1,68 @@
+extern crate gameboy_emu;
+
+mod common;
+
+use std::io;
+use common::{init_env, machine_with};
+use gameboy_emu::cpu::Register16;
+use gameboy_emu::gameboy::GameBoy;
+
+/// Machine a frame into INC A; LD (HL+),A; JR -4, with the LCD, the timer
+/// and the APU on
+fn running_machine() -> GameBoy {
+    let mut gb = init_env();
+    for (i, &b) in [0x3C, 0x22, 0x18, 0xFC].iter().enumerate() {
+        gb.write_byte(i as u16, b);
+    }
+    gb.cpu.set_16(Register16::HL, 0xC000);
+    gb.write_byte(0xFF40, 0x91);
+    gb.write_byte(0xFF07, 0x05);
+    gb.write_byte(0xFF26, 0x80);
+    gb.write_byte(0xFF12, 0xF0);
+    gb.write_byte(0xFF14, 0x80);
+    gb.run_frame();
+    gb
+}
+
+/// What the machine shows of its state, and the state itself
+fn snapshot(gb: &GameBoy) -> (u16, u16, u16, u64, u8, u8, u8, u8, Vec<u8>) {
+    (gb.cpu.get_16(Register16::AF), gb.cpu.get_16(Register16::HL), gb.cpu.get_16(Register16::PC),
+     gb.cpu.ticks(), gb.cpu.ppu.scanline, gb.read_byte(0xFF05), gb.read_byte(0xFF26),
+     gb.read_byte(0xC123), gb.save_state())
+}
+
+#[test]
+fn test_save_state_round_trip() {
+    let mut gb = running_machine();
+    let state = gb.save_state();
+    gb.run_frame();
+    gb.run_frame();
+    let after = snapshot(&gb);
+
+    gb.load_state(&state).unwrap();
+    assert_eq!(gb.save_state(), state);
+    gb.run_frame();
+    gb.run_frame();
+    assert!(snapshot(&gb) == after);
+}
+
+#[test]
+fn test_save_state_corrupted() {
+    let mut gb = running_machine();
+    let state = gb.save_state();
+    gb.run_frame();
+    let before = snapshot(&gb);
+
+    assert!(gb.load_state(&state[..state.len() - 1]).is_err());
+    assert!(gb.load_state(&state[..3]).is_err());
+    assert!(snapshot(&gb) == before);
+}
+
+#[test]
+fn test_save_state_other_rom() {
+    let state = running_machine().save_state();
+    let mut rom = vec![0; 0x8000];
+    rom[0x0134] = b'X';
+    let mut other = machine_with(rom);
+    assert_eq!(other.load_state(&state).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}